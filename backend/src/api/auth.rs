@@ -0,0 +1,241 @@
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::{
+    body::MessageBody,
+    cookie::{time::Duration, Cookie, SameSite},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header,
+    middleware::Next,
+    web, HttpRequest, HttpResponse, Result,
+};
+use qrcode::{render::svg, QrCode};
+use serde::{Deserialize, Serialize};
+
+use super::{ok, StatusResponse};
+
+use crate::{
+    auth::{AuthError, Authenticator},
+    clash::runtime::Runtime,
+    tls, utils,
+};
+
+pub const SESSION_COOKIE: &str = "tomoon_session";
+
+#[derive(Deserialize)]
+pub struct LoginParams {
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct PairParams {
+    token: String,
+}
+
+#[derive(Serialize)]
+pub struct PairingCodeResponse {
+    code: String,
+    expires_in: u64,
+}
+
+#[derive(Serialize)]
+pub struct PairingInfoResponse {
+    url: String,
+    urls: Vec<String>,
+    port: u16,
+    token: String,
+    code: String,
+    expires_in: u64,
+    qr_svg: String,
+    // 使用 HTTPS 时证书的 SHA-256 指纹
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<String>,
+}
+
+fn session_token(req: &HttpRequest) -> Option<String> {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        return Some(cookie.value().to_string());
+    }
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .map(|x| x.trim().to_string())
+}
+
+fn session_cookie(token: String, max_age: Duration) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .max_age(max_age)
+        .finish()
+}
+
+/// 外部服务的鉴权中间件，要求有效的会话 cookie 或 Bearer 令牌
+pub async fn require_session(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>> {
+    let authorized = match req.app_data::<web::Data<Runtime>>() {
+        Some(state) => session_token(req.request()).is_some_and(|x| state.auth.verify(&x)),
+        None => false,
+    };
+    if !authorized {
+        return Err(AuthError::Unauthorized.into());
+    }
+    next.call(req).await
+}
+
+pub async fn login(
+    state: web::Data<Runtime>,
+    req: HttpRequest,
+    params: web::Form<LoginParams>,
+) -> Result<HttpResponse> {
+    let ip = req.peer_addr().map(|x| x.ip());
+    let token = state.auth.login(ip, &params.code)?;
+    log::info!("External client {:?} paired", ip);
+
+    Ok(HttpResponse::Ok()
+        .cookie(session_cookie(token.clone(), Duration::days(7)))
+        .json(StatusResponse {
+            success: true,
+            data: Some(token),
+        }))
+}
+
+/// 扫描二维码后打开的链接，兑换一次性令牌后跳转到外部 Web UI
+pub async fn pair(
+    state: web::Data<Runtime>,
+    req: HttpRequest,
+    params: web::Query<PairParams>,
+) -> Result<HttpResponse> {
+    let ip = req.peer_addr().map(|x| x.ip());
+    let token = state.auth.redeem_pairing_token(ip, &params.token)?;
+    log::info!("External client {:?} paired with QR code", ip);
+
+    Ok(HttpResponse::SeeOther()
+        .cookie(session_cookie(token, Duration::days(7)))
+        .insert_header((header::LOCATION, "/"))
+        .finish())
+}
+
+pub async fn logout(state: web::Data<Runtime>, req: HttpRequest) -> Result<HttpResponse> {
+    if let Some(token) = session_token(&req) {
+        state.auth.logout(&token);
+    }
+    Ok(HttpResponse::Ok()
+        .cookie(session_cookie(String::new(), Duration::ZERO))
+        .json(StatusResponse::<()> {
+            success: true,
+            data: None,
+        }))
+}
+
+pub async fn status(state: web::Data<Runtime>, req: HttpRequest) -> Result<HttpResponse> {
+    let authorized = session_token(&req).is_some_and(|x| state.auth.verify(&x));
+    Ok(HttpResponse::Ok().json(StatusResponse::<()> {
+        success: authorized,
+        data: None,
+    }))
+}
+
+// 以下接口只注册在本机后端上，供 Deck 端显示配对码
+
+pub async fn get_pairing_code(state: web::Data<Runtime>) -> Result<HttpResponse> {
+    let (code, expires_in) = state.auth.pairing_code();
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(PairingCodeResponse { code, expires_in }),
+    }))
+}
+
+pub async fn regenerate_pairing_code(state: web::Data<Runtime>) -> Result<HttpResponse> {
+    let (code, expires_in) = state.auth.regenerate_pairing_code();
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(PairingCodeResponse { code, expires_in }),
+    }))
+}
+
+pub async fn pairing_info(state: web::Data<Runtime>) -> Result<HttpResponse> {
+    let settings = state.settings.get();
+    if !settings.enable_external_server {
+        return Ok(HttpResponse::BadRequest().json(StatusResponse {
+            success: false,
+            data: Some("External server is disabled".to_string()),
+        }));
+    }
+
+    let addresses = utils::get_local_addresses();
+    if addresses.is_empty() {
+        return Ok(HttpResponse::InternalServerError().json(StatusResponse {
+            success: false,
+            data: Some("No local network address found".to_string()),
+        }));
+    }
+
+    // 与外部服务读取同一份证书，尚未生成或未包含当前地址时在此重新生成
+    let fingerprint = match settings.external_tls {
+        true => tls::certificate_dir()
+            .and_then(|x| tls::ServerCertificate::load_or_create(&x, &addresses))
+            .map(|x| Some(x.fingerprint()))
+            .map_err(actix_web::error::ErrorInternalServerError)?,
+        false => None,
+    };
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(build_pairing_info(
+            &state.auth,
+            &addresses,
+            settings.external_port,
+            fingerprint,
+        )?),
+    }))
+}
+
+/// 为每个地址生成配对链接，二维码使用首个地址，`addresses` 不能为空
+pub fn build_pairing_info(
+    auth: &Authenticator,
+    addresses: &[IpAddr],
+    port: u16,
+    fingerprint: Option<String>,
+) -> Result<PairingInfoResponse> {
+    let scheme = if fingerprint.is_some() { "https" } else { "http" };
+    let (token, expires_in) = auth.issue_pairing_token();
+    let (code, _) = auth.pairing_code();
+    let urls: Vec<String> = addresses
+        .iter()
+        .map(|ip| {
+            format!(
+                "{}://{}/auth/pair?token={}",
+                scheme,
+                SocketAddr::new(*ip, port),
+                token
+            )
+        })
+        .collect();
+    let url = urls[0].clone();
+
+    let qr_svg = QrCode::new(url.as_bytes())
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .render::<svg::Color>()
+        .min_dimensions(256, 256)
+        .build();
+
+    Ok(PairingInfoResponse {
+        url,
+        urls,
+        port,
+        token,
+        code,
+        expires_in,
+        qr_svg,
+        fingerprint,
+    })
+}
+
+pub async fn revoke_sessions(state: web::Data<Runtime>) -> Result<HttpResponse> {
+    state.auth.revoke_sessions();
+    ok()
+}
@@ -1,9 +1,17 @@
-use actix_web::{web, HttpResponse, Result};
+use actix_web::{web, Either, HttpResponse, Result};
 use local_ip_address::local_ip;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 use crate::{
-    clash::{controller::{ClashError, ClashErrorKind, EnhancedMode}, runtime::Runtime}, subscriptions
+    clash::{
+        controller::{ClashError, ClashErrorKind, EnhancedMode},
+        preview::{self, ConfigDiff},
+        runtime::Runtime,
+    },
+    providers::ProviderKind,
+    settings::{generate_secret, RequestOptions, SettingsError},
+    subscriptions,
 };
 
 use super::{ok, StatusResponse};
@@ -11,6 +19,35 @@ use super::{ok, StatusResponse};
 #[derive(Deserialize)]
 pub struct DownloadSubParams {
     link: String,
+    #[serde(default)]
+    request: RequestOptions,
+}
+
+#[derive(Deserialize)]
+pub struct DownloadSubQuery {
+    // 为 false 时立即返回任务，由客户端轮询 /jobs/{id}
+    #[serde(default = "default_wait")]
+    wait: bool,
+}
+
+fn default_wait() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+pub struct PreviewConfigParams {
+    sub: Option<Uuid>,
+    skip_proxy: Option<bool>,
+    override_dns: Option<bool>,
+    allow_remote_access: Option<bool>,
+    enhanced_mode: Option<EnhancedMode>,
+    dashboard: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PreviewConfigResponse {
+    config: String,
+    diff: ConfigDiff,
 }
 
 #[derive(Serialize)]
@@ -26,22 +63,16 @@ pub struct GetConfigResponse {
 
 
 pub async fn restart_clash(state: web::Data<Runtime>) -> Result<HttpResponse> {
-    state.controller.write().unwrap().restart_core().await?;
+    state.controller.read().await.restart_core().await?;
 
     ok()
 }
 
 pub async fn reload_clash_config(state: web::Data<Runtime>) -> Result<HttpResponse> {
-    let clash = state.controller.write().unwrap();
+    let mut clash = state.controller.write().await;
     let settings = state.settings.get();
 
-    match clash.change_config(
-        settings.skip_proxy,
-        settings.override_dns,
-        settings.allow_remote_access,
-        settings.enhanced_mode,
-        settings.dashboard.clone(),
-    ) {
+    match clash.change_config(&settings) {
         Ok(_) => {}
         Err(e) => {
             log::error!("Failed while change clash config.");
@@ -58,19 +89,127 @@ pub async fn reload_clash_config(state: web::Data<Runtime>) -> Result<HttpRespon
     ok()
 }
 
-pub async fn get_config(state: web::Data<Runtime>) -> Result<HttpResponse> {
+/// 列出运行中内核的 provider
+pub async fn list_providers(state: web::Data<Runtime>) -> Result<HttpResponse> {
+    let providers = state.controller.read().await.get_providers().await?;
 
-    let clash = match state.controller.read() {
-        Ok(x) => x,
-        Err(e) => {
-            log::error!("read clash_state failed: {}", e);
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(providers),
+    }))
+}
+
+pub async fn update_provider(
+    state: web::Data<Runtime>,
+    path: web::Path<(ProviderKind, String)>,
+) -> Result<HttpResponse> {
+    let (kind, name) = path.into_inner();
+    state
+        .controller
+        .read()
+        .await
+        .update_provider(kind, &name)
+        .await?;
+
+    ok()
+}
+
+pub async fn health_check_provider(
+    state: web::Data<Runtime>,
+    name: web::Path<String>,
+) -> Result<HttpResponse> {
+    state
+        .controller
+        .read()
+        .await
+        .health_check_provider(&name)
+        .await?;
+
+    ok()
+}
+
+pub async fn rotate_secret(state: web::Data<Runtime>) -> Result<HttpResponse> {
+    let secret = generate_secret();
+    // 运行中的内核由 Runtime 在收到变更后重新加载
+    state.settings.update(|mut x| x.secret = secret.clone())?;
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(secret),
+    }))
+}
+
+pub async fn preview_config(
+    state: web::Data<Runtime>,
+    params: web::Query<PreviewConfigParams>,
+) -> Result<HttpResponse> {
+    let mut settings = state.settings.get();
+    // 只允许预览已保存的订阅，避免读取任意文件
+    let sub = match params.sub.or(settings.current_sub) {
+        Some(id) => settings
+            .subscriptions
+            .iter()
+            .find(|x| x.id == id)
+            .map(|x| x.path.clone())
+            .ok_or_else(|| ClashError {
+                message: format!("Subscription not found: {}", id),
+                error_kind: ClashErrorKind::NotFoundError,
+            })?,
+        None => {
             return Err(actix_web::Error::from(ClashError {
-                message: e.to_string(),
-                error_kind: ClashErrorKind::OtherError,
-            }));
+                message: "No subscription selected".to_string(),
+                error_kind: ClashErrorKind::NotFoundError,
+            }))
         }
     };
 
+    if let Some(x) = params.skip_proxy {
+        settings.skip_proxy = x;
+    }
+    if let Some(x) = params.override_dns {
+        settings.override_dns = x;
+    }
+    if let Some(x) = params.allow_remote_access {
+        settings.allow_remote_access = x;
+    }
+    if let Some(x) = params.enhanced_mode {
+        settings.enhanced_mode = x;
+    }
+    if let Some(x) = &params.dashboard {
+        settings.dashboard = x.clone();
+    }
+
+    let content_error = |e: Box<dyn std::error::Error>| ClashError {
+        message: e.to_string(),
+        error_kind: ClashErrorKind::ContentError,
+    };
+
+    let mut running = state
+        .controller
+        .read()
+        .await
+        .render_config(&sub, &settings)
+        .map_err(content_error)?;
+    let mut original = std::fs::read_to_string(&sub)
+        .map_err(|e| content_error(e.into()))
+        .and_then(|x| serde_yaml::from_str(&x).map_err(|e| content_error(e.into())))?;
+
+    preview::redact_secret(&mut running);
+    preview::redact_secret(&mut original);
+
+    let config = serde_yaml::to_string(&running).map_err(|e| content_error(e.into()))?;
+    let diff = ConfigDiff::between(&original, &running);
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(PreviewConfigResponse { config, diff }),
+    }))
+}
+
+pub async fn get_config(state: web::Data<Runtime>) -> Result<HttpResponse> {
+
+    let clash = state.controller.read().await;
+
     let settings = state.settings.get();
     let secret = match clash.get_running_secret() {
         Ok(s) => s,
@@ -83,21 +222,49 @@ pub async fn get_config(state: web::Data<Runtime>) -> Result<HttpResponse> {
         allow_remote_access: settings.allow_remote_access,
         enhanced_mode: settings.enhanced_mode,
         dashboard: settings.dashboard.clone(),
-        secret: secret,
+        secret,
         status_code: 200,
     };
-    return Ok(HttpResponse::Ok().json(r));
+    Ok(HttpResponse::Ok().json(r))
 }
 
+/// 下载新订阅，JSON 请求体可以附带请求参数，表单只包含链接
 pub async fn download_sub(
     state: web::Data<Runtime>,
-    params: web::Form<DownloadSubParams>,
+    query: web::Query<DownloadSubQuery>,
+    params: Either<web::Json<DownloadSubParams>, web::Form<DownloadSubParams>>,
 ) -> Result<HttpResponse> {
-    let url = params.link.clone();
+    let params = match params {
+        Either::Left(x) => x.into_inner(),
+        Either::Right(x) => x.into_inner(),
+    };
+    let errors = params.request.validate("request");
+    if !errors.is_empty() {
+        return Err(SettingsError::Invalid(errors).into());
+    }
 
-    subscriptions::download_new_sub(&url, &state.settings).await?;
+    // 下载在后台任务中进行，连接断开时仍会完成并可在 /jobs 中查询
+    let job = subscriptions::start_download(
+        &state.jobs,
+        params.link,
+        params.request,
+        state.settings.clone(),
+    );
+    if !query.wait {
+        return Ok(HttpResponse::Accepted().json(StatusResponse {
+            success: true,
+            data: Some(job),
+        }));
+    }
+    let job = state.jobs.wait(&job.id).await.unwrap_or(job);
+    if let Some(e) = &job.error {
+        return Err(ClashError::from(e).into());
+    }
 
-    ok()
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: job.result,
+    }))
 }
 
 pub async fn get_local_web_address() -> Result<HttpResponse> {
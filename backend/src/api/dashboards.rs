@@ -0,0 +1,106 @@
+use actix_web::{web, HttpResponse, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{ok, StatusResponse};
+
+use crate::{
+    clash::{
+        controller::{ClashError, ClashErrorKind},
+        runtime::Runtime,
+    },
+    dashboards::{self, Dashboard, DashboardDirs},
+    utils,
+};
+
+// 压缩包本身的大小上限
+const MAX_ARCHIVE_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Serialize)]
+pub struct DashboardResponse {
+    #[serde(flatten)]
+    dashboard: Dashboard,
+    current: bool,
+}
+
+#[derive(Deserialize)]
+pub struct InstallDashboardParams {
+    // 本地的 zip、tar 或 tar.gz 文件，可以带 file:// 前缀
+    path: String,
+    // 默认使用去掉扩展名的文件名
+    name: Option<String>,
+    #[serde(default)]
+    replace: bool,
+}
+
+fn dashboard_dirs() -> Result<DashboardDirs, ClashError> {
+    DashboardDirs::local().map_err(|e| ClashError {
+        message: e.to_string(),
+        error_kind: ClashErrorKind::IOError,
+    })
+}
+
+/// 列出内置与自定义面板
+pub async fn list_dashboards(state: web::Data<Runtime>) -> Result<HttpResponse> {
+    let current = state.settings.get().dashboard;
+    let dashboards: Vec<DashboardResponse> = dashboard_dirs()?
+        .list()
+        .into_iter()
+        .map(|x| DashboardResponse {
+            current: x.name == current,
+            dashboard: x,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(dashboards),
+    }))
+}
+
+pub async fn install_dashboard(body: web::Json<InstallDashboardParams>) -> Result<HttpResponse> {
+    let path = utils::get_file_path(body.path.clone()).unwrap_or_else(|| body.path.clone());
+    let path = std::path::Path::new(&path);
+    let name = match &body.name {
+        Some(x) => x.trim().to_string(),
+        None => dashboards::name_from_file(path).ok_or_else(|| ClashError {
+            message: format!("Cannot derive a dashboard name from {}", path.display()),
+            error_kind: ClashErrorKind::ContentError,
+        })?,
+    };
+
+    let metadata = std::fs::metadata(path).map_err(|e| ClashError {
+        message: format!("{}: {}", path.display(), e),
+        error_kind: ClashErrorKind::NotFoundError,
+    })?;
+    if metadata.len() > MAX_ARCHIVE_SIZE {
+        return Err(ClashError {
+            message: "The archive is too large".to_string(),
+            error_kind: ClashErrorKind::ContentError,
+        }
+        .into());
+    }
+    let archive = std::fs::read(path).map_err(|e| ClashError {
+        message: e.to_string(),
+        error_kind: ClashErrorKind::IOError,
+    })?;
+    let dashboard = dashboard_dirs()?.install(&archive, &name, body.replace)?;
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(dashboard),
+    }))
+}
+
+/// 删除自定义面板，正在使用的面板需要先切换
+pub async fn delete_dashboard(state: web::Data<Runtime>, name: web::Path<String>) -> Result<HttpResponse> {
+    if state.settings.get().dashboard == *name {
+        return Err(ClashError {
+            message: format!("Dashboard {} is in use", name),
+            error_kind: ClashErrorKind::ContentError,
+        }
+        .into());
+    }
+    dashboard_dirs()?.remove(&name)?;
+
+    ok()
+}
@@ -0,0 +1,48 @@
+use actix_web::{web, HttpResponse, Result};
+use serde::Serialize;
+
+use super::{rules::current_profile, StatusResponse};
+
+use crate::{
+    clash::runtime::Runtime,
+    games::{self, GameExecutable, SteamGame},
+    rules::{self, PresetAction},
+    utils,
+};
+
+#[derive(Serialize)]
+pub struct GameResponse {
+    #[serde(flatten)]
+    game: SteamGame,
+    executables: Vec<GameExecutable>,
+    // 未设置时为空，表示使用订阅自身的规则
+    action: Option<PresetAction>,
+}
+
+#[derive(Serialize)]
+pub struct GamesResponse {
+    games: Vec<GameResponse>,
+    // 当前订阅中的策略组，可作为游戏的目标
+    proxy_groups: Vec<String>,
+}
+
+/// 列出本地 Steam 库中已安装的游戏及其路由
+pub async fn list_games(state: web::Data<Runtime>) -> Result<HttpResponse> {
+    let settings = state.settings.get();
+    let games = games::list_games(&utils::get_steam_dir())
+        .into_iter()
+        .map(|x| GameResponse {
+            executables: x.executables(),
+            action: settings.game_routes.get(&x.app_id).cloned(),
+            game: x,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(GamesResponse {
+            games,
+            proxy_groups: rules::proxy_groups(&current_profile(&state)),
+        }),
+    }))
+}
@@ -0,0 +1,58 @@
+use actix_web::{web, HttpResponse, Result};
+use serde::Deserialize;
+
+use super::StatusResponse;
+
+use crate::{
+    clash::{
+        controller::{ClashError, ClashErrorKind},
+        runtime::Runtime,
+    },
+    geodata::{self, GeoDatabase},
+    utils,
+};
+
+#[derive(Deserialize)]
+pub struct UpdateGeodataParams {
+    databases: Option<Vec<GeoDatabase>>,
+}
+
+fn core_dir() -> Result<std::path::PathBuf, ClashError> {
+    utils::get_core_dir().map_err(|e| ClashError {
+        message: e.to_string(),
+        error_kind: ClashErrorKind::IOError,
+    })
+}
+
+/// 列出内核使用的数据库及其版本与更新时间
+pub async fn list_geodata(state: web::Data<Runtime>) -> Result<HttpResponse> {
+    let settings = state.settings.get();
+    let files = geodata::list(&core_dir()?, &settings.geodata);
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(files),
+    }))
+}
+
+/// 开始更新数据库，未指定时更新全部
+pub async fn update_geodata(
+    state: web::Data<Runtime>,
+    body: Option<web::Json<UpdateGeodataParams>>,
+) -> Result<HttpResponse> {
+    let databases = body
+        .and_then(|x| x.into_inner().databases)
+        .unwrap_or_else(|| GeoDatabase::ALL.to_vec());
+    let job = geodata::start_update(
+        &state.jobs,
+        databases,
+        core_dir()?,
+        state.settings.get().geodata,
+        state.controller.clone(),
+    );
+
+    Ok(HttpResponse::Accepted().json(StatusResponse {
+        success: true,
+        data: Some(job),
+    }))
+}
@@ -0,0 +1,55 @@
+use actix_web::{web, HttpResponse, Result};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::StatusResponse;
+
+use crate::{
+    clash::{
+        controller::{ClashError, ClashErrorKind},
+        runtime::Runtime,
+    },
+    jobs::JobKind,
+};
+
+#[derive(Deserialize)]
+pub struct ListJobsParams {
+    kind: Option<JobKind>,
+}
+
+fn not_found(id: &Uuid) -> ClashError {
+    ClashError {
+        message: format!("Job not found: {}", id),
+        error_kind: ClashErrorKind::NotFoundError,
+    }
+}
+
+/// 列出后台任务，最近开始的在前
+pub async fn list_jobs(
+    state: web::Data<Runtime>,
+    params: web::Query<ListJobsParams>,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(state.jobs.list(params.kind)),
+    }))
+}
+
+pub async fn get_job(state: web::Data<Runtime>, id: web::Path<Uuid>) -> Result<HttpResponse> {
+    let job = state.jobs.get(&id).ok_or_else(|| not_found(&id))?;
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(job),
+    }))
+}
+
+/// 取消任务，返回 202 表示取消请求已发出
+pub async fn cancel_job(state: web::Data<Runtime>, id: web::Path<Uuid>) -> Result<HttpResponse> {
+    let job = state.jobs.cancel(&id).ok_or_else(|| not_found(&id))?;
+
+    Ok(HttpResponse::Accepted().json(StatusResponse {
+        success: true,
+        data: Some(job),
+    }))
+}
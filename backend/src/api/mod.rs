@@ -1,11 +1,19 @@
+pub mod auth;
+pub mod dashboards;
+pub mod games;
+pub mod geodata;
+pub mod jobs;
+pub mod proxy;
+pub mod rules;
 pub mod settings;
+pub mod subscriptions;
 pub mod controller;
 
-use actix_web::{HttpResponse, Result};
+use actix_web::{http::header, HttpResponse, Result};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    clash::controller::{ClashError, ClashErrorKind}, settings::SettingsError
+    auth::AuthError, clash::controller::{ClashError, ClashErrorKind}, settings::SettingsError
 };
 
 #[derive(Deserialize)]
@@ -25,6 +33,7 @@ impl actix_web::ResponseError for ClashError {
         match self.error_kind {
             ClashErrorKind::NotFoundError => HttpResponse::NotFound(),
             ClashErrorKind::ContentError => HttpResponse::BadRequest(),
+            ClashErrorKind::ConflictError => HttpResponse::Conflict(),
             _ => HttpResponse::InternalServerError(),
         }.json(StatusResponse {
             success: false,
@@ -35,7 +44,36 @@ impl actix_web::ResponseError for ClashError {
 
 impl actix_web::ResponseError for SettingsError {
     fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
-        HttpResponse::InternalServerError().json(StatusResponse {
+        match self {
+            SettingsError::Invalid(errors) => {
+                HttpResponse::UnprocessableEntity().json(StatusResponse {
+                    success: false,
+                    data: Some(errors),
+                })
+            }
+            SettingsError::Conflict { .. } => HttpResponse::Conflict().json(StatusResponse {
+                success: false,
+                data: Some(self.to_string()),
+            }),
+            _ => HttpResponse::InternalServerError().json(StatusResponse {
+                success: false,
+                data: Some(self.to_string()),
+            }),
+        }
+    }
+}
+
+impl actix_web::ResponseError for AuthError {
+    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+        let mut response = match self {
+            AuthError::Locked { retry_after } => {
+                let mut response = HttpResponse::TooManyRequests();
+                response.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+                response
+            }
+            _ => HttpResponse::Unauthorized(),
+        };
+        response.json(StatusResponse {
             success: false,
             data: Some(self.to_string()),
         })
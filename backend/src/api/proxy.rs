@@ -0,0 +1,286 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use actix_files::NamedFile;
+use actix_web::{
+    http::{header, StatusCode},
+    web::{self, Bytes},
+    HttpRequest, HttpResponse, Result,
+};
+use futures_util::{stream, StreamExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    clash::{
+        controller::{ClashError, ClashErrorKind},
+        runtime::Runtime,
+    },
+    dashboards::DashboardDirs,
+};
+
+// 内核 external-controller 的本机地址
+const CONTROLLER_ADDR: &str = "127.0.0.1:9090";
+// 转发给内核的请求体上限
+const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+// WebSocket 握手响应头的上限
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+// 逐跳首部与 ToMoon 自身的鉴权首部不转发
+const SKIPPED_HEADERS: [&str; 13] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+    "cookie",
+    "authorization",
+    "origin",
+    "content-length",
+];
+
+/// 内核 WebSocket 握手响应的状态码、首部与首部之后已读取的数据
+pub struct ResponseHead {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub rest: Vec<u8>,
+}
+
+fn network_error(e: impl ToString) -> ClashError {
+    ClashError {
+        message: format!("Failed to reach the external controller: {}", e.to_string()),
+        error_kind: ClashErrorKind::NetworkError,
+    }
+}
+
+fn is_forwarded(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    // 跨域首部由外部服务的 Cors 中间件负责
+    !SKIPPED_HEADERS.contains(&name.as_str()) && !name.starts_with("access-control-")
+}
+
+/// 转发到内核的路径，面板放在查询参数中的 token 不转发
+///
+/// `path` 是请求中未解码的路径，保留节点名称中的 `%23`、`%3F` 等转义
+pub fn upstream_path(path: &str, query: &str) -> String {
+    let path = path.strip_prefix("/api").unwrap_or(path);
+    let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+    let query: Vec<&str> = query
+        .split('&')
+        .filter(|x| !x.is_empty() && *x != "token" && !x.starts_with("token="))
+        .collect();
+    match query.is_empty() {
+        true => path,
+        false => format!("{}?{}", path, query.join("&")),
+    }
+}
+
+/// 解析 HTTP/1.1 响应头，数据不完整时返回 None
+pub fn parse_response_head(data: &[u8]) -> Option<ResponseHead> {
+    let end = data.windows(4).position(|x| x == b"\r\n\r\n")?;
+    let head = String::from_utf8_lossy(&data[..end]);
+    let mut lines = head.split("\r\n");
+    let status = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+    let headers = lines
+        .filter_map(|x| x.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Some(ResponseHead {
+        status,
+        headers,
+        rest: data[end + 4..].to_vec(),
+    })
+}
+
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    // 日志与流量等接口是长连接，只限制建立连接的时间
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .no_proxy()
+            .connect_timeout(Duration::from_secs(5))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap_or_default()
+    })
+}
+
+fn is_websocket(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::UPGRADE)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.eq_ignore_ascii_case("websocket"))
+}
+
+/// 把外部服务的 `/api/*` 转发到内核，使用内核的 secret 代替 ToMoon 的会话
+pub async fn proxy_controller(
+    state: web::Data<Runtime>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse> {
+    // web::Path 会解码 `%23` 等字符，转发时使用原始路径
+    let path = upstream_path(req.uri().path(), req.query_string());
+    let secret = state
+        .controller
+        .read()
+        .await
+        .controller_secret()
+        .unwrap_or_else(|| state.settings.get().secret);
+
+    if is_websocket(&req) {
+        proxy_websocket(&req, &path, &secret, payload).await
+    } else {
+        proxy_http(&req, &path, &secret, payload).await
+    }
+}
+
+async fn proxy_http(req: &HttpRequest, path: &str, secret: &str, payload: web::Payload) -> Result<HttpResponse> {
+    let body = payload.to_bytes_limited(MAX_BODY_SIZE).await.map_err(|_| ClashError {
+        message: "The request body is too large".to_string(),
+        error_kind: ClashErrorKind::ContentError,
+    })??;
+    let method = reqwest::Method::from_bytes(req.method().as_str().as_bytes()).map_err(network_error)?;
+    let mut request = client()
+        .request(method, format!("http://{}{}", CONTROLLER_ADDR, path))
+        .body(body.to_vec());
+    for (name, value) in req.headers() {
+        if is_forwarded(name.as_str()) {
+            request = request.header(name.as_str(), value.as_bytes());
+        }
+    }
+    if !secret.is_empty() {
+        request = request.bearer_auth(secret);
+    }
+    let response = request.send().await.map_err(network_error)?;
+
+    let status = StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut builder = HttpResponse::build(status);
+    for (name, value) in response.headers() {
+        if is_forwarded(name.as_str()) {
+            builder.append_header((name.as_str(), value.as_bytes()));
+        }
+    }
+    // 内核的流式接口按块转发
+    let body = stream::unfold(Some(response), |response| async move {
+        let mut response = response?;
+        match response.chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), Some(response))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    Ok(builder.streaming(body))
+}
+
+async fn proxy_websocket(
+    req: &HttpRequest,
+    path: &str,
+    secret: &str,
+    mut payload: web::Payload,
+) -> Result<HttpResponse> {
+    let mut upstream = TcpStream::connect(CONTROLLER_ADDR).await.map_err(network_error)?;
+    let mut handshake = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n",
+        path, CONTROLLER_ADDR
+    );
+    for (name, value) in req.headers() {
+        if name.as_str().starts_with("sec-websocket-") {
+            if let Ok(value) = value.to_str() {
+                handshake.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+    }
+    if !secret.is_empty() {
+        handshake.push_str(&format!("Authorization: Bearer {}\r\n", secret));
+    }
+    handshake.push_str("\r\n");
+    upstream.write_all(handshake.as_bytes()).await.map_err(network_error)?;
+
+    let mut data = Vec::new();
+    let head = loop {
+        let mut buffer = [0u8; 4096];
+        let read = upstream.read(&mut buffer).await.map_err(network_error)?;
+        if read == 0 {
+            return Err(network_error("connection closed during handshake").into());
+        }
+        data.extend_from_slice(&buffer[..read]);
+        if let Some(head) = parse_response_head(&data) {
+            break head;
+        }
+        if data.len() > MAX_HEAD_SIZE {
+            return Err(network_error("handshake response is too large").into());
+        }
+    };
+
+    if head.status != 101 {
+        let status = StatusCode::from_u16(head.status).unwrap_or(StatusCode::BAD_GATEWAY);
+        return Ok(HttpResponse::build(status).body(head.rest));
+    }
+    let mut builder = HttpResponse::SwitchingProtocols();
+    builder.upgrade("websocket");
+    for (name, value) in &head.headers {
+        if name.to_ascii_lowercase().starts_with("sec-websocket-") {
+            builder.insert_header((name.as_str(), value.as_str()));
+        }
+    }
+
+    // 升级后的请求体即客户端发来的原始帧
+    let (reader, mut writer) = upstream.into_split();
+    actix_web::rt::spawn(async move {
+        while let Some(Ok(chunk)) = payload.next().await {
+            if writer.write_all(&chunk).await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    let rest = Bytes::from(head.rest);
+    let frames = stream::unfold(Some(reader), |reader| async move {
+        let mut reader = reader?;
+        let mut buffer = vec![0u8; 8192];
+        match reader.read(&mut buffer).await {
+            Ok(0) => None,
+            Ok(read) => {
+                buffer.truncate(read);
+                Some((Ok(Bytes::from(buffer)), Some(reader)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    let body = stream::iter((!rest.is_empty()).then_some(Ok(rest))).chain(frames);
+    Ok(builder.streaming(body))
+}
+
+/// 外部服务上的当前面板，`/ui/` 对应面板的 index.html
+pub async fn serve_dashboard(
+    state: web::Data<Runtime>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let name = state.settings.get().dashboard;
+    let dashboard = DashboardDirs::local()
+        .ok()
+        .and_then(|x| x.find(&name))
+        .ok_or_else(|| ClashError {
+            message: format!("Dashboard not found: {}", name),
+            error_kind: ClashErrorKind::NotFoundError,
+        })?;
+    let file = dashboard.file(&path).ok_or_else(|| ClashError {
+        message: format!("File not found: {}", path),
+        error_kind: ClashErrorKind::NotFoundError,
+    })?;
+    Ok(NamedFile::open(file)?.into_response(&req))
+}
+
+/// 面板使用相对路径加载资源，需要以 `/` 结尾
+pub async fn redirect_dashboard() -> HttpResponse {
+    HttpResponse::MovedPermanently()
+        .insert_header((header::LOCATION, "/ui/"))
+        .finish()
+}
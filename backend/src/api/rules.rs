@@ -0,0 +1,289 @@
+use std::collections::HashSet;
+
+use actix_web::{web, HttpResponse, Result};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{ok, StatusResponse};
+
+use crate::{
+    clash::{
+        controller::{ClashError, ClashErrorKind},
+        runtime::Runtime,
+    },
+    matcher::{MatchTarget, Matcher},
+    rules::{self, CustomRule, CustomRulePatch, PresetAction, RulePreset},
+    settings::{SettingsError, ValidationError, ValidationErrorKind},
+    utils,
+};
+
+#[derive(Serialize)]
+pub struct PresetResponse {
+    preset: RulePreset,
+    name: &'static str,
+    matchers: &'static [&'static str],
+    // 未设置时为空，表示不注入
+    action: Option<PresetAction>,
+}
+
+#[derive(Serialize)]
+pub struct PresetsResponse {
+    presets: Vec<PresetResponse>,
+    // 当前订阅中的策略组，可作为预设的目标
+    proxy_groups: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct CustomRuleResponse {
+    #[serde(flatten)]
+    rule: CustomRule,
+    // 注入配置中的规则文本
+    rule_text: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateRuleParams {
+    #[serde(flatten)]
+    rule: CustomRule,
+    // 插入位置，未指定时追加到末尾
+    index: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct ReorderParams {
+    ids: Vec<Uuid>,
+}
+
+#[derive(Deserialize)]
+pub struct MatchParams {
+    host: Option<String>,
+    port: Option<u16>,
+    process: Option<String>,
+    network: Option<String>,
+    // 是否为 IP 规则解析域名，默认解析
+    resolve: Option<bool>,
+}
+
+impl From<CustomRule> for CustomRuleResponse {
+    fn from(rule: CustomRule) -> Self {
+        Self {
+            rule_text: rule.to_rule(),
+            rule,
+        }
+    }
+}
+
+/// 当前订阅的配置，未选择订阅或无法读取时为空
+pub fn current_profile(state: &Runtime) -> serde_yaml::Value {
+    state
+        .settings
+        .get()
+        .current_sub_path()
+        .and_then(|x| std::fs::read_to_string(x).ok())
+        .and_then(|x| serde_yaml::from_str(&x).ok())
+        .unwrap_or_default()
+}
+
+pub async fn list_presets(state: web::Data<Runtime>) -> Result<HttpResponse> {
+    let settings = state.settings.get();
+    let presets = RulePreset::ALL
+        .iter()
+        .map(|x| PresetResponse {
+            preset: *x,
+            name: x.display_name(),
+            matchers: x.matchers(),
+            action: settings.rule_presets.get(x).cloned(),
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(PresetsResponse {
+            presets,
+            proxy_groups: rules::proxy_groups(&current_profile(&state)),
+        }),
+    }))
+}
+
+// 策略必须是内置策略或当前订阅中的策略组
+fn check_policy(state: &Runtime, policy: &str) -> Result<()> {
+    let groups: HashSet<String> = rules::proxy_groups(&current_profile(state)).into_iter().collect();
+    if rules::is_known_policy(policy, &groups) {
+        return Ok(());
+    }
+    Err(SettingsError::Invalid(vec![ValidationError::new(
+        "policy",
+        ValidationErrorKind::InvalidValue,
+        format!("Policy {:?} is not in the current profile", policy),
+    )])
+    .into())
+}
+
+/// 按注入顺序列出自定义规则
+pub async fn list_custom_rules(state: web::Data<Runtime>) -> Result<HttpResponse> {
+    let rules: Vec<CustomRuleResponse> = state
+        .settings
+        .get()
+        .custom_rules
+        .into_iter()
+        .map(CustomRuleResponse::from)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(rules),
+    }))
+}
+
+pub async fn create_custom_rule(
+    state: web::Data<Runtime>,
+    body: web::Json<CreateRuleParams>,
+) -> Result<HttpResponse> {
+    let CreateRuleParams { mut rule, index } = body.into_inner();
+    rule.id = Uuid::new_v4();
+    rule.value = rule.value.trim().to_string();
+    rule.policy = rule.policy.trim().to_string();
+    check_policy(&state, &rule.policy)?;
+
+    let rule = state.settings.modify(|x| -> Result<CustomRule> {
+        let index = index.unwrap_or(x.custom_rules.len()).min(x.custom_rules.len());
+        x.custom_rules.insert(index, rule.clone());
+        Ok(rule)
+    })?;
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(CustomRuleResponse::from(rule)),
+    }))
+}
+
+pub async fn patch_custom_rule(
+    state: web::Data<Runtime>,
+    id: web::Path<Uuid>,
+    body: web::Json<CustomRulePatch>,
+) -> Result<HttpResponse> {
+    if let Some(policy) = &body.policy {
+        check_policy(&state, policy.trim())?;
+    }
+    let rule = state.settings.modify(|x| -> Result<CustomRule> {
+        let rule = rules::find_rule_mut(x, &id)?;
+        body.apply(rule);
+        Ok(rule.clone())
+    })?;
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(CustomRuleResponse::from(rule)),
+    }))
+}
+
+pub async fn delete_custom_rule(state: web::Data<Runtime>, id: web::Path<Uuid>) -> Result<HttpResponse> {
+    state
+        .settings
+        .modify(|x| -> Result<CustomRule> { Ok(rules::remove_rule(x, &id)?) })?;
+
+    ok()
+}
+
+pub async fn reorder_custom_rules(
+    state: web::Data<Runtime>,
+    body: web::Json<ReorderParams>,
+) -> Result<HttpResponse> {
+    state.settings.modify(|x| {
+        rules::reorder_rules(x, &body.ids).map_err(|e| SettingsError::Invalid(vec![e]))
+    })?;
+
+    ok()
+}
+
+// 内核运行时使用实际的运行配置，否则按当前设置生成
+async fn running_profile(state: &Runtime) -> Result<serde_yaml::Value> {
+    let content_error = |e: Box<dyn std::error::Error>| ClashError {
+        message: e.to_string(),
+        error_kind: ClashErrorKind::ContentError,
+    };
+    let clash = state.controller.read().await;
+    if utils::is_clash_running() {
+        if let Ok(content) = clash.get_running_config().and_then(std::fs::read_to_string) {
+            return Ok(serde_yaml::from_str(&content).map_err(|e| content_error(e.into()))?);
+        }
+    }
+    let settings = state.settings.get();
+    let sub = settings.current_sub_path().ok_or_else(|| ClashError {
+        message: "No subscription selected".to_string(),
+        error_kind: ClashErrorKind::NotFoundError,
+    })?;
+    Ok(clash.render_config(sub, &settings).map_err(content_error)?)
+}
+
+// 内核运行时通过内核 DNS 解析，避免系统 DNS 返回 fake-ip
+async fn resolve_host(state: &Runtime, host: &str) -> Option<std::net::IpAddr> {
+    if utils::is_clash_running() {
+        return match state.controller.read().await.resolve_host(host).await {
+            Ok(x) => x,
+            Err(e) => {
+                log::warn!("Failed to resolve {} through the core: {}", host, e);
+                None
+            }
+        };
+    }
+    let mut addresses = tokio::net::lookup_host((host, 0)).await.ok()?;
+    addresses.next().map(|x| x.ip())
+}
+
+/// 按运行配置的规则顺序测试连接会命中的规则
+pub async fn match_rule(
+    state: web::Data<Runtime>,
+    params: web::Query<MatchParams>,
+) -> Result<HttpResponse> {
+    let params = params.into_inner();
+    let host = params.host.as_deref().map(|x| x.trim()).filter(|x| !x.is_empty());
+    if host.is_none() && params.process.is_none() {
+        return Err(SettingsError::Invalid(vec![ValidationError::new(
+            "host",
+            ValidationErrorKind::InvalidValue,
+            "A host or a process is required",
+        )])
+        .into());
+    }
+    let is_domain = host.is_some_and(|x| x.trim_matches(['[', ']']).parse::<std::net::IpAddr>().is_err());
+    if is_domain
+        && !host
+            .unwrap_or_default()
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || matches!(x, '-' | '.' | '_'))
+    {
+        return Err(SettingsError::Invalid(vec![ValidationError::new(
+            "host",
+            ValidationErrorKind::InvalidValue,
+            format!("Invalid host {:?}", host.unwrap_or_default()),
+        )])
+        .into());
+    }
+
+    let profile = running_profile(&state).await?;
+    let resolved = match (host, is_domain && params.resolve.unwrap_or(true)) {
+        (Some(host), true) => resolve_host(&state, host).await,
+        _ => None,
+    };
+    let target = MatchTarget {
+        host: host.map(|x| x.to_string()),
+        port: params.port,
+        process: params.process,
+        network: params.network,
+    };
+    let core_dir = utils::get_core_dir()?;
+    let mut report = Matcher::new(&profile, &core_dir, target, resolved).run();
+
+    if utils::is_clash_running() {
+        match state.controller.read().await.get_policy_chain(&report.policy).await {
+            Ok(chain) => report.chain = chain,
+            Err(e) => log::warn!("Failed to read the selected proxies: {}", e),
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(report),
+    }))
+}
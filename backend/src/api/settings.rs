@@ -1,11 +1,30 @@
-use actix_web::{web, HttpResponse, Result};
+use actix_web::{http::header, web, HttpResponse, Result};
+use serde::Serialize;
+use serde_json::{Map, Value};
 
-use super::{ok, SingleParam};
+use super::{ok, SingleParam, StatusResponse};
 
 use crate::{
-    clash::{controller::EnhancedMode, runtime::Runtime}
+    backup::{self, Bundle, BundleDirs, ExportOptions, ImportOptions, ImportReport},
+    clash::{controller::{ClashError, ClashErrorKind, EnhancedMode, LogLevel}, runtime::Runtime},
+    settings::{self, Settings, SettingsError},
 };
 
+// 导入备份包的请求体大小上限
+pub const MAX_BUNDLE_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Serialize)]
+pub struct SettingsResponse {
+    revision: u64,
+    settings: Settings,
+}
+
+#[derive(Serialize)]
+pub struct ImportResponse {
+    revision: u64,
+    report: ImportReport,
+}
+
 macro_rules! set_setting_func {
     ($field:ident, $field_type:ty) => {
         pub async fn $field(
@@ -20,6 +39,149 @@ macro_rules! set_setting_func {
 
 set_setting_func!(skip_proxy, bool);
 set_setting_func!(override_dns, bool);
-set_setting_func!(allow_remote_access, bool);
 set_setting_func!(enhanced_mode, EnhancedMode);
-set_setting_func!(dashboard, String);
+// 外部服务在下次启动后端时生效
+set_setting_func!(enable_external_server, bool);
+set_setting_func!(external_tls, bool);
+set_setting_func!(log_level, LogLevel);
+
+/// 与 PATCH /settings 相同，只接受已安装的面板，否则返回 422
+pub async fn dashboard(
+    state: web::Data<Runtime>,
+    params: web::Form<SingleParam<String>>,
+) -> Result<HttpResponse> {
+    state.settings.modify(|x| {
+        x.dashboard = params.param.trim().to_string();
+        Ok::<_, SettingsError>(())
+    })?;
+    ok()
+}
+
+
+pub async fn get_settings(state: web::Data<Runtime>) -> Result<HttpResponse> {
+    let (settings, revision) = state.settings.get_with_revision();
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(SettingsResponse { revision, settings }),
+    }))
+}
+
+/// 部分更新设置，请求体中的 `revision` 与当前修订号不一致时返回 409
+pub async fn patch_settings(
+    state: web::Data<Runtime>,
+    body: web::Json<Map<String, Value>>,
+) -> Result<HttpResponse> {
+    let mut patch = body.into_inner();
+    let expected = match patch.remove("revision") {
+        None | Some(Value::Null) => None,
+        Some(x) => Some(x.as_u64().ok_or_else(|| {
+            SettingsError::Invalid(vec![settings::ValidationError {
+                field: "revision".to_string(),
+                kind: settings::ValidationErrorKind::InvalidType,
+                message: "revision must be an unsigned integer".to_string(),
+            }])
+        })?),
+    };
+
+    loop {
+        let (current, revision) = state.settings.get_with_revision();
+        if let Some(expected) = expected.filter(|x| *x != revision) {
+            return Err(SettingsError::Conflict {
+                expected,
+                current: revision,
+            }
+            .into());
+        }
+        let settings = current.patched(&patch).map_err(SettingsError::Invalid)?;
+        match state.settings.replace(Some(revision), settings.clone()) {
+            Ok(revision) => {
+                return Ok(HttpResponse::Ok().json(StatusResponse {
+                    success: true,
+                    data: Some(SettingsResponse { revision, settings }),
+                }));
+            }
+            // 客户端未指定修订号时，遇到并发修改就基于最新设置重试
+            Err(SettingsError::Conflict { .. }) if expected.is_none() => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn bundle_dirs() -> Result<BundleDirs, ClashError> {
+    BundleDirs::local().map_err(|e| ClashError {
+        message: e.to_string(),
+        error_kind: ClashErrorKind::IOError,
+    })
+}
+
+/// 导出设置、订阅与自定义文件为 tar.gz 备份包
+pub async fn export_settings(
+    state: web::Data<Runtime>,
+    params: web::Query<ExportOptions>,
+) -> Result<HttpResponse> {
+    let settings = state.settings.get();
+    let data = backup::export(&settings, &bundle_dirs()?, &params)?;
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/gzip")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"tomoon-{}.tar.gz\"", timestamp),
+        ))
+        .body(data))
+}
+
+/// 导入备份包，设置校验通过后才写入文件
+pub async fn import_settings(
+    state: web::Data<Runtime>,
+    params: web::Query<ImportOptions>,
+    body: web::Bytes,
+) -> Result<HttpResponse> {
+    let bundle = Bundle::read(&body)?;
+    let (current, revision) = state.settings.get_with_revision();
+    let plan = bundle.plan(&current, &bundle_dirs()?, &params);
+
+    let errors = plan.validate();
+    if !errors.is_empty() {
+        return Err(SettingsError::Invalid(errors).into());
+    }
+    // 确认设置未被修改后再写入文件
+    let revision = state.settings.replace_with(Some(revision), plan.settings.clone(), || {
+        plan.apply().map_err(actix_web::Error::from)
+    })?;
+    log::info!("Settings bundle imported.");
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(ImportResponse {
+            revision,
+            report: plan.report,
+        }),
+    }))
+}
+
+pub async fn settings_schema() -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(settings::settings_schema()))
+}
+
+pub async fn allow_remote_access(
+    state: web::Data<Runtime>,
+    params: web::Form<SingleParam<bool>>,
+) -> Result<HttpResponse> {
+    // 没有 secret 时不允许对局域网开放 external-controller
+    state.settings.update(|mut x| {
+        if params.param && x.secret.is_empty() {
+            return Err(ClashError {
+                message: "A secret is required before enabling remote access".to_string(),
+                error_kind: ClashErrorKind::ContentError,
+            });
+        }
+        x.allow_remote_access = params.param;
+        Ok(())
+    })??;
+    ok()
+}
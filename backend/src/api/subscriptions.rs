@@ -0,0 +1,153 @@
+use actix_web::{web, HttpResponse, Result};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{ok, StatusResponse};
+
+use crate::{
+    clash::{
+        controller::{ClashError, ClashErrorKind},
+        runtime::Runtime,
+    },
+    settings::{SettingsError, Subscription},
+    subscriptions::{self, SubscriptionPatch},
+};
+
+#[derive(Serialize)]
+pub struct SubscriptionResponse {
+    #[serde(flatten)]
+    subscription: Subscription,
+    display_name: String,
+    current: bool,
+}
+
+#[derive(Deserialize)]
+pub struct RenameParams {
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct ReorderParams {
+    ids: Vec<Uuid>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateSubsParams {
+    ids: Option<Vec<Uuid>>,
+}
+
+/// 按显示顺序列出订阅
+pub async fn list_subs(state: web::Data<Runtime>) -> Result<HttpResponse> {
+    let settings = state.settings.get();
+    let subs: Vec<SubscriptionResponse> = settings
+        .subscriptions
+        .iter()
+        .map(|x| SubscriptionResponse {
+            display_name: x.display_name(),
+            current: settings.current_sub == Some(x.id),
+            subscription: x.clone(),
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(subs),
+    }))
+}
+
+fn update_sub(state: &Runtime, id: &Uuid, patch: &SubscriptionPatch) -> Result<Subscription> {
+    state.settings.modify(|x| {
+        let sub = subscriptions::find_sub_mut(x, id)?;
+        patch.apply(sub);
+        Ok(sub.clone())
+    })
+}
+
+pub async fn patch_sub(
+    state: web::Data<Runtime>,
+    id: web::Path<Uuid>,
+    body: web::Json<SubscriptionPatch>,
+) -> Result<HttpResponse> {
+    let sub = update_sub(&state, &id, &body)?;
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(sub),
+    }))
+}
+
+pub async fn rename_sub(
+    state: web::Data<Runtime>,
+    id: web::Path<Uuid>,
+    body: web::Json<RenameParams>,
+) -> Result<HttpResponse> {
+    let patch = SubscriptionPatch {
+        name: Some(body.name.clone()),
+        ..Default::default()
+    };
+    let sub = update_sub(&state, &id, &patch)?;
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(sub),
+    }))
+}
+
+pub async fn reorder_subs(
+    state: web::Data<Runtime>,
+    body: web::Json<ReorderParams>,
+) -> Result<HttpResponse> {
+    state.settings.modify(|x| {
+        subscriptions::reorder_subs(x, &body.ids)
+            .map_err(|e| SettingsError::Invalid(vec![e]))
+    })?;
+
+    ok()
+}
+
+/// 切换当前订阅，运行中的内核由 Runtime 重新加载
+pub async fn select_sub(state: web::Data<Runtime>, id: web::Path<Uuid>) -> Result<HttpResponse> {
+    let sub = subscriptions::select_sub(&state.settings, &id)?;
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        success: true,
+        data: Some(sub),
+    }))
+}
+
+/// 开始更新订阅，未指定 id 时更新全部启用的订阅；已有更新其他订阅的任务时返回 409
+pub async fn update_subs(
+    state: web::Data<Runtime>,
+    body: Option<web::Json<UpdateSubsParams>>,
+) -> Result<HttpResponse> {
+    let settings = state.settings.get();
+    let subs: Vec<Subscription> = match body.and_then(|x| x.into_inner().ids) {
+        Some(ids) => {
+            if let Some(id) = ids.iter().find(|id| !settings.subscriptions.iter().any(|x| x.id == **id)) {
+                return Err(ClashError {
+                    message: format!("Subscription not found: {}", id),
+                    error_kind: ClashErrorKind::NotFoundError,
+                }
+                .into());
+            }
+            settings
+                .subscriptions
+                .into_iter()
+                .filter(|x| ids.contains(&x.id))
+                .collect()
+        }
+        None => settings.subscriptions.into_iter().filter(|x| x.enabled).collect(),
+    };
+    let job = subscriptions::start_update(&state.jobs, subs)?;
+
+    Ok(HttpResponse::Accepted().json(StatusResponse {
+        success: true,
+        data: Some(job),
+    }))
+}
+
+pub async fn delete_sub(state: web::Data<Runtime>, id: web::Path<Uuid>) -> Result<HttpResponse> {
+    subscriptions::delete_sub(&state.settings, &id)?;
+
+    ok()
+}
@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::{distributions::Alphanumeric, Rng};
+
+// 配对码有效期
+const PAIRING_CODE_TTL: Duration = Duration::from_secs(10 * 60);
+// 会话有效期
+const SESSION_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+// 统计失败次数的时间窗口
+const FAILURE_WINDOW: Duration = Duration::from_secs(10 * 60);
+// 窗口内允许的失败次数，超过后锁定
+const MAX_FAILURES: u32 = 5;
+const LOCKOUT: Duration = Duration::from_secs(5 * 60);
+// 所有来源累计的失败次数，达到后更换配对码，避免多个地址轮流猜测
+const MAX_TOTAL_FAILURES: u32 = 20;
+
+#[derive(Debug)]
+pub enum AuthError {
+    Unauthorized,
+    InvalidCode,
+    Locked { retry_after: u64 },
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unauthorized => write!(f, "Authentication required"),
+            Self::InvalidCode => write!(f, "Invalid pairing code"),
+            Self::Locked { retry_after } => write!(
+                f,
+                "Too many failed attempts, retry after {} seconds",
+                retry_after
+            ),
+        }
+    }
+}
+
+struct PairingCode {
+    code: String,
+    expires_at: Instant,
+}
+
+impl PairingCode {
+    fn generate() -> Self {
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+        Self {
+            code,
+            expires_at: Instant::now() + PAIRING_CODE_TTL,
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    since: Instant,
+    locked_until: Option<Instant>,
+}
+
+struct AuthState {
+    pairing_code: PairingCode,
+    // 二维码中携带的一次性令牌
+    pairing_tokens: HashMap<String, Instant>,
+    sessions: HashMap<String, Instant>,
+    failures: HashMap<IpAddr, Failures>,
+    // 当前配对码累计的失败次数
+    total_failures: u32,
+}
+
+/// 外部 HTTP 服务的鉴权：Deck 上显示配对码，手机输入后换取会话令牌
+#[derive(Clone)]
+pub struct Authenticator {
+    state: Arc<Mutex<AuthState>>,
+}
+
+impl Default for Authenticator {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(AuthState {
+                pairing_code: PairingCode::generate(),
+                pairing_tokens: HashMap::new(),
+                sessions: HashMap::new(),
+                failures: HashMap::new(),
+                total_failures: 0,
+            })),
+        }
+    }
+}
+
+pub fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(48)
+        .map(char::from)
+        .collect()
+}
+
+// 避免按字节提前返回泄露比较进度
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a
+            .bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+impl Authenticator {
+    /// 当前配对码及剩余有效秒数，过期时自动更换
+    pub fn pairing_code(&self) -> (String, u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.pairing_code.expires_at <= Instant::now() {
+            Self::rotate_pairing_code(&mut state);
+        }
+        let expires_in = state
+            .pairing_code
+            .expires_at
+            .saturating_duration_since(Instant::now());
+        (state.pairing_code.code.clone(), expires_in.as_secs())
+    }
+
+    pub fn regenerate_pairing_code(&self) -> (String, u64) {
+        Self::rotate_pairing_code(&mut self.state.lock().unwrap());
+        self.pairing_code()
+    }
+
+    /// 签发用于配对链接的一次性令牌
+    pub fn issue_pairing_token(&self) -> (String, u64) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        state.pairing_tokens.retain(|_, expires_at| *expires_at > now);
+        let token = generate_token();
+        state
+            .pairing_tokens
+            .insert(token.clone(), now + PAIRING_CODE_TTL);
+        (token, PAIRING_CODE_TTL.as_secs())
+    }
+
+    /// 校验配对码并创建会话，配对码使用一次后立即更换
+    pub fn login(&self, ip: Option<IpAddr>, code: &str) -> Result<String, AuthError> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        Self::check_locked(&state, ip, now)?;
+
+        let valid = state.pairing_code.expires_at > now
+            && constant_time_eq(&state.pairing_code.code, code.trim());
+        if !valid {
+            Self::record_failure(&mut state, ip, now);
+            return Err(AuthError::InvalidCode);
+        }
+
+        if let Some(ip) = ip {
+            state.failures.remove(&ip);
+        }
+        Self::rotate_pairing_code(&mut state);
+        Ok(Self::create_session(&mut state))
+    }
+
+    /// 兑换配对链接中的一次性令牌
+    pub fn redeem_pairing_token(&self, ip: Option<IpAddr>, token: &str) -> Result<String, AuthError> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        Self::check_locked(&state, ip, now)?;
+
+        match state.pairing_tokens.remove(token) {
+            Some(expires_at) if expires_at > now => {
+                if let Some(ip) = ip {
+                    state.failures.remove(&ip);
+                }
+                Ok(Self::create_session(&mut state))
+            }
+            _ => {
+                Self::record_failure(&mut state, ip, now);
+                Err(AuthError::InvalidCode)
+            }
+        }
+    }
+
+    fn check_locked(state: &AuthState, ip: Option<IpAddr>, now: Instant) -> Result<(), AuthError> {
+        if let Some(failures) = ip.and_then(|ip| state.failures.get(&ip)) {
+            if let Some(until) = failures.locked_until.filter(|x| *x > now) {
+                return Err(AuthError::Locked {
+                    retry_after: until.duration_since(now).as_secs().max(1),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn rotate_pairing_code(state: &mut AuthState) {
+        state.pairing_code = PairingCode::generate();
+        state.total_failures = 0;
+    }
+
+    fn record_failure(state: &mut AuthState, ip: Option<IpAddr>, now: Instant) {
+        state.total_failures += 1;
+        if state.total_failures >= MAX_TOTAL_FAILURES {
+            log::warn!("Too many failed pairing attempts, regenerating the pairing code");
+            Self::rotate_pairing_code(state);
+        }
+
+        // 清理锁定已结束且超出统计窗口的记录
+        state.failures.retain(|_, x| {
+            x.locked_until.is_some_and(|until| until > now) || now.duration_since(x.since) <= FAILURE_WINDOW
+        });
+        let Some(ip) = ip else {
+            return;
+        };
+        let failures = state.failures.entry(ip).or_insert(Failures {
+            count: 0,
+            since: now,
+            locked_until: None,
+        });
+        if now.duration_since(failures.since) > FAILURE_WINDOW {
+            failures.count = 0;
+            failures.since = now;
+            failures.locked_until = None;
+        }
+        failures.count += 1;
+        if failures.count >= MAX_FAILURES {
+            log::warn!("Too many failed pairing attempts from {}", ip);
+            failures.locked_until = Some(now + LOCKOUT);
+        }
+    }
+
+    fn create_session(state: &mut AuthState) -> String {
+        let now = Instant::now();
+        state.sessions.retain(|_, expires_at| *expires_at > now);
+        let token = generate_token();
+        state.sessions.insert(token.clone(), now + SESSION_TTL);
+        token
+    }
+
+    pub fn verify(&self, token: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .sessions
+            .get(token)
+            .is_some_and(|expires_at| *expires_at > Instant::now())
+    }
+
+    pub fn logout(&self, token: &str) {
+        self.state.lock().unwrap().sessions.remove(token);
+    }
+
+    pub fn revoke_sessions(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.sessions.clear();
+        state.pairing_tokens.clear();
+    }
+}
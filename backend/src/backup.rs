@@ -0,0 +1,631 @@
+use std::collections::{btree_map::Entry, BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    clash::controller::{ClashError, ClashErrorKind},
+    dashboards,
+    settings::{Settings, ValidationError, ValidationErrorKind},
+    utils,
+};
+
+// 备份包格式版本，不兼容的修改需要递增
+const BUNDLE_FORMAT: u32 = 1;
+const MANIFEST: &str = "manifest.json";
+const SETTINGS: &str = "settings.json";
+const SUBS: &str = "subs";
+const DASHBOARDS: &str = "dashboards";
+// 导入时解压内容的总大小上限
+const MAX_UNPACKED_SIZE: u64 = 256 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    format: u32,
+    version: String,
+    created_at: u64,
+    secret_stripped: bool,
+}
+
+/// 备份包涉及的本地目录
+pub struct BundleDirs {
+    pub subs: PathBuf,
+    pub dashboards: PathBuf,
+}
+
+impl BundleDirs {
+    pub fn local() -> io::Result<Self> {
+        Ok(Self {
+            subs: utils::get_sub_dir()?,
+            dashboards: utils::get_dashboard_dir()?,
+        })
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct ExportOptions {
+    #[serde(default)]
+    pub strip_secret: bool,
+}
+
+/// 导入的文件与本地文件同名时的处理方式
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    #[default]
+    Rename,
+    Skip,
+    Overwrite,
+}
+
+#[derive(Deserialize)]
+pub struct ImportOptions {
+    #[serde(default)]
+    pub conflict: ConflictPolicy,
+    // 是否覆盖端口、DNS 等设置项，关闭时只合并订阅与文件
+    #[serde(default = "default_true")]
+    pub settings: bool,
+    // 保留本机的 secret，忽略备份包中的值
+    #[serde(default)]
+    pub strip_secret: bool,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            conflict: ConflictPolicy::default(),
+            settings: true,
+            strip_secret: false,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RenamedEntry {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ImportReport {
+    pub added: Vec<String>,
+    pub overwritten: Vec<String>,
+    pub renamed: Vec<RenamedEntry>,
+    pub skipped: Vec<String>,
+    pub unchanged: Vec<String>,
+    pub subscriptions_added: usize,
+    pub settings_applied: bool,
+    pub secret_imported: bool,
+}
+
+/// 解析后的备份包
+pub struct Bundle {
+    settings: Settings,
+    // 包内相对路径 -> 文件内容
+    files: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+/// 导入计划，校验设置通过后再写入文件
+pub struct ImportPlan {
+    pub settings: Settings,
+    pub report: ImportReport,
+    writes: Vec<(PathBuf, Vec<u8>)>,
+    removes: Vec<PathBuf>,
+    // 导入后可用的自定义面板
+    dashboards: HashSet<String>,
+}
+
+fn io_error(e: io::Error) -> ClashError {
+    ClashError {
+        message: e.to_string(),
+        error_kind: ClashErrorKind::IOError,
+    }
+}
+
+fn content_error(message: impl Into<String>) -> ClashError {
+    ClashError {
+        message: message.into(),
+        error_kind: ClashErrorKind::ContentError,
+    }
+}
+
+fn file_name(path: &str) -> Option<String> {
+    Path::new(path)
+        .file_name()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_string())
+}
+
+// 只接受由普通路径组成的相对路径，防止解压到目标目录之外
+pub fn is_safe_path(path: &Path) -> bool {
+    path.components().count() > 0
+        && path.components().all(|x| matches!(x, Component::Normal(_)))
+}
+
+fn append_file(
+    builder: &mut tar::Builder<GzEncoder<Vec<u8>>>,
+    path: &Path,
+    data: &[u8],
+    mtime: u64,
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_cksum();
+    builder.append_data(&mut header, path, data)
+}
+
+// 递归收集目录下的普通文件，返回相对路径，目录不存在时为空
+fn walk(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    if !root.is_dir() {
+        return Ok(files);
+    }
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        for entry in fs::read_dir(root.join(&relative))? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let path = relative.join(entry.file_name());
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_file() {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// 导出设置、订阅文件与自定义面板为 tar.gz
+pub fn export(
+    settings: &Settings,
+    dirs: &BundleDirs,
+    options: &ExportOptions,
+) -> Result<Vec<u8>, ClashError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+
+    let mut files: BTreeMap<PathBuf, Vec<u8>> = BTreeMap::new();
+    for path in walk(&dirs.subs).map_err(io_error)? {
+        let data = fs::read(dirs.subs.join(&path)).map_err(io_error)?;
+        files.insert(Path::new(SUBS).join(path), data);
+    }
+    // 订阅目录之外的本地订阅也一并打包，按文件名存放
+    for sub in &settings.subscriptions {
+        let Some(name) = file_name(&sub.path) else {
+            continue;
+        };
+        if let Entry::Vacant(entry) = files.entry(Path::new(SUBS).join(&name)) {
+            if let Ok(data) = fs::read(&sub.path) {
+                entry.insert(data);
+            }
+        }
+    }
+    for path in walk(&dirs.dashboards).map_err(io_error)? {
+        let data = fs::read(dirs.dashboards.join(&path)).map_err(io_error)?;
+        files.insert(Path::new(DASHBOARDS).join(path), data);
+    }
+
+    // 订阅路径只保留文件名，导入时映射到目标设备的订阅目录
+    let mut exported = settings.clone();
+    for sub in exported.subscriptions.iter_mut() {
+        sub.path = file_name(&sub.path).unwrap_or_default();
+    }
+    if options.strip_secret {
+        exported.secret = String::new();
+        // 订阅的登录凭据与请求头同样视为机密
+        for sub in exported.subscriptions.iter_mut() {
+            sub.request.headers.clear();
+            sub.request.basic_auth = None;
+            sub.request.cookie = None;
+        }
+        exported.geodata.request.headers.clear();
+        exported.geodata.request.basic_auth = None;
+        exported.geodata.request.cookie = None;
+    }
+
+    let manifest = Manifest {
+        format: BUNDLE_FORMAT,
+        version: crate::VERSION.to_string(),
+        created_at: now,
+        secret_stripped: options.strip_secret,
+    };
+
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    let json_error = |e: serde_json::Error| content_error(e.to_string());
+    append_file(
+        &mut builder,
+        Path::new(MANIFEST),
+        &serde_json::to_vec_pretty(&manifest).map_err(json_error)?,
+        now,
+    )
+    .map_err(io_error)?;
+    append_file(
+        &mut builder,
+        Path::new(SETTINGS),
+        &serde_json::to_vec_pretty(&exported).map_err(json_error)?,
+        now,
+    )
+    .map_err(io_error)?;
+    for (path, data) in &files {
+        append_file(&mut builder, path, data, now).map_err(io_error)?;
+    }
+    builder
+        .into_inner()
+        .and_then(|x| x.finish())
+        .map_err(io_error)
+}
+
+impl Bundle {
+    pub fn read(data: &[u8]) -> Result<Self, ClashError> {
+        let mut archive = tar::Archive::new(GzDecoder::new(data));
+        let mut manifest: Option<Manifest> = None;
+        let mut settings: Option<Settings> = None;
+        let mut files = BTreeMap::new();
+        let mut total: u64 = 0;
+
+        let bad_archive = |e: io::Error| content_error(format!("Invalid bundle: {}", e));
+        for entry in archive.entries().map_err(bad_archive)? {
+            let mut entry = entry.map_err(bad_archive)?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry.path().map_err(bad_archive)?.into_owned();
+            if !is_safe_path(&path) {
+                return Err(content_error(format!(
+                    "Invalid path in bundle: {}",
+                    path.display()
+                )));
+            }
+            total += entry.size();
+            if total > MAX_UNPACKED_SIZE {
+                return Err(content_error("Bundle is too large"));
+            }
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).map_err(bad_archive)?;
+
+            if path == Path::new(MANIFEST) {
+                manifest = Some(serde_json::from_slice(&data).map_err(|e| {
+                    content_error(format!("Invalid manifest: {}", e))
+                })?);
+            } else if path == Path::new(SETTINGS) {
+                // 旧版本导出的设置按正常加载流程迁移
+                settings = Some(
+                    Settings::from_bytes(&data)
+                        .map_err(|e| content_error(format!("Invalid settings: {}", e)))?,
+                );
+            } else if path.starts_with(SUBS) || path.starts_with(DASHBOARDS) {
+                files.insert(path, data);
+            } else {
+                log::warn!("Ignoring unknown bundle entry {}", path.display());
+            }
+        }
+
+        let manifest = manifest.ok_or_else(|| content_error("Bundle has no manifest"))?;
+        if manifest.format > BUNDLE_FORMAT {
+            return Err(content_error(format!(
+                "Unsupported bundle format {}",
+                manifest.format
+            )));
+        }
+        let settings = settings.ok_or_else(|| content_error("Bundle has no settings"))?;
+        Ok(Self { settings, files })
+    }
+
+    /// 计算导入结果，不修改任何文件
+    pub fn plan(
+        &self,
+        current: &Settings,
+        dirs: &BundleDirs,
+        options: &ImportOptions,
+    ) -> ImportPlan {
+        let mut plan = ImportPlan {
+            settings: current.clone(),
+            report: ImportReport::default(),
+            writes: Vec::new(),
+            removes: Vec::new(),
+            dashboards: HashSet::new(),
+        };
+        let mut reserved: HashSet<PathBuf> = HashSet::new();
+
+        // 订阅文件按单个文件处理冲突
+        let mut sub_paths: BTreeMap<String, PathBuf> = BTreeMap::new();
+        for (path, data) in &self.files {
+            let Ok(root) = path.strip_prefix(SUBS) else {
+                continue;
+            };
+            let target = dirs.subs.join(root);
+            let name = path.to_string_lossy().to_string();
+            let resolved = if !target.exists() && !reserved.contains(&target) {
+                plan.report.added.push(name);
+                Some(target.clone())
+            } else if fs::read(&target).is_ok_and(|x| x == *data) {
+                plan.report.unchanged.push(name);
+                None
+            } else {
+                match options.conflict {
+                    ConflictPolicy::Skip => {
+                        plan.report.skipped.push(name);
+                        None
+                    }
+                    ConflictPolicy::Overwrite => {
+                        plan.report.overwritten.push(name);
+                        Some(target.clone())
+                    }
+                    ConflictPolicy::Rename => match available_path(&target, &reserved) {
+                        Some(renamed) => {
+                            plan.report.renamed.push(RenamedEntry {
+                                from: name,
+                                to: renamed.to_string_lossy().to_string(),
+                            });
+                            Some(renamed)
+                        }
+                        None => {
+                            plan.report.skipped.push(name);
+                            None
+                        }
+                    },
+                }
+            };
+            let local = match resolved {
+                Some(x) => {
+                    reserved.insert(x.clone());
+                    plan.writes.push((x.clone(), data.clone()));
+                    x
+                }
+                None => target,
+            };
+            sub_paths.insert(root.to_string_lossy().to_string(), local);
+        }
+
+        // 面板按整个目录处理冲突，重命名时记录新的目录名
+        let mut dashboard_names: HashMap<String, String> = HashMap::new();
+        let mut dashboards: BTreeMap<String, Vec<(&PathBuf, &Vec<u8>)>> = BTreeMap::new();
+        for (path, data) in &self.files {
+            let Ok(relative) = path.strip_prefix(DASHBOARDS) else {
+                continue;
+            };
+            if let Some(Component::Normal(name)) = relative.components().next() {
+                dashboards
+                    .entry(name.to_string_lossy().to_string())
+                    .or_default()
+                    .push((path, data));
+            }
+        }
+        for (name, entries) in dashboards {
+            let target = dirs.dashboards.join(&name);
+            let label = format!("{}/{}", DASHBOARDS, name);
+            let destination = if !target.exists() {
+                plan.report.added.push(label);
+                Some(target.clone())
+            } else {
+                match options.conflict {
+                    ConflictPolicy::Skip => {
+                        plan.report.skipped.push(label);
+                        None
+                    }
+                    ConflictPolicy::Overwrite => {
+                        plan.report.overwritten.push(label);
+                        plan.removes.push(target.clone());
+                        Some(target.clone())
+                    }
+                    ConflictPolicy::Rename => match available_path(&target, &reserved) {
+                        Some(renamed) => {
+                            plan.report.renamed.push(RenamedEntry {
+                                from: label,
+                                to: renamed.to_string_lossy().to_string(),
+                            });
+                            Some(renamed)
+                        }
+                        None => {
+                            plan.report.skipped.push(label);
+                            None
+                        }
+                    },
+                }
+            };
+            let Some(destination) = destination else {
+                continue;
+            };
+            reserved.insert(destination.clone());
+            let prefix = Path::new(DASHBOARDS).join(&name);
+            let installed = destination.file_name().map(|x| x.to_string_lossy().to_string());
+            if let Some(installed) = installed {
+                let has_index = entries.iter().any(|(x, _)| *x == &prefix.join("index.html"));
+                if has_index && dashboards::is_valid_name(&installed) {
+                    plan.dashboards.insert(installed.clone());
+                }
+                if installed != name {
+                    dashboard_names.insert(name.clone(), installed);
+                }
+            }
+            for (path, data) in entries {
+                if let Ok(relative) = path.strip_prefix(&prefix) {
+                    plan.writes.push((destination.join(relative), data.clone()));
+                }
+            }
+        }
+
+        // 订阅列表按本地路径合并
+        let local_path = |name: &str| -> Option<PathBuf> {
+            let name = file_name(name)?;
+            Some(
+                sub_paths
+                    .get(&name)
+                    .cloned()
+                    .unwrap_or_else(|| dirs.subs.join(&name)),
+            )
+        };
+        // 备份包中的订阅 id -> 导入后的本地 id
+        let mut ids: HashMap<Uuid, Uuid> = HashMap::new();
+        for sub in &self.settings.subscriptions {
+            let Some(path) = local_path(&sub.path) else {
+                continue;
+            };
+            let path = path.to_string_lossy().to_string();
+            match plan.settings.subscriptions.iter_mut().find(|x| x.path == path) {
+                Some(existing) => {
+                    if options.conflict == ConflictPolicy::Overwrite {
+                        existing.url = sub.url.clone();
+                    }
+                    ids.insert(sub.id, existing.id);
+                }
+                None => {
+                    let mut imported = sub.clone();
+                    imported.path = path;
+                    if plan.settings.subscriptions.iter().any(|x| x.id == sub.id) {
+                        imported.id = Uuid::new_v4();
+                    }
+                    ids.insert(sub.id, imported.id);
+                    plan.settings.subscriptions.push(imported);
+                    plan.report.subscriptions_added += 1;
+                }
+            }
+        }
+
+        if options.settings {
+            let imported = &self.settings;
+            let settings = &mut plan.settings;
+            settings.backend_port = imported.backend_port;
+            settings.external_port = imported.external_port;
+            settings.skip_proxy = imported.skip_proxy;
+            settings.override_dns = imported.override_dns;
+            settings.enhanced_mode = imported.enhanced_mode;
+            settings.allow_remote_access = imported.allow_remote_access;
+            settings.dashboard = dashboard_names
+                .get(&imported.dashboard)
+                .cloned()
+                .unwrap_or_else(|| imported.dashboard.clone());
+            settings.enable_external_server = imported.enable_external_server;
+            settings.external_tls = imported.external_tls;
+            settings.log_level = imported.log_level;
+            settings.geodata = imported.geodata.clone();
+            settings.rule_presets = imported.rule_presets.clone();
+            settings.custom_rules = imported.custom_rules.clone();
+            settings.game_routes = imported.game_routes.clone();
+            if let Some(id) = imported.current_sub.and_then(|x| ids.get(&x)) {
+                settings.current_sub = Some(*id);
+            }
+            if !options.strip_secret && !imported.secret.is_empty() {
+                settings.secret = imported.secret.clone();
+                plan.report.secret_imported = true;
+            }
+            plan.report.settings_applied = true;
+        }
+
+        plan
+    }
+}
+
+/// 导入过程中被修改的文件，失败时用于还原
+#[derive(Default)]
+struct ImportUndo {
+    // (备份目录, 原目录)
+    moved: Vec<(PathBuf, PathBuf)>,
+    // (文件, 写入前的内容)
+    written: Vec<(PathBuf, Option<Vec<u8>>)>,
+}
+
+impl ImportUndo {
+    fn rollback(self) {
+        for (path, data) in self.written.into_iter().rev() {
+            let result = match data {
+                Some(data) => fs::write(&path, data),
+                None => fs::remove_file(&path),
+            };
+            if let Err(e) = result {
+                log::warn!("Failed to restore {}: {}", path.display(), e);
+            }
+        }
+        for (backup, dir) in self.moved.into_iter().rev() {
+            let _ = fs::remove_dir_all(&dir);
+            if let Err(e) = fs::rename(&backup, &dir) {
+                log::warn!("Failed to restore {}: {}", dir.display(), e);
+            }
+        }
+    }
+
+    fn commit(self) {
+        for (backup, _) in self.moved {
+            let _ = fs::remove_dir_all(backup);
+        }
+    }
+}
+
+impl ImportPlan {
+    /// 校验导入后的设置，备份包自带的面板视为已安装
+    pub fn validate(&self) -> Vec<ValidationError> {
+        self.settings
+            .validate()
+            .into_iter()
+            .filter(|x| {
+                x.kind != ValidationErrorKind::UnknownDashboard
+                    || !self.dashboards.contains(&self.settings.dashboard)
+            })
+            .collect()
+    }
+
+    /// 写入计划中的文件，任一步失败时还原已修改的文件
+    pub fn apply(&self) -> Result<(), ClashError> {
+        let mut undo = ImportUndo::default();
+        match self.apply_files(&mut undo) {
+            Ok(()) => {
+                undo.commit();
+                Ok(())
+            }
+            Err(e) => {
+                undo.rollback();
+                Err(io_error(e))
+            }
+        }
+    }
+
+    fn apply_files(&self, undo: &mut ImportUndo) -> io::Result<()> {
+        // 要替换的目录先移到一旁，成功后再删除
+        for dir in &self.removes {
+            let mut backup = dir.clone().into_os_string();
+            backup.push(".import-backup");
+            let backup = PathBuf::from(backup);
+            let _ = fs::remove_dir_all(&backup);
+            fs::rename(dir, &backup)?;
+            undo.moved.push((backup, dir.clone()));
+        }
+        for (path, data) in &self.writes {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            undo.written.push((path.clone(), fs::read(path).ok()));
+            fs::write(path, data)?;
+        }
+        Ok(())
+    }
+}
+
+// 与下载订阅相同的命名方式：name_1.yaml、name_2.yaml ...
+fn available_path(path: &Path, reserved: &HashSet<PathBuf>) -> Option<PathBuf> {
+    let stem = path.file_stem()?.to_string_lossy().to_string();
+    let extension = path.extension().map(|x| x.to_string_lossy().to_string());
+    (1..=128)
+        .map(|i| {
+            let name = match &extension {
+                Some(ext) => format!("{}_{}.{}", stem, i, ext),
+                None => format!("{}_{}", stem, i),
+            };
+            path.with_file_name(name)
+        })
+        .find(|x| !x.exists() && !reserved.contains(x))
+}
@@ -8,26 +8,89 @@ use tokio::process::Command;
 use tokio::select;
 use tokio::sync::oneshot;
 
+use crate::dashboards::{DashboardDirs, DashboardSource};
+use crate::games;
+use crate::providers::{self, ProviderKind};
+use crate::rules;
+use crate::settings::Settings;
 use crate::utils;
 
 use serde_json::json;
 
-#[derive(Debug, Serialize, Deserialize, Copy, Clone)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
 pub enum EnhancedMode {
     RedirHost,
     FakeIp,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Silent,
+    Error,
+    Warning,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Silent => "silent",
+            Self::Error => "error",
+            Self::Warning => "warning",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
 pub enum ClashErrorKind {
     ContentError,
     NotFoundError,
     NetworkError,
     IOError,
     KernelError,
+    // 与正在进行的操作冲突
+    ConflictError,
     OtherError,
 }
 
+/// 运行中内核报告的 provider 状态
+#[derive(Serialize, Clone, Debug)]
+pub struct ProviderInfo {
+    pub kind: ProviderKind,
+    pub name: String,
+    pub vehicle_type: String,
+    pub behavior: Option<String>,
+    pub updated_at: Option<String>,
+    // 节点数或规则数
+    pub count: usize,
+}
+
+/// 解析内核 /providers/{proxies,rules} 的响应
+pub fn parse_providers(kind: ProviderKind, data: &serde_json::Value) -> Vec<ProviderInfo> {
+    let Some(providers) = data.get("providers").and_then(|x| x.as_object()) else {
+        return Vec::new();
+    };
+    providers
+        .iter()
+        .filter(|(_, value)| value["vehicleType"].as_str() != Some("Compatible"))
+        .map(|(name, value)| ProviderInfo {
+            kind,
+            name: name.clone(),
+            vehicle_type: value["vehicleType"].as_str().unwrap_or_default().to_string(),
+            behavior: value["behavior"].as_str().map(|x| x.to_string()),
+            updated_at: value["updatedAt"].as_str().map(|x| x.to_string()),
+            count: match kind {
+                ProviderKind::Proxy => value["proxies"].as_array().map_or(0, |x| x.len()),
+                ProviderKind::Rule => value["ruleCount"].as_u64().unwrap_or_default() as usize,
+            },
+        })
+        .collect()
+}
+
 #[derive(Debug)]
 pub struct ClashError {
     pub message: String,
@@ -50,6 +113,8 @@ pub struct Controller {
     path: std::path::PathBuf,
     config: std::path::PathBuf,
     shutdown_tx: Option<oneshot::Sender<()>>,
+    // 内核当前生效的 secret，轮换后需用旧值完成最后一次重载
+    core_secret: Option<String>,
 }
 
 impl Default for Controller {
@@ -60,23 +125,16 @@ impl Default for Controller {
                 .unwrap()
                 .join("config.yaml"),
             shutdown_tx: None,
+            core_secret: None,
         }
     }
 }
 
 impl Controller {
-    pub async fn run(
-        &mut self,
-        config_path: &String,
-        skip_proxy: bool,
-        override_dns: bool,
-        allow_remote_access: bool,
-        enhanced_mode: EnhancedMode,
-        dashboard: String,
-    ) -> Result<(), ClashError> {
+    pub async fn run(&mut self, config_path: &str, settings: &Settings) -> Result<(), ClashError> {
         // decky 插件数据目录
         let decky_data_dir = utils::get_decky_data_dir().unwrap();
-        let clash_dir = utils::get_current_working_dir().unwrap().join("bin/core");
+        let clash_dir = utils::get_core_dir().unwrap();
 
         // 检查 decky_data_dir 是否存在，不存在则创建
         if !decky_data_dir.exists() {
@@ -85,13 +143,7 @@ impl Controller {
 
         self.update_config_path(config_path);
         // 修改配置文件为推荐配置
-        match self.change_config(
-            skip_proxy,
-            override_dns,
-            allow_remote_access,
-            enhanced_mode,
-            dashboard,
-        ) {
+        match self.change_config(settings) {
             Ok(_) => (),
             Err(e) => {
                 return Err(ClashError {
@@ -112,6 +164,14 @@ impl Controller {
             }
         })?;
 
+        // 以 `:` 分隔的目录列表
+        let safe_paths = [utils::get_provider_dir(), utils::get_dashboard_dir()]
+            .into_iter()
+            .flatten()
+            .map(|x| x.to_string_lossy().to_string())
+            .collect::<Vec<_>>()
+            .join(":");
+
         log::info!("Starting Clash...");
 
         let mut program = Command::new(self.path.clone())
@@ -119,6 +179,8 @@ impl Controller {
             .arg(clash_dir)
             .arg("-f")
             .arg(run_config)
+            // provider 缓存与自定义面板不在内核工作目录下，需要加入允许的路径
+            .env("SAFE_PATHS", safe_paths)
             .stdout(outputs)
             .stderr(errors)
             .spawn()
@@ -129,6 +191,7 @@ impl Controller {
             }})?;
         let (tx, rx) = oneshot::channel();
         self.shutdown_tx = Some(tx);
+        self.core_secret = Some(settings.secret.clone());
         tokio::spawn(async move {
             select! {
                 r = program.wait() => {
@@ -151,17 +214,32 @@ impl Controller {
         Ok(())
     }
 
-    pub fn update_config_path(&mut self, path: &String) {
-        self.config = std::path::PathBuf::from((*path).clone());
+    pub fn update_config_path(&mut self, path: &str) {
+        self.config = std::path::PathBuf::from(path);
     }
 
     pub fn get_running_config(&self) -> std::io::Result<std::path::PathBuf> {
-        let decky_data_dir = utils::get_decky_data_dir().unwrap();
-        let run_config = decky_data_dir.join("running_config.yaml");
-        Ok(run_config)
+        running_config_path()
+    }
+
+    /// 内核 external-controller 当前使用的 secret
+    pub fn controller_secret(&self) -> Option<String> {
+        self.core_secret
+            .clone()
+            .or_else(|| self.get_running_secret().ok())
     }
 
-    pub async fn reload_config(&self) -> Result<(), ClashError> {
+    /// 为发往内核 external-controller 的请求附加鉴权头
+    fn authorize(&self, request: minreq::Request) -> minreq::Request {
+        match self.controller_secret() {
+            Some(secret) if !secret.is_empty() => {
+                request.with_header("Authorization", format!("Bearer {}", secret))
+            }
+            _ => request,
+        }
+    }
+
+    pub async fn reload_config(&mut self) -> Result<(), ClashError> {
         let run_config = self.get_running_config().unwrap();
         log::info!("Reloading Clash config, config: {}", run_config.display());
 
@@ -173,7 +251,8 @@ impl Controller {
 
         let body_str = serde_json::to_string(&body).unwrap();
 
-        let res = match minreq::put(url)
+        let res = match self
+            .authorize(minreq::put(url))
             .with_header("Content-Type", "application/json")
             .with_body(body_str)
             .send().await
@@ -190,6 +269,10 @@ impl Controller {
 
         if res.status_code == 200 || res.status_code == 204 {
             log::info!("Clash config reloaded successfully");
+            // 重载后内核使用运行配置中的 secret
+            if let Ok(secret) = self.get_running_secret() {
+                self.core_secret = Some(secret);
+            }
             Ok(())
         } else {
             log::error!(
@@ -203,6 +286,149 @@ impl Controller {
         }
     }
 
+    /// 通过 PATCH /configs 热更新内核配置，无需重新生成配置文件
+    pub async fn patch_config(&self, patch: serde_json::Value) -> Result<(), ClashError> {
+        log::info!("Patching Clash config: {}", patch);
+
+        let url = "http://127.0.0.1:9090/configs";
+        let res = self
+            .authorize(minreq::patch(url))
+            .with_header("Content-Type", "application/json")
+            .with_body(patch.to_string())
+            .send()
+            .await
+            .map_err(|e| ClashError {
+                message: e.to_string(),
+                error_kind: ClashErrorKind::IOError,
+            })?;
+
+        if res.status_code == 200 || res.status_code == 204 {
+            Ok(())
+        } else {
+            log::error!("Failed to patch Clash config, status_code {}", res.status_code);
+            Err(ClashError {
+                message: format!("Failed to patch Clash config, status code {}", res.status_code),
+                error_kind: ClashErrorKind::KernelError,
+            })
+        }
+    }
+
+    async fn get_core_json(&self, path: &str) -> Result<serde_json::Value, ClashError> {
+        let url = format!("http://127.0.0.1:9090{}", path);
+        let res = self
+            .authorize(minreq::get(url))
+            .send()
+            .await
+            .map_err(|e| ClashError {
+                message: e.to_string(),
+                error_kind: ClashErrorKind::IOError,
+            })?;
+        if res.status_code != 200 {
+            return Err(ClashError {
+                message: format!("Request to {} failed, status code {}", path, res.status_code),
+                error_kind: ClashErrorKind::KernelError,
+            });
+        }
+        let body = res.as_str().map_err(|e| ClashError {
+            message: e.to_string(),
+            error_kind: ClashErrorKind::ContentError,
+        })?;
+        serde_json::from_str(body).map_err(|e| ClashError {
+            message: e.to_string(),
+            error_kind: ClashErrorKind::ContentError,
+        })
+    }
+
+    /// 列出运行中内核的 proxy 与 rule provider，不含内核自动生成的 Compatible provider
+    pub async fn get_providers(&self) -> Result<Vec<ProviderInfo>, ClashError> {
+        let mut result = Vec::new();
+        for kind in [ProviderKind::Proxy, ProviderKind::Rule] {
+            let data = self
+                .get_core_json(&format!("/providers/{}", kind.api_path()))
+                .await?;
+            result.extend(parse_providers(kind, &data));
+        }
+        Ok(result)
+    }
+
+    /// 通过内核的 DNS 解析域名，结果不受 fake-ip 影响
+    pub async fn resolve_host(&self, name: &str) -> Result<Option<std::net::IpAddr>, ClashError> {
+        let data = self
+            .get_core_json(&format!("/dns/query?name={}&type=A", urlencoding::encode(name)))
+            .await?;
+        Ok(data["Answer"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find_map(|x| x["data"].as_str()?.parse().ok()))
+    }
+
+    /// 从策略开始沿策略组当前选择的节点展开，不包含策略本身
+    pub async fn get_policy_chain(&self, policy: &str) -> Result<Vec<String>, ClashError> {
+        let data = self.get_core_json("/proxies").await?;
+        let mut chain: Vec<String> = Vec::new();
+        let mut current = policy.to_string();
+        while let Some(next) = data["proxies"][current.as_str()]["now"].as_str() {
+            if next == policy || chain.iter().any(|x| x == next) {
+                break;
+            }
+            chain.push(next.to_string());
+            current = next.to_string();
+        }
+        Ok(chain)
+    }
+
+    async fn send_provider_request(
+        &self,
+        request: minreq::Request,
+        action: &str,
+        name: &str,
+    ) -> Result<(), ClashError> {
+        let res = self.authorize(request).send().await.map_err(|e| ClashError {
+            message: e.to_string(),
+            error_kind: ClashErrorKind::IOError,
+        })?;
+        match res.status_code {
+            200 | 204 => {
+                log::info!("Provider {} {} successfully", name, action);
+                Ok(())
+            }
+            404 => Err(ClashError {
+                message: format!("Provider not found: {}", name),
+                error_kind: ClashErrorKind::NotFoundError,
+            }),
+            code => {
+                let data = res.as_str().unwrap_or_default();
+                log::error!("Failed to {} provider {}: {}", action, name, data);
+                Err(ClashError {
+                    message: format!("Failed to {} provider {}, status code {}", action, name, code),
+                    error_kind: ClashErrorKind::KernelError,
+                })
+            }
+        }
+    }
+
+    /// 让内核重新下载 provider
+    pub async fn update_provider(&self, kind: ProviderKind, name: &str) -> Result<(), ClashError> {
+        let url = format!(
+            "http://127.0.0.1:9090/providers/{}/{}",
+            kind.api_path(),
+            urlencoding::encode(name)
+        );
+        self.send_provider_request(minreq::put(url), "update", name)
+            .await
+    }
+
+    /// 对 proxy provider 中的节点进行健康检查，内核在检查完成后才返回
+    pub async fn health_check_provider(&self, name: &str) -> Result<(), ClashError> {
+        let url = format!(
+            "http://127.0.0.1:9090/providers/proxies/{}/healthcheck",
+            urlencoding::encode(name)
+        );
+        self.send_provider_request(minreq::get(url), "health check", name)
+            .await
+    }
+
     pub async fn restart_core(&self) -> Result<(), ClashError> {
         log::info!("Restarting Clash core...");
 
@@ -212,7 +438,8 @@ impl Controller {
         });
         let body_str = serde_json::to_string(&body).unwrap();
 
-        let res = match minreq::post(url)
+        let res = match self
+            .authorize(minreq::post(url))
             .with_header("Content-Type", "application/json")
             .with_body(body_str)
             .send().await
@@ -240,24 +467,42 @@ impl Controller {
         }
     }
 
-    pub fn change_config(
+    pub fn change_config(&self, settings: &Settings) -> Result<(), Box<dyn error::Error>> {
+        log::info!("change_config path: {:?}", self.config);
+
+        let yaml = self.render_config(&self.config, settings)?;
+
+        let run_config = self.get_running_config()?;
+
+        let yaml_str = serde_yaml::to_string(&yaml)?;
+
+        match fs::write(run_config, yaml_str) {
+            Ok(_) => {
+                log::info!("Clash config changed successfully");
+            }
+            Err(e) => {
+                log::error!("Error occurred while changing Clash config: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 根据订阅文件和设置生成运行配置，只返回结果，不写入也不应用
+    pub fn render_config<P: AsRef<std::path::Path>>(
         &self,
-        skip_proxy: bool,
-        override_dns: bool,
-        allow_remote_access: bool,
-        enhanced_mode: EnhancedMode,
-        dashboard: String,
-    ) -> Result<(), Box<dyn error::Error>> {
-        let path = self.config.clone();
-        log::info!("change_config path: {:?}", path);
-
-        let config = fs::read_to_string(path)?;
-        let mut yaml: serde_yaml::Value = serde_yaml::from_str(config.as_str())?;
-        let yaml = yaml.as_mapping_mut().unwrap();
+        source: P,
+        settings: &Settings,
+    ) -> Result<Value, Box<dyn error::Error>> {
+        let config = fs::read_to_string(source)?;
+        let mut root: serde_yaml::Value = serde_yaml::from_str(config.as_str())?;
+        let yaml = root
+            .as_mapping_mut()
+            .ok_or("The profile is not a YAML mapping")?;
 
         log::info!("Changing Clash config...");
 
-        let external_ip = if allow_remote_access {
+        let external_ip = if settings.allow_remote_access {
             "0.0.0.0"
         } else {
             "127.0.0.1"
@@ -266,38 +511,60 @@ impl Controller {
         //修改 WebUI
         match yaml.get_mut("external-controller") {
             Some(x) => {
-                *x = Value::String(String::from(format!("{}:9090", external_ip)));
+                *x = Value::String(format!("{}:9090", external_ip));
             }
             None => {
                 yaml.insert(
                     Value::String(String::from("external-controller")),
-                    Value::String(String::from(format!("{}:9090", external_ip))),
+                    Value::String(format!("{}:9090", external_ip)),
                 );
             }
         }
 
+        // 自定义规则与预设需要检查配置中的策略组，在修改配置前生成
+        let profile = Value::Mapping(yaml.clone());
+        let custom = rules::custom_rules(&settings.custom_rules, &profile);
+        // 只有设置了游戏路由时才扫描 Steam 库
+        let games = match settings.game_routes.is_empty() {
+            true => Vec::new(),
+            false => games::game_rules(
+                &settings.game_routes,
+                &games::list_games(&utils::get_steam_dir()),
+                &profile,
+            ),
+        };
+        let presets = rules::preset_rules(&settings.rule_presets, &profile);
+
+        // 没有 rules 的配置补上空列表以便注入规则，未匹配的流量仍然直连
+        if !yaml.contains_key("rules") {
+            yaml.insert(Value::String(String::from("rules")), Value::Sequence(Vec::new()));
+        }
+
         //修改 test.steampowered.com
         //这个域名用于 Steam Deck 网络连接验证，可以直连
         if let Some(x) = yaml.get_mut("rules") {
-            let rules = x.as_sequence_mut().unwrap();
-            rules.insert(
-                0,
-                Value::String(String::from("DOMAIN,test.steampowered.com,DIRECT")),
-            );
-
-            if skip_proxy {
-                rules.insert(
-                    0,
-                    Value::String(String::from("DOMAIN-SUFFIX,cm.steampowered.com,DIRECT")),
-                );
-                rules.insert(
-                    0,
-                    Value::String(String::from("DOMAIN-SUFFIX,steamserver.net,DIRECT")),
-                );
+            let rules = x.as_sequence_mut().ok_or_else(|| ClashError {
+                message: String::from("rules in the profile must be a list"),
+                error_kind: ClashErrorKind::ContentError,
+            })?;
+            let mut injected = Vec::new();
+            if settings.skip_proxy {
+                injected.push(String::from("DOMAIN-SUFFIX,steamserver.net,DIRECT"));
+                injected.push(String::from("DOMAIN-SUFFIX,cm.steampowered.com,DIRECT"));
             }
+            injected.push(String::from("DOMAIN,test.steampowered.com,DIRECT"));
+            injected.extend(custom);
+            injected.extend(games);
+            injected.extend(presets);
+            rules.splice(0..0, injected.into_iter().map(Value::String));
         }
 
-        let webui_dir = utils::get_current_working_dir()?.join("bin/core/web");
+        // 自定义面板不在 bin/core/web 中，external-ui 指向面板所在的目录
+        let dashboard_dirs = DashboardDirs::local()?;
+        let webui_dir = match dashboard_dirs.find(&settings.dashboard) {
+            Some(x) if x.source == DashboardSource::Custom => dashboard_dirs.custom,
+            _ => dashboard_dirs.bundled,
+        };
 
         match yaml.get_mut("external-ui") {
             Some(x) => {
@@ -315,12 +582,12 @@ impl Controller {
         //  修改 dashboard 名称
         match yaml.get_mut("external-ui-name") {
             Some(x) => {
-                *x = Value::String(String::from(dashboard));
+                *x = Value::String(settings.dashboard.clone());
             }
             None => {
                 yaml.insert(
                     Value::String(String::from("external-ui-name")),
-                    Value::String(String::from(dashboard)),
+                    Value::String(settings.dashboard.clone()),
                 );
             }
         }
@@ -423,10 +690,10 @@ impl Controller {
         match yaml.get("dns") {
             Some(_) => {
                 //删除 DNS 配置
-                if override_dns {
-                    log::info!("EnhancedMode: {:?}", enhanced_mode);
+                if settings.override_dns {
+                    log::info!("EnhancedMode: {:?}", settings.enhanced_mode);
                     yaml.remove("dns").unwrap();
-                    match enhanced_mode {
+                    match settings.enhanced_mode {
                         EnhancedMode::FakeIp => {
                             insert_config(yaml, dns_config_fakeip, "dns");
                         }
@@ -441,17 +708,24 @@ impl Controller {
             }
         }
 
-        // // 如果设置了 secret， 更改 secret 为 "tomoon"
-        // let secret_config = "tomoon";
-        // match yaml.get("secret") {
-        //     Some(_) => {
-        //         yaml.remove("secret").unwrap();
-        //         insert_config(yaml, secret_config, "secret");
-        //     }
-        //     None => {
-        //         insert_config(yaml, secret_config, "secret");
-        //     }
-        // }
+        yaml.insert(
+            Value::String(String::from("log-level")),
+            Value::String(settings.log_level.as_str().to_string()),
+        );
+
+        // 使用 ToMoon 管理的 secret 覆盖订阅中的 secret
+        if settings.secret.is_empty() && settings.allow_remote_access {
+            return Err("Refusing to expose the external controller without a secret".into());
+        }
+        yaml.insert(
+            Value::String(String::from("secret")),
+            Value::String(settings.secret.clone()),
+        );
+
+        // 已预先下载的 provider 直接从本地缓存加载
+        if let Ok(dir) = utils::get_provider_dir() {
+            providers::rewrite_paths(yaml, &dir);
+        }
 
         // 保存上次的配置
         match yaml.get("profile") {
@@ -464,21 +738,7 @@ impl Controller {
             }
         }
 
-        let run_config = self.get_running_config()?;
-
-        let yaml_str = serde_yaml::to_string(&yaml)?;
-
-        match fs::write(run_config, yaml_str) {
-            Ok(_) => {
-                log::info!("Clash config changed successfully");
-            }
-            Err(e) => {
-                log::error!("Error occurred while changing Clash config: {}", e);
-            }
-        }
-
-        log::info!("Clash config changed successfully");
-        Ok(())
+        Ok(root)
     }
 
     pub fn get_running_secret(&self) -> Result<String, Box<dyn error::Error>> {
@@ -494,3 +754,23 @@ impl Controller {
         }
     }
 }
+
+fn running_config_path() -> std::io::Result<std::path::PathBuf> {
+    let decky_data_dir = utils::get_decky_data_dir()?;
+    let run_config = decky_data_dir.join("running_config.yaml");
+    Ok(run_config)
+}
+
+/// 运行中内核提供的本地代理地址，依次使用 mixed-port、port、socks-port
+pub fn get_running_proxy() -> Option<String> {
+    if !utils::is_clash_running() {
+        return None;
+    }
+    let content = fs::read_to_string(running_config_path().ok()?).ok()?;
+    let yaml: Value = serde_yaml::from_str(&content).ok()?;
+    let port = |key: &str| yaml.get(key).and_then(|x| x.as_u64()).filter(|x| *x > 0);
+    if let Some(port) = port("mixed-port").or_else(|| port("port")) {
+        return Some(format!("http://127.0.0.1:{}", port));
+    }
+    port("socks-port").map(|port| format!("socks5h://127.0.0.1:{}", port))
+}
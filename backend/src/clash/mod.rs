@@ -1,2 +1,3 @@
 pub mod controller;
+pub mod preview;
 pub mod runtime;
\ No newline at end of file
@@ -0,0 +1,171 @@
+use serde::Serialize;
+use serde_yaml::{Mapping, Value};
+
+const REDACTED: &str = "******";
+
+// 规则列表差异计算的最大规模，超过后退化为整体删除 + 整体新增
+const MAX_LCS_CELLS: usize = 4_000_000;
+
+#[derive(Serialize, Debug, Default)]
+pub struct ConfigDiff {
+    pub added: Mapping,
+    pub removed: Mapping,
+    pub changed: Vec<ChangedEntry>,
+    pub rules: RulesDiff,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChangedEntry {
+    pub key: String,
+    pub original: Value,
+    pub running: Value,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct RulesDiff {
+    pub added: Vec<RuleLine>,
+    pub removed: Vec<RuleLine>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct RuleLine {
+    pub index: usize,
+    pub rule: String,
+}
+
+/// 隐藏配置中的 secret，避免在预览中泄露
+pub fn redact_secret(config: &mut Value) {
+    if let Some(secret) = config.get_mut("secret") {
+        if !secret.as_str().unwrap_or("").is_empty() {
+            *secret = Value::String(REDACTED.to_string());
+        }
+    }
+}
+
+impl ConfigDiff {
+    /// 比较原始订阅与运行配置的顶层键，`rules` 按条目单独比较
+    pub fn between(original: &Value, running: &Value) -> Self {
+        let empty = Mapping::new();
+        let original = original.as_mapping().unwrap_or(&empty);
+        let running = running.as_mapping().unwrap_or(&empty);
+
+        let mut diff = ConfigDiff::default();
+        for (key, value) in running {
+            match original.get(key) {
+                None => {
+                    diff.added.insert(key.clone(), value.clone());
+                }
+                Some(old) if old != value && key.as_str() != Some("rules") => {
+                    diff.changed.push(ChangedEntry {
+                        key: key_name(key),
+                        original: old.clone(),
+                        running: value.clone(),
+                    });
+                }
+                _ => (),
+            }
+        }
+        for (key, value) in original {
+            if !running.contains_key(key) {
+                diff.removed.insert(key.clone(), value.clone());
+            }
+        }
+
+        diff.rules = diff_rules(
+            &rule_lines(original.get("rules")),
+            &rule_lines(running.get("rules")),
+        );
+        diff
+    }
+}
+
+fn key_name(key: &Value) -> String {
+    match key.as_str() {
+        Some(x) => x.to_string(),
+        None => serde_yaml::to_string(key)
+            .unwrap_or_default()
+            .trim_end()
+            .to_string(),
+    }
+}
+
+fn rule_lines(rules: Option<&Value>) -> Vec<String> {
+    rules
+        .and_then(|x| x.as_sequence())
+        .map(|x| {
+            x.iter()
+                .map(|rule| match rule.as_str() {
+                    Some(s) => s.to_string(),
+                    None => key_name(rule),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn diff_rules(original: &[String], running: &[String]) -> RulesDiff {
+    let prefix = original
+        .iter()
+        .zip(running)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = original[prefix..]
+        .iter()
+        .rev()
+        .zip(running[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old = &original[prefix..original.len() - suffix];
+    let new = &running[prefix..running.len() - suffix];
+
+    let mut diff = RulesDiff::default();
+    if old.len().saturating_mul(new.len()) > MAX_LCS_CELLS {
+        diff.removed = lines_from(old, prefix);
+        diff.added = lines_from(new, prefix);
+        return diff;
+    }
+
+    // 最长公共子序列，lcs[i][j] 为 old[i..] 与 new[j..] 的公共长度
+    let mut lcs = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            diff.added.push(RuleLine {
+                index: prefix + j,
+                rule: new[j].clone(),
+            });
+            j += 1;
+        } else {
+            diff.removed.push(RuleLine {
+                index: prefix + i,
+                rule: old[i].clone(),
+            });
+            i += 1;
+        }
+    }
+    diff
+}
+
+fn lines_from(lines: &[String], offset: usize) -> Vec<RuleLine> {
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, rule)| RuleLine {
+            index: offset + i,
+            rule: rule.clone(),
+        })
+        .collect()
+}
@@ -1,24 +1,219 @@
-use std::sync::{Arc, RwLock};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
 
+use serde_json::json;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::RwLock;
+use tokio::task::LocalSet;
+
+use crate::auth::Authenticator;
 use crate::utils;
-use crate::settings::SettingsInstance;
+use crate::settings::{SettingsChange, SettingsInstance};
+use crate::jobs::JobRegistry;
+use crate::providers;
 
 use super::controller::Controller;
 
+// 等待连续的设置修改，合并后一次性应用
+const REACT_DELAY: Duration = Duration::from_millis(300);
+
 #[derive(Clone)]
 pub struct Runtime {
     pub settings: SettingsInstance,
     pub controller: Arc<RwLock<Controller>>,
+    pub auth: Authenticator,
+    pub jobs: JobRegistry,
+}
+
+/// 设置变更需要对内核执行的操作，按影响从小到大排列
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ApplyAction {
+    None,
+    HotPatch,
+    Regenerate,
+    Restart,
 }
 
+impl ApplyAction {
+    pub fn for_change(change: &SettingsChange) -> Self {
+        match change {
+            SettingsChange::LogLevel(_) => Self::HotPatch,
+            SettingsChange::SkipProxy(_)
+            | SettingsChange::OverrideDns(_)
+            | SettingsChange::EnhancedMode(_)
+            | SettingsChange::CurrentSub(_)
+            | SettingsChange::Dashboard(_)
+            | SettingsChange::RulePresets
+            | SettingsChange::CustomRules
+            | SettingsChange::GameRoutes
+            | SettingsChange::Secret => Self::Regenerate,
+            // external-controller 的监听地址需要重启内核才能可靠生效
+            SettingsChange::AllowRemoteAccess(_) => Self::Restart,
+            SettingsChange::BackendPort(_)
+            | SettingsChange::ExternalPort(_)
+            | SettingsChange::EnableExternalServer(_)
+            | SettingsChange::ExternalTls(_)
+            | SettingsChange::Subscriptions
+            | SettingsChange::Geodata => Self::None,
+        }
+    }
+}
+
+/// 在独立线程的 LocalSet 中运行后台任务。
+/// minreq 请求的 future 不是 Send，无法直接交给 tokio::spawn。
+pub fn spawn_background<F, Fut>(name: &str, task: F)
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + 'static,
+{
+    let name = name.to_string();
+    let result = std::thread::Builder::new().name(name.clone()).spawn(move || {
+        let rt = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("Failed to start background task {}: {}", name, e);
+                return;
+            }
+        };
+        LocalSet::new().block_on(&rt, task());
+    });
+    if let Err(e) = result {
+        log::error!("Failed to spawn background thread: {}", e);
+    }
+}
 
 impl Runtime {
     pub fn new() -> Self {
         let settings_path = utils::get_settings_path().unwrap();
         let clash = Controller::default();
+        // 设置无法读写时仍以默认设置启动，避免插件无法使用
+        let settings = SettingsInstance::open(&settings_path).unwrap_or_else(|e| {
+            log::error!("Failed to open settings, using defaults: {}", e);
+            SettingsInstance::new(&settings_path)
+        });
         Self {
-            settings: SettingsInstance::open(settings_path).unwrap(),
+            settings,
             controller: Arc::new(RwLock::new(clash)),
+            auth: Authenticator::default(),
+            jobs: JobRegistry::default(),
+        }
+    }
+
+    /// 监听设置变更，并按需热更新、重新生成配置或重启内核
+    pub fn spawn_reactor(&self) {
+        let runtime = self.clone();
+        let mut changes = self.settings.subscribe();
+        spawn_background("settings-reactor", move || async move {
+            loop {
+                let mut lagged = false;
+                let mut batch = Vec::new();
+                match changes.recv().await {
+                    Ok(x) => batch.push(x),
+                    Err(RecvError::Lagged(_)) => lagged = true,
+                    Err(RecvError::Closed) => break,
+                }
+                tokio::time::sleep(REACT_DELAY).await;
+                loop {
+                    match changes.try_recv() {
+                        Ok(x) => batch.push(x),
+                        Err(TryRecvError::Lagged(_)) => lagged = true,
+                        Err(_) => break,
+                    }
+                }
+                runtime.apply_changes(&batch, lagged).await;
+            }
+        });
+    }
+
+    /// 定期刷新已过期的 provider 缓存，并清理不再使用的文件
+    pub fn spawn_provider_refresh(&self) {
+        let runtime = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(providers::PROVIDER_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let dir = match utils::get_provider_dir() {
+                    Ok(x) => x,
+                    Err(e) => {
+                        log::error!("Failed to get provider dir: {}", e);
+                        continue;
+                    }
+                };
+                let subs = runtime.settings.get().subscriptions;
+                let enabled = subs.iter().filter(|x| x.enabled).cloned().collect();
+                let job = providers::start_refresh(&runtime.jobs, enabled, dir.clone(), true);
+                runtime.jobs.wait(&job.id).await;
+                providers::remove_unused(&subs, &dir);
+            }
+        });
+    }
+
+    async fn apply_changes(&self, changes: &[SettingsChange], lagged: bool) {
+        for change in changes {
+            if matches!(
+                change,
+                SettingsChange::BackendPort(_)
+                    | SettingsChange::ExternalPort(_)
+                    | SettingsChange::EnableExternalServer(_)
+                    | SettingsChange::ExternalTls(_)
+            ) {
+                log::info!("{:?} takes effect after restarting ToMoon", change);
+            }
+        }
+
+        let mut action = changes
+            .iter()
+            .map(ApplyAction::for_change)
+            .max()
+            .unwrap_or(ApplyAction::None);
+        // 丢失过事件时无法确定改了什么，重新生成最稳妥
+        if lagged {
+            action = action.max(ApplyAction::Regenerate);
+        }
+        if action == ApplyAction::None {
+            return;
+        }
+        if !utils::is_clash_running() {
+            log::info!("Clash is not running, settings will apply on next start");
+            return;
+        }
+
+        let settings = self.settings.get();
+        let mut clash = self.controller.write().await;
+        if changes
+            .iter()
+            .any(|x| matches!(x, SettingsChange::CurrentSub(_)))
+        {
+            if let Some(path) = settings.current_sub_path() {
+                clash.update_config_path(path);
+            }
+        }
+
+        log::info!("Applying settings changes with {:?}", action);
+        let result = match action {
+            ApplyAction::None => Ok(()),
+            ApplyAction::HotPatch => {
+                clash
+                    .patch_config(json!({ "log-level": settings.log_level.as_str() }))
+                    .await
+            }
+            ApplyAction::Regenerate | ApplyAction::Restart => {
+                match clash.change_config(&settings) {
+                    Err(e) => {
+                        log::error!("Failed to regenerate Clash config: {}", e);
+                        return;
+                    }
+                    Ok(_) if action == ApplyAction::Restart => clash.restart_core().await,
+                    Ok(_) => clash.reload_config().await,
+                }
+            }
+        };
+        if let Err(e) = result {
+            log::error!("Failed to apply settings changes: {}", e);
         }
     }
 }
@@ -0,0 +1,280 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use serde::Serialize;
+
+use crate::{
+    backup::is_safe_path,
+    clash::controller::{ClashError, ClashErrorKind},
+    utils,
+};
+
+// 解压后的总大小与文件数量上限
+const MAX_UNPACKED_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_ENTRIES: usize = 10000;
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DashboardSource {
+    // 随插件发布，位于 bin/core/web
+    Bundled,
+    // 用户安装，可以删除
+    Custom,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Dashboard {
+    pub name: String,
+    pub source: DashboardSource,
+    pub path: PathBuf,
+}
+
+impl Dashboard {
+    /// 面板中的文件，空路径与目录对应其中的 index.html
+    pub fn file(&self, path: &str) -> Option<PathBuf> {
+        let path = path.trim_matches('/');
+        let file = match path.is_empty() {
+            true => self.path.clone(),
+            false if is_safe_path(Path::new(path)) => self.path.join(path),
+            false => return None,
+        };
+        let file = match file.is_dir() {
+            true => file.join("index.html"),
+            false => file,
+        };
+        file.is_file().then_some(file)
+    }
+}
+
+/// 面板所在的目录
+pub struct DashboardDirs {
+    pub bundled: PathBuf,
+    pub custom: PathBuf,
+}
+
+impl DashboardDirs {
+    pub fn local() -> io::Result<Self> {
+        Ok(Self {
+            bundled: utils::get_current_working_dir()?.join("bin/core/web"),
+            custom: utils::get_dashboard_dir()?,
+        })
+    }
+
+    fn scan(dir: &Path, source: DashboardSource) -> Vec<Dashboard> {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        let mut dashboards: Vec<Dashboard> = entries
+            .flatten()
+            .filter(|x| x.path().join("index.html").is_file())
+            .filter_map(|x| {
+                Some(Dashboard {
+                    name: x.file_name().to_str()?.to_string(),
+                    source,
+                    path: x.path(),
+                })
+            })
+            .filter(|x| is_valid_name(&x.name))
+            .collect();
+        dashboards.sort_by(|x, y| x.name.cmp(&y.name));
+        dashboards
+    }
+
+    /// 列出包含 index.html 的面板，与内置面板同名的自定义面板不会生效
+    pub fn list(&self) -> Vec<Dashboard> {
+        let mut dashboards = Self::scan(&self.bundled, DashboardSource::Bundled);
+        for dashboard in Self::scan(&self.custom, DashboardSource::Custom) {
+            if !dashboards.iter().any(|x| x.name == dashboard.name) {
+                dashboards.push(dashboard);
+            }
+        }
+        dashboards
+    }
+
+    pub fn find(&self, name: &str) -> Option<Dashboard> {
+        if !is_valid_name(name) {
+            return None;
+        }
+        self.list().into_iter().find(|x| x.name == name)
+    }
+
+    /// 从 zip、tar 或 tar.gz 安装自定义面板，`replace` 为 true 时覆盖同名的自定义面板
+    pub fn install(&self, archive: &[u8], name: &str, replace: bool) -> Result<Dashboard, ClashError> {
+        if !is_valid_name(name) {
+            return Err(content_error(format!("Invalid dashboard name {:?}", name)));
+        }
+        if Self::scan(&self.bundled, DashboardSource::Bundled)
+            .iter()
+            .any(|x| x.name == name)
+        {
+            return Err(content_error(format!("{} is a bundled dashboard", name)));
+        }
+        let target = self.custom.join(name);
+        if target.exists() && !replace {
+            return Err(content_error(format!("Dashboard {} is already installed", name)));
+        }
+
+        // 先解压到临时目录，确认包含 index.html 后再替换
+        fs::create_dir_all(&self.custom).map_err(io_error)?;
+        let temp = self.custom.join(format!(".{}.part", name));
+        if temp.exists() {
+            fs::remove_dir_all(&temp).map_err(io_error)?;
+        }
+        let result = unpack(archive, &temp).and_then(|_| {
+            let root = find_root(&temp)
+                .ok_or_else(|| content_error("The archive does not contain index.html"))?;
+            if target.exists() {
+                fs::remove_dir_all(&target).map_err(io_error)?;
+            }
+            fs::rename(&root, &target).map_err(io_error)
+        });
+        if temp.exists() {
+            let _ = fs::remove_dir_all(&temp);
+        }
+        result?;
+
+        log::info!("Dashboard {} installed to {}", name, target.display());
+        Ok(Dashboard {
+            name: name.to_string(),
+            source: DashboardSource::Custom,
+            path: target,
+        })
+    }
+
+    /// 删除自定义面板，内置面板不能删除
+    pub fn remove(&self, name: &str) -> Result<(), ClashError> {
+        match self.find(name) {
+            Some(x) if x.source == DashboardSource::Custom => {
+                fs::remove_dir_all(&x.path).map_err(io_error)?;
+                log::info!("Dashboard {} removed", name);
+                Ok(())
+            }
+            Some(_) => Err(content_error(format!("{} is a bundled dashboard", name))),
+            None => Err(ClashError {
+                message: format!("Dashboard not found: {}", name),
+                error_kind: ClashErrorKind::NotFoundError,
+            }),
+        }
+    }
+}
+
+fn io_error(e: io::Error) -> ClashError {
+    ClashError {
+        message: e.to_string(),
+        error_kind: ClashErrorKind::IOError,
+    }
+}
+
+fn content_error(message: impl Into<String>) -> ClashError {
+    ClashError {
+        message: message.into(),
+        error_kind: ClashErrorKind::ContentError,
+    }
+}
+
+/// 面板名称即目录名，不能包含路径分隔符
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || matches!(x, '-' | '_' | '.'))
+}
+
+/// 由压缩包文件名得到默认的面板名称，如 `metacubexd.tgz` -> `metacubexd`
+pub fn name_from_file(path: &Path) -> Option<String> {
+    let mut name = path.file_name()?.to_str()?;
+    for extension in [".zip", ".tgz", ".gz", ".tar"] {
+        name = name.strip_suffix(extension).unwrap_or(name);
+    }
+    is_valid_name(name).then(|| name.to_string())
+}
+
+// index.html 位于根目录或唯一的顶层目录中
+fn find_root(dir: &Path) -> Option<PathBuf> {
+    if dir.join("index.html").is_file() {
+        return Some(dir.to_path_buf());
+    }
+    let entries: Vec<_> = fs::read_dir(dir).ok()?.flatten().collect();
+    match entries.as_slice() {
+        [entry] if entry.path().join("index.html").is_file() => Some(entry.path()),
+        _ => None,
+    }
+}
+
+struct Unpacker<'a> {
+    dir: &'a Path,
+    total: u64,
+    entries: usize,
+}
+
+impl Unpacker<'_> {
+    fn write(&mut self, path: &Path, size: u64, reader: impl Read) -> Result<(), ClashError> {
+        // 打包时常带有 `./` 前缀
+        let path: PathBuf = path.components().filter(|x| *x != Component::CurDir).collect();
+        if !is_safe_path(&path) {
+            return Err(content_error(format!("Invalid path in archive: {}", path.display())));
+        }
+        self.entries += 1;
+        self.total += size;
+        if self.entries > MAX_ENTRIES || self.total > MAX_UNPACKED_SIZE {
+            return Err(content_error("The archive is too large"));
+        }
+        let target = self.dir.join(&path);
+        fs::create_dir_all(target.parent().unwrap()).map_err(io_error)?;
+        let mut file = fs::File::create(&target).map_err(io_error)?;
+        // 实际大小以读取到的内容为准，避免声明的大小与内容不符
+        let written = io::copy(&mut reader.take(size + 1), &mut file).map_err(io_error)?;
+        if written > size {
+            return Err(content_error("The archive is corrupted"));
+        }
+        Ok(())
+    }
+}
+
+fn unpack(archive: &[u8], dir: &Path) -> Result<(), ClashError> {
+    fs::create_dir_all(dir).map_err(io_error)?;
+    let mut unpacker = Unpacker { dir, total: 0, entries: 0 };
+    match archive {
+        [b'P', b'K', 3, 4, ..] => unpack_zip(archive, &mut unpacker),
+        [0x1f, 0x8b, ..] => unpack_tar(GzDecoder::new(archive), &mut unpacker),
+        _ if archive.get(257..262) == Some(b"ustar") => unpack_tar(archive, &mut unpacker),
+        _ => Err(content_error("Unsupported archive, expected zip, tar or tar.gz")),
+    }
+}
+
+fn unpack_tar(reader: impl Read, unpacker: &mut Unpacker) -> Result<(), ClashError> {
+    let bad_archive = |e: io::Error| content_error(format!("Invalid archive: {}", e));
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries().map_err(bad_archive)? {
+        let entry = entry.map_err(bad_archive)?;
+        // 目录随文件创建，链接等其他类型忽略
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path().map_err(bad_archive)?.into_owned();
+        let size = entry.size();
+        unpacker.write(&path, size, entry)?;
+    }
+    Ok(())
+}
+
+fn unpack_zip(data: &[u8], unpacker: &mut Unpacker) -> Result<(), ClashError> {
+    let bad_archive = |e: zip::result::ZipError| content_error(format!("Invalid archive: {}", e));
+    let mut archive = zip::ZipArchive::new(io::Cursor::new(data)).map_err(bad_archive)?;
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i).map_err(bad_archive)?;
+        // 目录随文件创建，链接忽略
+        if !entry.is_file() {
+            continue;
+        }
+        let path = PathBuf::from(entry.name().map_err(bad_archive)?.as_ref());
+        let size = entry.size();
+        unpacker.write(&path, size, entry)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,263 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::rules::{self, PresetAction};
+
+// 扫描游戏目录时的最大深度与可执行文件数量
+const SCAN_MAX_DEPTH: usize = 4;
+const SCAN_MAX_EXECUTABLES: usize = 64;
+// 运行库与安装程序目录中的可执行文件不是游戏本身
+const SKIPPED_DIRS: [&str; 4] = ["_commonredist", "__installer", "redist", "redistributables"];
+// Steam 安装的兼容层与运行库，不是游戏
+const TOOL_PREFIXES: [&str; 3] = ["Proton", "Steam Linux Runtime", "Steamworks Common Redistributables"];
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct GameExecutable {
+    pub path: PathBuf,
+    // .exe 通过 Proton 运行，只能按文件名匹配
+    pub windows: bool,
+}
+
+impl GameExecutable {
+    pub fn to_rule(&self, policy: &str) -> Option<String> {
+        let rule = match self.windows {
+            true => format!("PROCESS-NAME,{},{}", self.path.file_name()?.to_str()?, policy),
+            false => format!("PROCESS-PATH,{},{}", self.path.to_str()?, policy),
+        };
+        // 规则以逗号分隔，包含逗号的路径无法表示
+        match rule.matches(',').count() {
+            2 => Some(rule),
+            _ => None,
+        }
+    }
+}
+
+/// 本地 Steam 库中已安装的游戏
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct SteamGame {
+    pub app_id: u32,
+    pub name: String,
+    pub install_dir: PathBuf,
+}
+
+impl SteamGame {
+    pub fn executables(&self) -> Vec<GameExecutable> {
+        let mut found = Vec::new();
+        scan_executables(&self.install_dir, 0, &mut found);
+        found.sort_by(|x, y| x.path.cmp(&y.path));
+        found
+    }
+}
+
+// 跳过空白与 `//` 注释
+fn skip_blank(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    while let Some(c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if *c == '/' {
+            chars.next();
+            while chars.next().is_some_and(|x| x != '\n') {}
+        } else {
+            break;
+        }
+    }
+}
+
+fn read_token(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+    skip_blank(chars);
+    let mut token = String::new();
+    if chars.peek() == Some(&'"') {
+        chars.next();
+        loop {
+            match chars.next()? {
+                '"' => return Some(token),
+                '\\' => match chars.next()? {
+                    'n' => token.push('\n'),
+                    't' => token.push('\t'),
+                    x => token.push(x),
+                },
+                x => token.push(x),
+            }
+        }
+    }
+    while let Some(c) = chars.peek() {
+        if c.is_whitespace() || matches!(c, '{' | '}' | '"') {
+            break;
+        }
+        token.push(*c);
+        chars.next();
+    }
+    (!token.is_empty()).then_some(token)
+}
+
+fn parse_object(chars: &mut std::iter::Peekable<std::str::Chars>, depth: usize) -> Option<Map<String, Value>> {
+    let mut map = Map::new();
+    loop {
+        skip_blank(chars);
+        match chars.peek() {
+            None if depth == 0 => return Some(map),
+            None => return None,
+            Some('}') if depth > 0 => {
+                chars.next();
+                return Some(map);
+            }
+            _ => {}
+        }
+        let key = read_token(chars)?;
+        skip_blank(chars);
+        let value = match chars.peek() {
+            Some('{') => {
+                chars.next();
+                Value::Object(parse_object(chars, depth + 1)?)
+            }
+            _ => Value::String(read_token(chars)?),
+        };
+        map.insert(key, value);
+    }
+}
+
+/// 解析 Steam 的 KeyValues 文本（.acf / .vdf）
+pub fn parse_vdf(content: &str) -> Option<Map<String, Value>> {
+    parse_object(&mut content.chars().peekable(), 0)
+}
+
+fn read_vdf(path: &Path) -> Option<Map<String, Value>> {
+    parse_vdf(&std::fs::read_to_string(path).ok()?)
+}
+
+/// Steam 库目录，包含 Steam 安装目录与 libraryfolders.vdf 中的其他库
+pub fn library_folders(steam_dir: &Path) -> Vec<PathBuf> {
+    let mut folders = vec![steam_dir.to_path_buf()];
+    let libraries = read_vdf(&steam_dir.join("steamapps/libraryfolders.vdf"));
+    let entries = libraries
+        .as_ref()
+        .and_then(|x| x.get("libraryfolders"))
+        .and_then(|x| x.as_object());
+    for entry in entries.into_iter().flat_map(|x| x.values()) {
+        if let Some(path) = entry.get("path").and_then(|x| x.as_str()) {
+            let path = PathBuf::from(path);
+            if !folders.contains(&path) {
+                folders.push(path);
+            }
+        }
+    }
+    folders
+}
+
+fn read_manifest(path: &Path, library: &Path) -> Option<SteamGame> {
+    let manifest = read_vdf(path)?;
+    let state = manifest.get("AppState")?;
+    let name = state.get("name")?.as_str()?.to_string();
+    if TOOL_PREFIXES.iter().any(|x| name.starts_with(x)) {
+        return None;
+    }
+    Some(SteamGame {
+        app_id: state.get("appid")?.as_str()?.parse().ok()?,
+        name,
+        install_dir: library
+            .join("steamapps/common")
+            .join(state.get("installdir")?.as_str()?),
+    })
+}
+
+/// 列出所有库中已安装的游戏，按名称排序
+pub fn list_games(steam_dir: &Path) -> Vec<SteamGame> {
+    let mut seen = HashSet::new();
+    let mut games = Vec::new();
+    for library in library_folders(steam_dir) {
+        let Ok(entries) = std::fs::read_dir(library.join("steamapps")) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if !file_name.starts_with("appmanifest_") || !file_name.ends_with(".acf") {
+                continue;
+            }
+            match read_manifest(&entry.path(), &library) {
+                Some(game) if seen.insert(game.app_id) => games.push(game),
+                Some(_) => {}
+                None => log::debug!("Skipping Steam manifest {}", entry.path().display()),
+            }
+        }
+    }
+    games.sort_by_key(|x| x.name.to_lowercase());
+    games
+}
+
+fn is_elf(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    std::fs::File::open(path)
+        .and_then(|mut x| x.read_exact(&mut magic))
+        .is_ok()
+        && magic == *b"\x7fELF"
+}
+
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+fn scan_executables(dir: &Path, depth: usize, found: &mut Vec<GameExecutable>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if found.len() >= SCAN_MAX_EXECUTABLES {
+            return;
+        }
+        let path = entry.path();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let name = entry.file_name().to_string_lossy().to_lowercase();
+        if metadata.is_dir() {
+            if depth < SCAN_MAX_DEPTH && !SKIPPED_DIRS.contains(&name.as_str()) {
+                scan_executables(&path, depth + 1, found);
+            }
+        } else if name.ends_with(".exe") {
+            found.push(GameExecutable { path, windows: true });
+        } else if is_executable(&metadata) && is_elf(&path) {
+            found.push(GameExecutable { path, windows: false });
+        }
+    }
+}
+
+/// 生成游戏的进程规则，未安装的游戏与不在配置中的策略组会被跳过
+pub fn game_rules(
+    routes: &BTreeMap<u32, PresetAction>,
+    games: &[SteamGame],
+    config: &serde_yaml::Value,
+) -> Vec<String> {
+    let groups: HashSet<String> = rules::proxy_groups(config).into_iter().collect();
+    let mut result = Vec::new();
+    for (app_id, action) in routes {
+        let policy = action.policy();
+        let Some(game) = games.iter().find(|x| x.app_id == *app_id) else {
+            log::debug!("Skipping route for app {}: not installed", app_id);
+            continue;
+        };
+        if !rules::is_known_policy(policy, &groups) {
+            log::warn!("Skipping route for {}: policy {} is not in the profile", game.name, policy);
+            continue;
+        }
+        let rules: Vec<String> = game
+            .executables()
+            .iter()
+            .filter_map(|x| x.to_rule(policy))
+            .collect();
+        if rules.is_empty() {
+            log::warn!("No executable found for {} in {}", game.name, game.install_dir.display());
+        }
+        // 同一文件名的 .exe 在不同目录中只需一条规则
+        for rule in rules {
+            if !result.contains(&rule) {
+                result.push(rule);
+            }
+        }
+    }
+    result
+}
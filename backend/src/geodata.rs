@@ -0,0 +1,512 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::sync::{oneshot, RwLock};
+
+use crate::{
+    clash::{
+        controller::{ClashError, ClashErrorKind, Controller},
+        runtime::spawn_background,
+    },
+    jobs::{ItemStatus, Job, JobItem, JobKind, JobRegistry},
+    settings::GeodataSettings,
+    subscriptions, utils,
+};
+
+// MMDB 元数据段的起始标记
+const MMDB_METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+// 元数据位于文件末尾 128KB 内
+const MMDB_METADATA_MAX_SIZE: usize = 128 * 1024;
+// 解析元数据时的最大嵌套层数
+const MMDB_MAX_DEPTH: usize = 8;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GeoDatabase {
+    Mmdb,
+    GeoSite,
+    GeoIp,
+    Asn,
+}
+
+impl GeoDatabase {
+    pub const ALL: [GeoDatabase; 4] = [Self::Mmdb, Self::GeoSite, Self::GeoIp, Self::Asn];
+
+    /// 内核默认使用的文件名，查找时不区分大小写
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Self::Mmdb => "Country.mmdb",
+            Self::GeoSite => "GeoSite.dat",
+            Self::GeoIp => "GeoIP.dat",
+            Self::Asn => "ASN.mmdb",
+        }
+    }
+
+    pub fn url<'a>(&self, settings: &'a GeodataSettings) -> &'a str {
+        match self {
+            Self::Mmdb => &settings.mmdb_url,
+            Self::GeoSite => &settings.geosite_url,
+            Self::GeoIp => &settings.geoip_url,
+            Self::Asn => &settings.asn_url,
+        }
+    }
+
+    fn is_mmdb(&self) -> bool {
+        matches!(self, Self::Mmdb | Self::Asn)
+    }
+
+    /// 内核实际读取的文件，目录中已有同名（忽略大小写）文件时使用该文件
+    pub fn locate(&self, dir: &Path) -> PathBuf {
+        let existing = std::fs::read_dir(dir).ok().and_then(|entries| {
+            entries
+                .flatten()
+                .find(|x| x.file_name().to_string_lossy().eq_ignore_ascii_case(self.file_name()))
+        });
+        match existing {
+            Some(x) => x.path(),
+            None => dir.join(self.file_name()),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct GeoFileInfo {
+    pub database: GeoDatabase,
+    pub path: String,
+    pub url: String,
+    pub exists: bool,
+    pub size: u64,
+    // 修改时间与距今的秒数
+    pub modified_at: Option<u64>,
+    pub age: Option<u64>,
+    // MMDB 的 database_type 与 build_epoch，.dat 文件没有版本信息
+    pub version: Option<String>,
+    pub build_epoch: Option<u64>,
+}
+
+// 按 MaxMind DB 格式解析一个数据字段
+fn decode_field(data: &[u8], offset: &mut usize, depth: usize) -> Option<Value> {
+    if depth > MMDB_MAX_DEPTH {
+        return None;
+    }
+    let mut next = || {
+        let byte = *data.get(*offset)?;
+        *offset += 1;
+        Some(byte)
+    };
+    let control = next()?;
+    let mut kind = control >> 5;
+    // 指针指向数据段中的其他位置，解析目标后不移动当前偏移以外的位置
+    if kind == 1 {
+        let extra = ((control >> 3) & 0x3) as usize;
+        let mut pointer = match extra {
+            3 => 0,
+            _ => (control & 0x7) as usize,
+        };
+        for _ in 0..=extra {
+            pointer = (pointer << 8) | next()? as usize;
+        }
+        pointer += match extra {
+            1 => 2048,
+            2 => 526336,
+            _ => 0,
+        };
+        return decode_field(data, &mut pointer, depth + 1);
+    }
+    if kind == 0 {
+        kind = 7 + next()?;
+    }
+    let mut size = (control & 0x1f) as usize;
+    if size >= 29 {
+        let extra = size - 28;
+        let mut value = 0usize;
+        for _ in 0..extra {
+            value = (value << 8) | next()? as usize;
+        }
+        size = match extra {
+            1 => 29 + value,
+            2 => 285 + value,
+            _ => 65821 + value,
+        };
+    }
+
+    let mut take = |len: usize| {
+        let bytes = data.get(*offset..*offset + len)?;
+        *offset += len;
+        Some(bytes)
+    };
+    let unsigned = |bytes: &[u8]| bytes.iter().fold(0u64, |x, b| (x << 8) | *b as u64);
+    match kind {
+        2 => Some(Value::String(String::from_utf8_lossy(take(size)?).to_string())),
+        3 => Some(Value::from(f64::from_be_bytes(take(8)?.try_into().ok()?))),
+        5 | 6 | 9 if size <= 8 => Some(Value::from(unsigned(take(size)?))),
+        7 => {
+            let mut map = Map::new();
+            for _ in 0..size {
+                let key = decode_field(data, offset, depth + 1)?;
+                let value = decode_field(data, offset, depth + 1)?;
+                map.insert(key.as_str()?.to_string(), value);
+            }
+            Some(Value::Object(map))
+        }
+        11 => {
+            let mut array = Vec::new();
+            for _ in 0..size {
+                array.push(decode_field(data, offset, depth + 1)?);
+            }
+            Some(Value::Array(array))
+        }
+        14 => Some(Value::Bool(size != 0)),
+        // 元数据中用不到的类型只跳过
+        4 | 8 | 10 => {
+            take(size)?;
+            Some(Value::Null)
+        }
+        15 => {
+            take(4)?;
+            Some(Value::Null)
+        }
+        _ => None,
+    }
+}
+
+/// 读取 MMDB 文件末尾的元数据
+pub fn mmdb_metadata(data: &[u8]) -> Option<Map<String, Value>> {
+    let tail = &data[data.len().saturating_sub(MMDB_METADATA_MAX_SIZE)..];
+    let start = tail
+        .windows(MMDB_METADATA_MARKER.len())
+        .rposition(|x| x == MMDB_METADATA_MARKER)?
+        + MMDB_METADATA_MARKER.len();
+    let mut offset = 0;
+    match decode_field(&tail[start..], &mut offset, 0)? {
+        Value::Object(x) => Some(x),
+        _ => None,
+    }
+}
+
+/// 在 MMDB 的搜索树中查找地址，返回对应的数据记录
+pub fn mmdb_lookup(data: &[u8], ip: IpAddr) -> Option<Value> {
+    let metadata = mmdb_metadata(data)?;
+    let field = |name: &str| metadata.get(name).and_then(|x| x.as_u64()).map(|x| x as usize);
+    let node_count = field("node_count")?;
+    let record_size = field("record_size")?;
+    let node_size = record_size / 4;
+    // IPv6 数据库中的 IPv4 地址位于 ::/96 之下
+    let bytes = match (ip, field("ip_version")?) {
+        (IpAddr::V4(x), 6) => x.to_ipv6_compatible().octets().to_vec(),
+        (IpAddr::V4(x), _) => x.octets().to_vec(),
+        (IpAddr::V6(x), 6) => x.octets().to_vec(),
+        (IpAddr::V6(_), _) => return None,
+    };
+
+    let record = |node: usize, right: bool| -> Option<usize> {
+        let n = data.get(node * node_size..(node + 1) * node_size)?;
+        let read = |bytes: &[u8]| bytes.iter().fold(0usize, |x, b| (x << 8) | *b as usize);
+        match (record_size, right) {
+            (24, false) => Some(read(&n[0..3])),
+            (24, true) => Some(read(&n[3..6])),
+            (28, false) => Some(((n[3] as usize >> 4) << 24) | read(&n[0..3])),
+            (28, true) => Some(((n[3] as usize & 0x0f) << 24) | read(&n[4..7])),
+            (32, false) => Some(read(&n[0..4])),
+            (32, true) => Some(read(&n[4..8])),
+            _ => None,
+        }
+    };
+    let mut node = 0;
+    let mut bit = 0;
+    while node < node_count {
+        let byte = *bytes.get(bit / 8)?;
+        node = record(node, (byte >> (7 - bit % 8)) & 1 == 1)?;
+        bit += 1;
+    }
+    // 等于 node_count 表示没有记录
+    let mut offset = node.checked_sub(node_count + 16)?;
+    let section = data.get(node_count * node_size + 16..)?;
+    decode_field(section, &mut offset, 0)
+}
+
+/// 按 GEOIP 规则的写法查找地址所属的国家代码
+pub fn country_code(data: &[u8], ip: IpAddr) -> Option<String> {
+    let record = mmdb_lookup(data, ip)?;
+    let country = record.get("country").or_else(|| record.get("registered_country"))?;
+    Some(country.get("iso_code")?.as_str()?.to_string())
+}
+
+fn read_varint(data: &[u8], offset: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*offset)?;
+        *offset += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// 读取一个 protobuf 字段，长度前缀的字段返回其内容，其余类型只返回数值
+fn read_proto_field<'a>(data: &'a [u8], offset: &mut usize) -> Option<(u64, u64, &'a [u8])> {
+    let key = read_varint(data, offset)?;
+    match key & 0x7 {
+        0 => Some((key >> 3, read_varint(data, offset)?, &[])),
+        2 => {
+            let len = read_varint(data, offset)? as usize;
+            let bytes = data.get(*offset..offset.checked_add(len)?)?;
+            *offset += len;
+            Some((key >> 3, 0, bytes))
+        }
+        1 => {
+            *offset += 8;
+            Some((key >> 3, 0, &[]))
+        }
+        5 => {
+            *offset += 4;
+            Some((key >> 3, 0, &[]))
+        }
+        _ => None,
+    }
+}
+
+// GeoSite 中的单条域名：类型 0 为关键字，1 为正则，2 为域名及子域名，3 为完整域名
+fn geosite_domain_matches(entry: &[u8], domain: &str, attribute: Option<&str>) -> bool {
+    let mut kind = 0;
+    let mut value = "";
+    let mut attributes = Vec::new();
+    let mut offset = 0;
+    while offset < entry.len() {
+        let Some((field, number, bytes)) = read_proto_field(entry, &mut offset) else {
+            return false;
+        };
+        match field {
+            1 => kind = number,
+            2 => value = std::str::from_utf8(bytes).unwrap_or_default(),
+            3 => {
+                let mut inner = 0;
+                if let Some((1, _, key)) = read_proto_field(bytes, &mut inner) {
+                    attributes.push(String::from_utf8_lossy(key).to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    if attribute.is_some_and(|x| !attributes.iter().any(|y| y.eq_ignore_ascii_case(x))) {
+        return false;
+    }
+    match kind {
+        0 => domain.contains(value),
+        1 => regex::Regex::new(value).is_ok_and(|x| x.is_match(domain)),
+        2 => domain == value || domain.ends_with(&format!(".{}", value)),
+        3 => domain == value,
+        _ => false,
+    }
+}
+
+/// 判断域名是否属于 GeoSite 列表，`code@attr` 只匹配带有该属性的条目，列表不存在时返回 None
+pub fn geosite_contains(data: &[u8], code: &str, domain: &str) -> Option<bool> {
+    let (code, attribute) = match code.split_once('@') {
+        Some((code, attribute)) => (code, Some(attribute)),
+        None => (code, None),
+    };
+    let mut offset = 0;
+    while offset < data.len() {
+        let (field, _, site) = read_proto_field(data, &mut offset)?;
+        if field != 1 {
+            continue;
+        }
+        let mut inner = 0;
+        // 第一个字段为列表名称，不匹配时跳过整个列表
+        match read_proto_field(site, &mut inner) {
+            Some((1, _, name)) if name.eq_ignore_ascii_case(code.as_bytes()) => {}
+            _ => continue,
+        }
+        while inner < site.len() {
+            let (field, _, entry) = read_proto_field(site, &mut inner)?;
+            if field == 2 && geosite_domain_matches(entry, domain, attribute) {
+                return Some(true);
+            }
+        }
+        return Some(false);
+    }
+    None
+}
+
+/// 检查下载的数据库是否完整可用
+pub fn verify(database: GeoDatabase, data: &[u8]) -> Result<(), ClashError> {
+    let invalid = |message: &str| ClashError {
+        message: format!("{} is invalid: {}", database.file_name(), message),
+        error_kind: ClashErrorKind::ContentError,
+    };
+    if database.is_mmdb() {
+        let metadata = mmdb_metadata(data).ok_or_else(|| invalid("missing MMDB metadata"))?;
+        if metadata.get("node_count").and_then(|x| x.as_u64()).unwrap_or_default() == 0 {
+            return Err(invalid("empty search tree"));
+        }
+    } else if data.first() != Some(&0x0a) {
+        // .dat 为 protobuf，第一个字段是长度前缀的条目列表
+        return Err(invalid("not a geodata list"));
+    }
+    Ok(())
+}
+
+pub fn file_info(database: GeoDatabase, dir: &Path, settings: &GeodataSettings) -> GeoFileInfo {
+    let path = database.locate(dir);
+    let metadata = std::fs::metadata(&path).ok();
+    let modified = metadata
+        .as_ref()
+        .and_then(|x| x.modified().ok())
+        .and_then(|x| x.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|x| x.as_secs());
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+
+    let mmdb = match metadata.is_some() && database.is_mmdb() {
+        true => std::fs::read(&path).ok().and_then(|x| mmdb_metadata(&x)),
+        false => None,
+    };
+    let build_epoch = mmdb.as_ref().and_then(|x| x.get("build_epoch")?.as_u64());
+    let version = mmdb.as_ref().and_then(|x| {
+        let database_type = x.get("database_type")?.as_str()?;
+        Some(match build_epoch {
+            Some(epoch) => format!("{} ({})", database_type, epoch),
+            None => database_type.to_string(),
+        })
+    });
+
+    GeoFileInfo {
+        database,
+        path: path.to_string_lossy().to_string(),
+        url: database.url(settings).to_string(),
+        exists: metadata.is_some(),
+        size: metadata.map(|x| x.len()).unwrap_or_default(),
+        modified_at: modified,
+        age: modified.map(|x| now.saturating_sub(x)),
+        version,
+        build_epoch,
+    }
+}
+
+pub fn list(dir: &Path, settings: &GeodataSettings) -> Vec<GeoFileInfo> {
+    GeoDatabase::ALL
+        .iter()
+        .map(|x| file_info(*x, dir, settings))
+        .collect()
+}
+
+/// 下载并校验数据库，校验通过后才替换原文件
+pub async fn update(
+    database: GeoDatabase,
+    dir: &Path,
+    settings: &GeodataSettings,
+) -> Result<(), ClashError> {
+    let url = database.url(settings);
+    let data = match utils::get_file_path(url.to_string()) {
+        Some(path) => tokio::fs::read(path).await.map_err(|e| ClashError {
+            message: e.to_string(),
+            error_kind: ClashErrorKind::IOError,
+        })?,
+        None => {
+            let response = subscriptions::fetch_remote(url, &settings.request).await?;
+            if !response.status().is_success() {
+                return Err(ClashError {
+                    message: format!("Status Code: {}", response.status().as_u16()),
+                    error_kind: ClashErrorKind::NetworkError,
+                });
+            }
+            response
+                .bytes()
+                .await
+                .map_err(|e| ClashError {
+                    message: e.to_string(),
+                    error_kind: ClashErrorKind::NetworkError,
+                })?
+                .to_vec()
+        }
+    };
+    verify(database, &data)?;
+
+    let io_error = |e: std::io::Error| ClashError {
+        message: e.to_string(),
+        error_kind: ClashErrorKind::IOError,
+    };
+    let path = database.locate(dir);
+    let temp = path.with_extension("part");
+    std::fs::create_dir_all(dir).map_err(io_error)?;
+    std::fs::write(&temp, &data).map_err(io_error)?;
+    std::fs::rename(&temp, &path).map_err(io_error)?;
+    log::info!("{} updated from {}", path.display(), url);
+    Ok(())
+}
+
+// 内核只在启动时加载数据库，更新后需要重启
+async fn restart_core(controller: Arc<RwLock<Controller>>) -> Result<(), ClashError> {
+    let (tx, rx) = oneshot::channel();
+    spawn_background("geodata-restart", move || async move {
+        let result = controller.read().await.restart_core().await;
+        let _ = tx.send(result);
+    });
+    rx.await.map_err(|_| ClashError {
+        message: "Failed to restart Clash core".to_string(),
+        error_kind: ClashErrorKind::KernelError,
+    })?
+}
+
+/// 依次更新数据库，至少一个更新成功且内核在运行时重启内核
+pub fn start_update(
+    jobs: &JobRegistry,
+    databases: Vec<GeoDatabase>,
+    dir: PathBuf,
+    settings: GeodataSettings,
+    controller: Arc<RwLock<Controller>>,
+) -> Job {
+    let items = databases
+        .iter()
+        .map(|x| JobItem {
+            id: serde_json::to_value(x)
+                .ok()
+                .and_then(|x| x.as_str().map(|x| x.to_string()))
+                .unwrap_or_default(),
+            name: x.file_name().to_string(),
+            status: ItemStatus::Pending,
+        })
+        .collect();
+
+    jobs.spawn_exclusive(JobKind::UpdateGeodata, items, move |handle| async move {
+        let mut updated = 0;
+        for (index, database) in databases.iter().enumerate() {
+            handle.set_item(index, ItemStatus::Downloading);
+            let status = match update(*database, &dir, &settings).await {
+                Ok(()) => {
+                    updated += 1;
+                    ItemStatus::Ok
+                }
+                Err(e) => {
+                    log::error!("Failed to update {}: {}", database.file_name(), e);
+                    ItemStatus::Failed {
+                        error_kind: e.error_kind,
+                        message: e.message,
+                    }
+                }
+            };
+            handle.set_item(index, status);
+        }
+        if updated == 0 && !databases.is_empty() {
+            return Err(ClashError {
+                message: "No geodata database was updated".to_string(),
+                error_kind: ClashErrorKind::NetworkError,
+            });
+        }
+
+        let restarted = updated > 0 && utils::is_clash_running();
+        if restarted {
+            restart_core(controller).await?;
+        }
+        Ok(serde_json::json!({ "updated": updated, "restarted": restarted }))
+    })
+}
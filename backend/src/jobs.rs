@@ -0,0 +1,326 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use crate::clash::controller::{ClashError, ClashErrorKind};
+
+// 已结束任务的保留时间
+const JOB_RETENTION: Duration = Duration::from_secs(60 * 60);
+// 保留的已结束任务数量上限
+const MAX_FINISHED_JOBS: usize = 32;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    DownloadSub,
+    UpdateSubs,
+    RefreshProviders,
+    UpdateGeodata,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// 任务中单个条目的状态
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ItemStatus {
+    Pending,
+    Downloading,
+    Ok,
+    Failed {
+        error_kind: ClashErrorKind,
+        message: String,
+    },
+    Cancelled,
+}
+
+impl ItemStatus {
+    fn is_done(&self) -> bool {
+        !matches!(self, Self::Pending | Self::Downloading)
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct JobItem {
+    // 订阅 id 或其他条目的标识
+    pub id: String,
+    pub name: String,
+    #[serde(flatten)]
+    pub status: ItemStatus,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct JobError {
+    pub error_kind: ClashErrorKind,
+    pub message: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Job {
+    pub id: Uuid,
+    pub kind: JobKind,
+    pub state: JobState,
+    pub progress: Progress,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub items: Vec<JobItem>,
+    pub result: Option<Value>,
+    pub error: Option<JobError>,
+}
+
+impl Job {
+    pub fn finished(&self) -> bool {
+        self.state != JobState::Running
+    }
+}
+
+struct Entry {
+    job: Job,
+    cancel: watch::Sender<bool>,
+    done: watch::Sender<bool>,
+    finished: Option<Instant>,
+}
+
+/// 后台任务登记表，记录下载、更新等耗时操作的进度与结果
+#[derive(Clone, Default)]
+pub struct JobRegistry {
+    jobs: Arc<Mutex<VecDeque<Entry>>>,
+}
+
+/// 交给任务本身使用，用于汇报进度
+#[derive(Clone)]
+pub struct JobHandle {
+    id: Uuid,
+    registry: JobRegistry,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+// 等待取消信号，发送端被丢弃时永不返回
+async fn wait_true(mut receiver: watch::Receiver<bool>) {
+    if receiver.wait_for(|x| *x).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+impl JobHandle {
+    /// 更新条目状态，进度按已结束的条目计算
+    pub fn set_item(&self, index: usize, status: ItemStatus) {
+        self.registry.with_job(&self.id, |job| {
+            if let Some(item) = job.items.get_mut(index) {
+                item.status = status;
+            }
+            job.progress.done = job.items.iter().filter(|x| x.status.is_done()).count();
+        });
+    }
+}
+
+impl JobRegistry {
+    fn with_job(&self, id: &Uuid, function: impl FnOnce(&mut Job)) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(entry) = jobs.iter_mut().find(|x| x.job.id == *id) {
+            function(&mut entry.job);
+        }
+    }
+
+    /// 在后台运行任务，任务返回的值作为结果保存
+    pub fn spawn<F, Fut>(&self, kind: JobKind, items: Vec<JobItem>, task: F) -> Job
+    where
+        F: FnOnce(JobHandle) -> Fut,
+        Fut: Future<Output = Result<Value, ClashError>> + Send + 'static,
+    {
+        self.start(kind, items, false, task)
+    }
+
+    /// 同类任务正在运行时直接返回该任务，不重复启动
+    pub fn spawn_exclusive<F, Fut>(&self, kind: JobKind, items: Vec<JobItem>, task: F) -> Job
+    where
+        F: FnOnce(JobHandle) -> Fut,
+        Fut: Future<Output = Result<Value, ClashError>> + Send + 'static,
+    {
+        self.start(kind, items, true, task)
+    }
+
+    fn start<F, Fut>(&self, kind: JobKind, items: Vec<JobItem>, exclusive: bool, task: F) -> Job
+    where
+        F: FnOnce(JobHandle) -> Fut,
+        Fut: Future<Output = Result<Value, ClashError>> + Send + 'static,
+    {
+        let (job, handle, cancel) = {
+            let mut jobs = self.jobs.lock().unwrap();
+            if exclusive {
+                if let Some(running) = jobs
+                    .iter()
+                    .find(|x| x.job.kind == kind && !x.job.finished())
+                {
+                    return running.job.clone();
+                }
+            }
+            Self::prune(&mut jobs);
+
+            let job = Job {
+                id: Uuid::new_v4(),
+                kind,
+                state: JobState::Running,
+                progress: Progress {
+                    done: 0,
+                    total: items.len(),
+                },
+                started_at: now(),
+                finished_at: None,
+                items,
+                result: None,
+                error: None,
+            };
+            let (cancel, cancel_rx) = watch::channel(false);
+            jobs.push_back(Entry {
+                job: job.clone(),
+                cancel,
+                done: watch::channel(false).0,
+                finished: None,
+            });
+            let handle = JobHandle {
+                id: job.id,
+                registry: self.clone(),
+            };
+            (job, handle, cancel_rx)
+        };
+
+        log::info!("Job {} ({:?}) started", job.id, kind);
+        let registry = self.clone();
+        let future = task(handle);
+        let id = job.id;
+        tokio::spawn(async move {
+            let result = tokio::select! {
+                result = future => Some(result),
+                _ = wait_true(cancel) => None,
+            };
+            registry.finish(&id, result);
+        });
+        job
+    }
+
+    fn finish(&self, id: &Uuid, result: Option<Result<Value, ClashError>>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(entry) = jobs.iter_mut().find(|x| x.job.id == *id) else {
+            return;
+        };
+        let job = &mut entry.job;
+        match result {
+            Some(Ok(value)) => {
+                job.state = JobState::Succeeded;
+                job.result = Some(value);
+            }
+            Some(Err(e)) => {
+                log::error!("Job {} failed: {}", id, e);
+                job.state = JobState::Failed;
+                job.error = Some(JobError {
+                    error_kind: e.error_kind,
+                    message: e.message,
+                });
+            }
+            None => {
+                log::info!("Job {} cancelled", id);
+                job.state = JobState::Cancelled;
+                job.error = Some(JobError {
+                    error_kind: ClashErrorKind::OtherError,
+                    message: "Job was cancelled".to_string(),
+                });
+                for item in job.items.iter_mut().filter(|x| !x.status.is_done()) {
+                    item.status = ItemStatus::Cancelled;
+                }
+            }
+        }
+        job.finished_at = Some(now());
+        entry.finished = Some(Instant::now());
+        entry.done.send_replace(true);
+    }
+
+    // 清理过期的已结束任务，运行中的任务始终保留
+    fn prune(jobs: &mut VecDeque<Entry>) {
+        jobs.retain(|x| x.finished.is_none_or(|at| at.elapsed() < JOB_RETENTION));
+        let mut excess = jobs
+            .iter()
+            .filter(|x| x.finished.is_some())
+            .count()
+            .saturating_sub(MAX_FINISHED_JOBS);
+        jobs.retain(|x| {
+            if excess > 0 && x.finished.is_some() {
+                excess -= 1;
+                return false;
+            }
+            true
+        });
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<Job> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter().find(|x| x.job.id == *id).map(|x| x.job.clone())
+    }
+
+    /// 按开始时间倒序列出任务
+    pub fn list(&self, kind: Option<JobKind>) -> Vec<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        Self::prune(&mut jobs);
+        jobs.iter()
+            .rev()
+            .filter(|x| kind.is_none_or(|kind| x.job.kind == kind))
+            .map(|x| x.job.clone())
+            .collect()
+    }
+
+    /// 请求取消任务，已结束的任务不受影响
+    pub fn cancel(&self, id: &Uuid) -> Option<Job> {
+        let jobs = self.jobs.lock().unwrap();
+        let entry = jobs.iter().find(|x| x.job.id == *id)?;
+        if !entry.job.finished() {
+            entry.cancel.send_replace(true);
+        }
+        Some(entry.job.clone())
+    }
+
+    /// 等待任务结束并返回最终状态
+    pub async fn wait(&self, id: &Uuid) -> Option<Job> {
+        let done = {
+            let jobs = self.jobs.lock().unwrap();
+            jobs.iter().find(|x| x.job.id == *id)?.done.subscribe()
+        };
+        wait_true(done).await;
+        self.get(id)
+    }
+}
+
+impl From<&JobError> for ClashError {
+    fn from(e: &JobError) -> Self {
+        ClashError {
+            message: e.message.clone(),
+            error_kind: e.error_kind,
+        }
+    }
+}
+
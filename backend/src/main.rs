@@ -1,19 +1,31 @@
 mod api;
+mod auth;
+mod backup;
 mod clash;
+mod dashboards;
+mod games;
+mod geodata;
+mod jobs;
+mod matcher;
+mod providers;
+mod rules;
 mod utils;
 mod settings;
 mod subscriptions;
+mod tls;
 mod test;
 
 use actix_cors::Cors;
 use actix_files as fs;
-use actix_web::{http::Method, middleware, web, App, HttpServer};
+use actix_web::{http::Method, middleware::{self, from_fn}, web, App, HttpServer};
 use simplelog::{ColorChoice, CombinedLogger, LevelFilter, TermLogger, TerminalMode, WriteLogger};
 
 use crate::clash::runtime::Runtime;
 
 pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 pub const PACKAGE_NAME: &'static str = env!("CARGO_PKG_NAME");
+// Decky 前端所在的源，本机后端返回配对令牌与 secret，只允许它跨域访问
+const DECKY_ORIGIN: &str = "https://steamloopback.host";
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
@@ -47,42 +59,155 @@ async fn main() -> Result<(), std::io::Error> {
     println!("Starting back-end ({} v{})", PACKAGE_NAME, VERSION);
 
     let runtime = Runtime::new();
+    runtime.spawn_reactor();
+    runtime.spawn_provider_refresh();
     let runtime_cp = runtime.clone();
+    let settings = runtime.settings.clone();
     let backend_port = runtime.settings.get().backend_port;
     let external_port = runtime.settings.get().external_port;
+    let external_tls = runtime.settings.get().external_tls;
 
-    tokio::spawn(
-        HttpServer::new(move || {
+    if runtime.settings.get().enable_external_server {
+        let external_app = move || {
             App::new()
                 .app_data(web::Data::new(runtime.clone()))
                 .wrap(middleware::Logger::default())
                 .wrap(Cors::default()
                     .allow_any_origin()
-                    .allowed_methods(vec![Method::GET, Method::POST])
+                    .allowed_methods(vec![Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE])
                     .allow_any_header())
-                // 公共接口
+                // 鉴权
+                .service(
+                    web::resource("/auth/login")
+                    .route(web::post().to(api::auth::login)))
+                .service(
+                    web::resource("/auth/pair")
+                    .route(web::get().to(api::auth::pair)))
+                .service(
+                    web::resource("/auth/logout")
+                    .route(web::post().to(api::auth::logout)))
+                .service(
+                    web::resource("/auth/status")
+                    .route(web::get().to(api::auth::status)))
+                // 需要登录的接口
                 .service(
                     web::resource("/download_sub")
+                    .wrap(from_fn(api::auth::require_session))
                     .route(web::post().to(api::controller::download_sub)))
+                // 完整设置与导出包含 secret 和订阅凭据，只在本机后端提供
+                .service(
+                    web::resource("/settings/schema")
+                    .wrap(from_fn(api::auth::require_session))
+                    .route(web::get().to(api::settings::settings_schema)))
+                .service(
+                    web::resource("/settings/import")
+                    .wrap(from_fn(api::auth::require_session))
+                    .app_data(web::PayloadConfig::new(api::settings::MAX_BUNDLE_SIZE))
+                    .route(web::post().to(api::settings::import_settings)))
+                .service(
+                    web::scope("/subs")
+                    .wrap(from_fn(api::auth::require_session))
+                    .route("", web::get().to(api::subscriptions::list_subs))
+                    .route("/reorder", web::post().to(api::subscriptions::reorder_subs))
+                    .route("/update", web::post().to(api::subscriptions::update_subs))
+                    .route("/{id}", web::patch().to(api::subscriptions::patch_sub))
+                    .route("/{id}", web::delete().to(api::subscriptions::delete_sub))
+                    .route("/{id}/rename", web::post().to(api::subscriptions::rename_sub))
+                    .route("/{id}/select", web::post().to(api::subscriptions::select_sub)))
+                .service(
+                    web::scope("/rules")
+                    .wrap(from_fn(api::auth::require_session))
+                    .route("/presets", web::get().to(api::rules::list_presets))
+                    .route("/custom", web::get().to(api::rules::list_custom_rules))
+                    .route("/custom", web::post().to(api::rules::create_custom_rule))
+                    .route("/custom/reorder", web::post().to(api::rules::reorder_custom_rules))
+                    .route("/custom/{id}", web::patch().to(api::rules::patch_custom_rule))
+                    .route("/custom/{id}", web::delete().to(api::rules::delete_custom_rule))
+                    .route("/match", web::get().to(api::rules::match_rule)))
+                .service(
+                    web::scope("/games")
+                    .wrap(from_fn(api::auth::require_session))
+                    .route("", web::get().to(api::games::list_games)))
+                .service(
+                    web::scope("/geodata")
+                    .wrap(from_fn(api::auth::require_session))
+                    .route("", web::get().to(api::geodata::list_geodata))
+                    .route("/update", web::post().to(api::geodata::update_geodata)))
+                .service(
+                    web::scope("/dashboards")
+                    .wrap(from_fn(api::auth::require_session))
+                    // 安装接口读取 Deck 上的本地文件，只在本机后端提供
+                    .route("", web::get().to(api::dashboards::list_dashboards))
+                    .route("/{name}", web::delete().to(api::dashboards::delete_dashboard)))
+                .service(
+                    web::scope("/providers")
+                    .wrap(from_fn(api::auth::require_session))
+                    .route("", web::get().to(api::controller::list_providers))
+                    .route("/proxy/{name}/healthcheck", web::post().to(api::controller::health_check_provider))
+                    .route("/{kind}/{name}/update", web::post().to(api::controller::update_provider)))
+                .service(
+                    web::scope("/jobs")
+                    .wrap(from_fn(api::auth::require_session))
+                    .route("", web::get().to(api::jobs::list_jobs))
+                    .route("/{id}", web::get().to(api::jobs::get_job))
+                    .route("/{id}/cancel", web::post().to(api::jobs::cancel_job)))
+                // 内核面板与 external-controller 的反向代理
+                .service(
+                    web::resource("/ui")
+                    .wrap(from_fn(api::auth::require_session))
+                    .route(web::get().to(api::proxy::redirect_dashboard)))
+                .service(
+                    web::resource("/ui/{path:.*}")
+                    .wrap(from_fn(api::auth::require_session))
+                    .route(web::get().to(api::proxy::serve_dashboard)))
+                .service(
+                    web::resource("/api/{tail:.*}")
+                    .wrap(from_fn(api::auth::require_session))
+                    .route(web::route().to(api::proxy::proxy_controller)))
                 // web
                 .service(
                     fs::Files::new("/", "./web")
-                        .index_file("index.html")
-                        .show_files_listing(),
+                        .index_file("index.html"),
                 )
-        })
-        .bind(("0.0.0.0", external_port))?
-        .bind(("[::]", external_port))?
-        .workers(1)
-        .run()
-    );
+        };
+        if external_tls {
+            // 证书保存后复用，客户端可以固定其指纹，本机地址变化时才重新生成
+            let server = tls::certificate_dir().and_then(|dir| {
+                let certificate = tls::ServerCertificate::load_or_create(&dir, &utils::get_local_addresses())?;
+                log::info!("External server uses HTTPS, certificate fingerprint {}", certificate.fingerprint());
+                tls::run_https_server(external_app, external_port, tls::server_config(&dir, &certificate)?)
+            });
+            match server {
+                Ok(server) => {
+                    tokio::spawn(server);
+                }
+                Err(e) => log::error!("Failed to start the external server with HTTPS: {}", e),
+            }
+        } else {
+            tokio::spawn(
+                HttpServer::new(external_app)
+                .bind(("0.0.0.0", external_port))?
+                .bind(("[::]", external_port))?
+                .workers(1)
+                .run()
+            );
+        }
+    } else {
+        log::info!("External server is disabled");
+    }
 
-    HttpServer::new(move || {
+    let result = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(runtime_cp.clone()))
             .wrap(middleware::Logger::default())
-            .wrap(Cors::permissive())
+            .wrap(Cors::default()
+                .allowed_origin(DECKY_ORIGIN)
+                .allowed_methods(vec![Method::GET, Method::POST, Method::PATCH, Method::DELETE])
+                .allow_any_header())
             // 功能接口
+            .service(
+                web::resource("/get_config")
+                .route(web::get().to(api::controller::get_config)))
             .service(
                 web::resource("/get_ip_address")
                 .route(web::get().to(api::controller::get_local_web_address)))
@@ -95,7 +220,85 @@ async fn main() -> Result<(), std::io::Error> {
             .service(
                 web::resource("/download_sub")
                 .route(web::post().to(api::controller::download_sub)))
+            .service(
+                web::resource("/rotate_secret")
+                .route(web::post().to(api::controller::rotate_secret)))
+            .service(
+                web::resource("/preview_config")
+                .route(web::get().to(api::controller::preview_config)))
+            // 外部服务配对
+            .service(
+                web::resource("/pairing_code")
+                .route(web::get().to(api::auth::get_pairing_code))
+                .route(web::post().to(api::auth::regenerate_pairing_code)))
+            .service(
+                web::resource("/pairing_info")
+                .route(web::get().to(api::auth::pairing_info)))
+            .service(
+                web::resource("/revoke_sessions")
+                .route(web::post().to(api::auth::revoke_sessions)))
             // 设置值
+            .service(
+                web::resource("/settings")
+                .route(web::get().to(api::settings::get_settings))
+                .route(web::patch().to(api::settings::patch_settings)))
+            .service(
+                web::resource("/settings/schema")
+                .route(web::get().to(api::settings::settings_schema)))
+            .service(
+                web::resource("/settings/export")
+                .route(web::get().to(api::settings::export_settings)))
+            .service(
+                web::resource("/settings/import")
+                .app_data(web::PayloadConfig::new(api::settings::MAX_BUNDLE_SIZE))
+                .route(web::post().to(api::settings::import_settings)))
+            // 订阅
+            .service(
+                web::scope("/subs")
+                .route("", web::get().to(api::subscriptions::list_subs))
+                .route("/reorder", web::post().to(api::subscriptions::reorder_subs))
+                .route("/update", web::post().to(api::subscriptions::update_subs))
+                .route("/{id}", web::patch().to(api::subscriptions::patch_sub))
+                .route("/{id}", web::delete().to(api::subscriptions::delete_sub))
+                .route("/{id}/rename", web::post().to(api::subscriptions::rename_sub))
+                .route("/{id}/select", web::post().to(api::subscriptions::select_sub)))
+            // 内核 provider
+            .service(
+                web::scope("/providers")
+                .route("", web::get().to(api::controller::list_providers))
+                .route("/proxy/{name}/healthcheck", web::post().to(api::controller::health_check_provider))
+                .route("/{kind}/{name}/update", web::post().to(api::controller::update_provider)))
+            // 规则
+            .service(
+                web::scope("/rules")
+                .route("/presets", web::get().to(api::rules::list_presets))
+                .route("/custom", web::get().to(api::rules::list_custom_rules))
+                .route("/custom", web::post().to(api::rules::create_custom_rule))
+                .route("/custom/reorder", web::post().to(api::rules::reorder_custom_rules))
+                .route("/custom/{id}", web::patch().to(api::rules::patch_custom_rule))
+                .route("/custom/{id}", web::delete().to(api::rules::delete_custom_rule))
+                .route("/match", web::get().to(api::rules::match_rule)))
+            // Steam 游戏
+            .service(
+                web::scope("/games")
+                .route("", web::get().to(api::games::list_games)))
+            // geodata 数据库
+            .service(
+                web::scope("/geodata")
+                .route("", web::get().to(api::geodata::list_geodata))
+                .route("/update", web::post().to(api::geodata::update_geodata)))
+            // 面板
+            .service(
+                web::scope("/dashboards")
+                .route("", web::get().to(api::dashboards::list_dashboards))
+                .route("/install", web::post().to(api::dashboards::install_dashboard))
+                .route("/{name}", web::delete().to(api::dashboards::delete_dashboard)))
+            // 后台任务
+            .service(
+                web::scope("/jobs")
+                .route("", web::get().to(api::jobs::list_jobs))
+                .route("/{id}", web::get().to(api::jobs::get_job))
+                .route("/{id}/cancel", web::post().to(api::jobs::cancel_job)))
             .service(
                 web::resource("/skip_proxy")
                 .route(web::post().to(api::settings::skip_proxy)))
@@ -111,9 +314,24 @@ async fn main() -> Result<(), std::io::Error> {
             .service(
                 web::resource("/dashboard")
                 .route(web::post().to(api::settings::dashboard)))
+            .service(
+                web::resource("/enable_external_server")
+                .route(web::post().to(api::settings::enable_external_server)))
+            .service(
+                web::resource("/external_tls")
+                .route(web::post().to(api::settings::external_tls)))
+            .service(
+                web::resource("/log_level")
+                .route(web::post().to(api::settings::log_level)))
     })
     .bind(("localhost", backend_port))?
     .workers(1)
     .run()
-    .await
+    .await;
+
+    // 写入尚未保存的设置
+    if let Err(e) = settings.save() {
+        log::error!("Failed to save settings on exit: {}", e);
+    }
+    result
 }
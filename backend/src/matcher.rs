@@ -0,0 +1,414 @@
+use std::cell::OnceCell;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+
+use crate::geodata::{self, GeoDatabase};
+
+// 内核在没有规则匹配时使用的策略
+const DEFAULT_POLICY: &str = "DIRECT";
+
+/// 要测试的连接，未提供的条件对应的规则会被跳过
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct MatchTarget {
+    // 域名或 IP
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    // 进程名或完整路径
+    pub process: Option<String>,
+    // tcp 或 udp
+    pub network: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct MatchedRule {
+    pub index: usize,
+    pub rule: String,
+    pub policy: String,
+}
+
+/// 无法在本地判断的规则，测试结果可能与内核不同
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct SkippedRule {
+    pub index: usize,
+    pub rule: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct MatchReport {
+    pub matched: Option<MatchedRule>,
+    // 最终使用的策略，没有规则匹配时为 DIRECT
+    pub policy: String,
+    pub resolved_ip: Option<IpAddr>,
+    pub skipped: Vec<SkippedRule>,
+    // 策略组当前选择的节点，内核未运行时为空
+    pub chain: Vec<String>,
+}
+
+enum Outcome {
+    Match,
+    NoMatch,
+    Skip(String),
+}
+
+fn skip(reason: &str) -> Outcome {
+    Outcome::Skip(reason.to_string())
+}
+
+impl From<bool> for Outcome {
+    fn from(value: bool) -> Self {
+        match value {
+            true => Outcome::Match,
+            false => Outcome::NoMatch,
+        }
+    }
+}
+
+fn suffix_matches(domain: &str, suffix: &str) -> bool {
+    domain == suffix || domain.ends_with(&format!(".{}", suffix))
+}
+
+fn cidr_contains(cidr: &str, ip: IpAddr) -> Option<bool> {
+    let (network, prefix) = cidr.split_once('/')?;
+    let prefix: u32 = prefix.parse().ok()?;
+    match (network.parse::<IpAddr>().ok()?, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) if prefix <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            Some(u32::from(network) & mask == u32::from(ip) & mask)
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) if prefix <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            Some(u128::from(network) & mask == u128::from(ip) & mask)
+        }
+        (IpAddr::V4(_), IpAddr::V6(_)) | (IpAddr::V6(_), IpAddr::V4(_)) => Some(false),
+        _ => None,
+    }
+}
+
+fn port_matches(ports: &str, port: u16) -> Option<bool> {
+    let mut matched = false;
+    for part in ports.split('/') {
+        let (start, end) = part.split_once('-').unwrap_or((part, part));
+        let (start, end) = (start.trim().parse::<u16>().ok()?, end.trim().parse::<u16>().ok()?);
+        matched |= start <= port && port <= end;
+    }
+    Some(matched)
+}
+
+// 局域网与保留地址，对应 GEOIP,LAN
+fn is_lan(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(x) => x.is_private() || x.is_loopback() || x.is_link_local() || x.is_unspecified(),
+        IpAddr::V6(x) => {
+            let first = x.segments()[0];
+            // fc00::/7 为唯一本地地址，fe80::/10 为链路本地地址
+            x.is_loopback() || x.is_unspecified() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+// rule-set 中 domain 类型的条目：`+.` 匹配域名及子域名，`.` 只匹配子域名，`*.` 匹配一级子域名
+fn domain_entry_matches(entry: &str, domain: &str) -> bool {
+    if let Some(suffix) = entry.strip_prefix("+.") {
+        suffix_matches(domain, suffix)
+    } else if let Some(suffix) = entry.strip_prefix('.') {
+        domain.ends_with(&format!(".{}", suffix))
+    } else if let Some(suffix) = entry.strip_prefix("*.") {
+        domain
+            .strip_suffix(suffix)
+            .and_then(|x| x.strip_suffix('.'))
+            .is_some_and(|x| !x.is_empty() && !x.contains('.'))
+    } else {
+        entry == domain
+    }
+}
+
+/// 在本地按配置中的规则顺序测试连接，规则格式与 mihomo 相同
+pub struct Matcher<'a> {
+    config: &'a Value,
+    // 内核工作目录，geodata 与相对路径的 rule-set 位于此处
+    core_dir: PathBuf,
+    domain: Option<String>,
+    ip: Option<IpAddr>,
+    target: MatchTarget,
+    country: OnceCell<Option<Vec<u8>>>,
+    asn: OnceCell<Option<Vec<u8>>>,
+    geosite: OnceCell<Option<Vec<u8>>>,
+}
+
+impl<'a> Matcher<'a> {
+    /// `resolved` 为域名解析得到的地址，用于没有 no-resolve 的 IP 规则
+    pub fn new(config: &'a Value, core_dir: &Path, target: MatchTarget, resolved: Option<IpAddr>) -> Self {
+        let host = target
+            .host
+            .as_deref()
+            .map(|x| x.trim().trim_end_matches('.').to_lowercase());
+        let (domain, ip) = match host.as_deref().map(|x| x.trim_matches(['[', ']']).parse::<IpAddr>()) {
+            Some(Ok(ip)) => (None, Some(ip)),
+            Some(Err(_)) => (host, resolved),
+            None => (None, None),
+        };
+        Self {
+            config,
+            core_dir: core_dir.to_path_buf(),
+            domain,
+            ip,
+            target,
+            country: OnceCell::new(),
+            asn: OnceCell::new(),
+            geosite: OnceCell::new(),
+        }
+    }
+
+    // 数据库在第一次用到时读取
+    fn database(&self, database: GeoDatabase) -> Option<&[u8]> {
+        let cell = match database {
+            GeoDatabase::Mmdb => &self.country,
+            GeoDatabase::Asn => &self.asn,
+            GeoDatabase::GeoSite => &self.geosite,
+            // GeoIP.dat 只供内核使用，本地通过 Country.mmdb 查询
+            GeoDatabase::GeoIp => return None,
+        };
+        cell.get_or_init(|| std::fs::read(database.locate(&self.core_dir)).ok())
+            .as_deref()
+    }
+
+    // IP 规则使用的地址，域名目标在 no-resolve 时不匹配
+    fn target_ip(&self, params: &[&str]) -> Result<IpAddr, Outcome> {
+        if self.domain.is_some() && params.contains(&"no-resolve") {
+            return Err(Outcome::NoMatch);
+        }
+        match (self.ip, &self.target.host) {
+            (Some(ip), _) => Ok(ip),
+            (None, Some(_)) => Err(skip("The host could not be resolved")),
+            (None, None) => Err(skip("No host given")),
+        }
+    }
+
+    fn evaluate(&self, kind: &str, value: &str, params: &[&str], depth: usize) -> Outcome {
+        let domain = self.domain.as_deref();
+        let process = self.target.process.as_deref();
+        match kind {
+            "MATCH" => Outcome::Match,
+            "DOMAIN" => domain.is_some_and(|x| x == value.to_lowercase()).into(),
+            "DOMAIN-SUFFIX" => domain.is_some_and(|x| suffix_matches(x, &value.to_lowercase())).into(),
+            "DOMAIN-KEYWORD" => domain.is_some_and(|x| x.contains(&value.to_lowercase())).into(),
+            "DOMAIN-REGEX" => match (domain, Regex::new(value)) {
+                (None, _) => Outcome::NoMatch,
+                (Some(x), Ok(regex)) => regex.is_match(x).into(),
+                (Some(_), Err(_)) => skip("Invalid regular expression"),
+            },
+            "GEOSITE" => {
+                let Some(domain) = domain else {
+                    return Outcome::NoMatch;
+                };
+                let Some(data) = self.database(GeoDatabase::GeoSite) else {
+                    return skip("GeoSite.dat is missing");
+                };
+                match geodata::geosite_contains(data, value, domain) {
+                    Some(x) => x.into(),
+                    None => skip("The list is not in GeoSite.dat"),
+                }
+            }
+            "IP-CIDR" | "IP-CIDR6" => match self.target_ip(params) {
+                Ok(ip) => cidr_contains(value, ip).map_or_else(|| skip("Invalid CIDR"), Outcome::from),
+                Err(outcome) => outcome,
+            },
+            "GEOIP" => {
+                let ip = match self.target_ip(params) {
+                    Ok(ip) => ip,
+                    Err(outcome) => return outcome,
+                };
+                if value.eq_ignore_ascii_case("LAN") {
+                    return is_lan(ip).into();
+                }
+                let Some(data) = self.database(GeoDatabase::Mmdb) else {
+                    return skip("Country.mmdb is missing");
+                };
+                geodata::country_code(data, ip)
+                    .is_some_and(|x| x.eq_ignore_ascii_case(value))
+                    .into()
+            }
+            "IP-ASN" => {
+                let ip = match self.target_ip(params) {
+                    Ok(ip) => ip,
+                    Err(outcome) => return outcome,
+                };
+                let Some(data) = self.database(GeoDatabase::Asn) else {
+                    return skip("ASN.mmdb is missing");
+                };
+                let asn = geodata::mmdb_lookup(data, ip)
+                    .and_then(|x| x.get("autonomous_system_number")?.as_u64());
+                asn.is_some_and(|x| x.to_string() == value).into()
+            }
+            "DST-PORT" => match self.target.port {
+                Some(port) => port_matches(value, port).map_or_else(|| skip("Invalid port"), Outcome::from),
+                None => skip("No port given"),
+            },
+            "NETWORK" => match &self.target.network {
+                Some(x) => x.eq_ignore_ascii_case(value).into(),
+                None => skip("No network given"),
+            },
+            "PROCESS-NAME" => match process {
+                Some(x) => (Path::new(x).file_name().is_some_and(|x| x == value)).into(),
+                None => skip("No process given"),
+            },
+            "PROCESS-PATH" => match process {
+                Some(x) => (x == value).into(),
+                None => skip("No process given"),
+            },
+            "PROCESS-NAME-REGEX" | "PROCESS-PATH-REGEX" => match (process, Regex::new(value)) {
+                (None, _) => skip("No process given"),
+                (Some(_), Err(_)) => skip("Invalid regular expression"),
+                (Some(x), Ok(regex)) if kind == "PROCESS-PATH-REGEX" => regex.is_match(x).into(),
+                (Some(x), Ok(regex)) => {
+                    regex.is_match(&Path::new(x).file_name().unwrap_or_default().to_string_lossy()).into()
+                }
+            },
+            "RULE-SET" if depth == 0 => self.evaluate_rule_set(value, params),
+            "SRC-IP-CIDR" | "SRC-PORT" | "IN-PORT" | "IN-TYPE" | "IN-USER" | "IN-NAME" | "UID" => {
+                skip("Depends on the connection source")
+            }
+            "AND" | "OR" | "NOT" | "SUB-RULE" => skip("Logic rules are not evaluated"),
+            _ => skip("Unsupported rule type"),
+        }
+    }
+
+    fn rule_set_payload(&self, name: &str) -> Result<(String, Vec<String>), Outcome> {
+        let provider = self
+            .config
+            .get("rule-providers")
+            .and_then(|x| x.get(name))
+            .ok_or_else(|| skip("The rule provider is not in the config"))?;
+        let behavior = provider
+            .get("behavior")
+            .and_then(|x| x.as_str())
+            .unwrap_or("classical")
+            .to_string();
+        let entries = |value: &Value| -> Vec<String> {
+            value
+                .as_sequence()
+                .map(|x| x.iter().filter_map(|x| x.as_str().map(|x| x.trim().to_string())).collect())
+                .unwrap_or_default()
+        };
+        if let Some(payload) = provider.get("payload") {
+            return Ok((behavior, entries(payload)));
+        }
+
+        if provider.get("format").and_then(|x| x.as_str()) == Some("mrs") {
+            return Err(skip("Binary rule sets are not supported"));
+        }
+        let path = provider
+            .get("path")
+            .and_then(|x| x.as_str())
+            .ok_or_else(|| skip("The rule provider has no local file"))?;
+        let content = std::fs::read_to_string(self.core_dir.join(path))
+            .map_err(|_| skip("The rule provider has not been downloaded"))?;
+        let payload = match serde_yaml::from_str::<Value>(&content) {
+            Ok(x) if x.get("payload").is_some() => entries(&x["payload"]),
+            _ => content
+                .lines()
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty() && !x.starts_with('#'))
+                .collect(),
+        };
+        Ok((behavior, payload))
+    }
+
+    fn evaluate_rule_set(&self, name: &str, params: &[&str]) -> Outcome {
+        let (behavior, payload) = match self.rule_set_payload(name) {
+            Ok(x) => x,
+            Err(outcome) => return outcome,
+        };
+        match behavior.as_str() {
+            "domain" => match &self.domain {
+                Some(domain) => payload.iter().any(|x| domain_entry_matches(&x.to_lowercase(), domain)).into(),
+                None => Outcome::NoMatch,
+            },
+            "ipcidr" => match self.target_ip(params) {
+                Ok(ip) => payload.iter().any(|x| cidr_contains(x, ip) == Some(true)).into(),
+                Err(outcome) => outcome,
+            },
+            _ => {
+                // classical 中的每一项都是不含策略的规则
+                let mut skipped = None;
+                for entry in &payload {
+                    let parts: Vec<&str> = entry.split(',').map(|x| x.trim()).collect();
+                    let kind = parts[0].to_uppercase();
+                    let value = parts.get(1).copied().unwrap_or_default();
+                    match self.evaluate(&kind, value, parts.get(2..).unwrap_or_default(), 1) {
+                        Outcome::Match => return Outcome::Match,
+                        Outcome::Skip(reason) => skipped = skipped.or(Some(reason)),
+                        Outcome::NoMatch => {}
+                    }
+                }
+                match skipped {
+                    Some(reason) => Outcome::Skip(format!("Some entries were not evaluated: {}", reason)),
+                    None => Outcome::NoMatch,
+                }
+            }
+        }
+    }
+
+    /// 依次测试配置中的规则，返回第一条匹配的规则
+    pub fn run(&self) -> MatchReport {
+        let rules = self.config.get("rules").and_then(|x| x.as_sequence());
+        let mut skipped = Vec::new();
+        let mut matched = None;
+        for (index, rule) in rules.into_iter().flatten().enumerate() {
+            let Some(text) = rule.as_str() else {
+                continue;
+            };
+            let parts: Vec<&str> = text.split(',').map(|x| x.trim()).collect();
+            let kind = parts[0].to_uppercase();
+            // MATCH 只有策略，其他规则为 类型,值,策略[,参数]
+            let (value, policy, params) = match kind.as_str() {
+                "MATCH" | "FINAL" => ("", parts.get(1), &[][..]),
+                _ => (
+                    parts.get(1).copied().unwrap_or_default(),
+                    parts.get(2),
+                    parts.get(3..).unwrap_or_default(),
+                ),
+            };
+            let kind = if kind == "FINAL" { "MATCH".to_string() } else { kind };
+            let Some(policy) = policy else {
+                skipped.push(SkippedRule {
+                    index,
+                    rule: text.to_string(),
+                    reason: "Malformed rule".to_string(),
+                });
+                continue;
+            };
+            match self.evaluate(&kind, value, params, 0) {
+                Outcome::Match => {
+                    matched = Some(MatchedRule {
+                        index,
+                        rule: text.to_string(),
+                        policy: policy.to_string(),
+                    });
+                    break;
+                }
+                Outcome::Skip(reason) => skipped.push(SkippedRule {
+                    index,
+                    rule: text.to_string(),
+                    reason,
+                }),
+                Outcome::NoMatch => {}
+            }
+        }
+
+        MatchReport {
+            policy: matched
+                .as_ref()
+                .map_or(DEFAULT_POLICY.to_string(), |x| x.policy.clone()),
+            matched,
+            resolved_ip: self.domain.as_ref().and(self.ip),
+            skipped,
+            chain: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+
+use crate::{
+    clash::controller::{ClashError, ClashErrorKind},
+    jobs::{ItemStatus, Job, JobItem, JobKind, JobRegistry},
+    settings::{RequestOptions, Subscription},
+    subscriptions,
+};
+
+// 配置中未写 interval 时按一天刷新一次
+const DEFAULT_PROVIDER_INTERVAL: u64 = 24 * 60 * 60;
+// 后台检查缓存是否过期的间隔
+pub const PROVIDER_CHECK_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderKind {
+    Rule,
+    Proxy,
+}
+
+impl ProviderKind {
+    fn key(&self) -> &'static str {
+        match self {
+            Self::Rule => "rule-providers",
+            Self::Proxy => "proxy-providers",
+        }
+    }
+
+    /// 内核 `/providers/{..}` 接口中的路径
+    pub fn api_path(&self) -> &'static str {
+        match self {
+            Self::Rule => "rules",
+            Self::Proxy => "proxies",
+        }
+    }
+
+    fn dir_name(&self) -> &'static str {
+        match self {
+            Self::Rule => "rule",
+            Self::Proxy => "proxy",
+        }
+    }
+}
+
+/// 配置中通过 URL 获取的 provider
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Provider {
+    pub kind: ProviderKind,
+    pub name: String,
+    pub url: String,
+    pub interval: u64,
+    // 缓存文件名，由 URL 和格式决定，多个订阅引用同一 URL 时共用
+    pub file_name: String,
+}
+
+impl Provider {
+    pub fn cache_path(&self, dir: &Path) -> PathBuf {
+        dir.join(self.kind.dir_name()).join(&self.file_name)
+    }
+
+    /// 缓存不存在或已超过 provider 自身的刷新间隔
+    pub fn is_stale(&self, dir: &Path) -> bool {
+        let modified = std::fs::metadata(self.cache_path(dir)).and_then(|x| x.modified());
+        match modified.ok().and_then(|x| SystemTime::now().duration_since(x).ok()) {
+            Some(age) => age.as_secs() >= self.interval,
+            None => true,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct PrefetchReport {
+    pub fetched: Vec<String>,
+    pub skipped: Vec<String>,
+    pub failed: Vec<PrefetchFailure>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PrefetchFailure {
+    pub name: String,
+    pub error_kind: ClashErrorKind,
+    pub message: String,
+}
+
+// FNV-1a，文件名需要在不同版本之间保持稳定
+fn stable_hash(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn file_extension(value: &Value) -> &'static str {
+    let format = value.get("format").and_then(|x| x.as_str());
+    let path = value.get("path").and_then(|x| x.as_str()).unwrap_or_default();
+    match format {
+        Some("mrs") => "mrs",
+        Some("text") => "txt",
+        _ if path.ends_with(".txt") || path.ends_with(".list") => "txt",
+        _ => "yaml",
+    }
+}
+
+/// 列出配置中所有 `type: http` 的 provider
+pub fn list_providers(config: &Value) -> Vec<Provider> {
+    let mut providers = Vec::new();
+    for kind in [ProviderKind::Rule, ProviderKind::Proxy] {
+        let Some(entries) = config.get(kind.key()).and_then(|x| x.as_mapping()) else {
+            continue;
+        };
+        for (name, value) in entries {
+            let (Some(name), Some(url)) = (name.as_str(), value.get("url").and_then(|x| x.as_str()))
+            else {
+                continue;
+            };
+            if value.get("type").and_then(|x| x.as_str()) != Some("http") {
+                continue;
+            }
+            providers.push(Provider {
+                kind,
+                name: name.to_string(),
+                url: url.to_string(),
+                interval: value
+                    .get("interval")
+                    .and_then(|x| x.as_u64())
+                    .filter(|x| *x > 0)
+                    .unwrap_or(DEFAULT_PROVIDER_INTERVAL),
+                file_name: format!("{:016x}.{}", stable_hash(url), file_extension(value)),
+            });
+        }
+    }
+    providers
+}
+
+pub fn read_providers<P: AsRef<Path>>(path: P) -> Result<Vec<Provider>, ClashError> {
+    let content = std::fs::read_to_string(path).map_err(|e| ClashError {
+        message: e.to_string(),
+        error_kind: ClashErrorKind::IOError,
+    })?;
+    let config: Value = serde_yaml::from_str(&content).map_err(|e| ClashError {
+        message: e.to_string(),
+        error_kind: ClashErrorKind::ContentError,
+    })?;
+    Ok(list_providers(&config))
+}
+
+/// 将已缓存的 provider 的 `path` 指向缓存文件，内核启动时无需联网即可加载
+pub fn rewrite_paths(config: &mut Mapping, dir: &Path) {
+    let providers = list_providers(&Value::Mapping(config.clone()));
+    for provider in providers {
+        let path = provider.cache_path(dir);
+        if !path.exists() {
+            continue;
+        }
+        let entry = config
+            .get_mut(provider.kind.key())
+            .and_then(|x| x.get_mut(provider.name.as_str()))
+            .and_then(|x| x.as_mapping_mut());
+        if let Some(entry) = entry {
+            entry.insert(
+                Value::String("path".to_string()),
+                Value::String(path.to_string_lossy().to_string()),
+            );
+        }
+    }
+}
+
+// provider 往往与订阅不在同一主机，只沿用连接方式，不发送订阅的认证信息
+fn provider_request(options: &RequestOptions) -> RequestOptions {
+    RequestOptions {
+        user_agent: options.user_agent.clone(),
+        timeout: options.timeout,
+        follow_redirects: options.follow_redirects,
+        allow_insecure: options.allow_insecure,
+        fetch_via: options.fetch_via.clone(),
+        retry_via_core: options.retry_via_core,
+        ..Default::default()
+    }
+}
+
+async fn fetch_provider(provider: &Provider, options: &RequestOptions, dir: &Path) -> Result<(), ClashError> {
+    let response = subscriptions::fetch_remote(&provider.url, options).await?;
+    if !response.status().is_success() {
+        return Err(ClashError {
+            message: format!("Status Code: {}", response.status().as_u16()),
+            error_kind: ClashErrorKind::NetworkError,
+        });
+    }
+    let content = response.bytes().await.map_err(|e| ClashError {
+        message: e.to_string(),
+        error_kind: ClashErrorKind::NetworkError,
+    })?;
+    if content.is_empty() {
+        return Err(ClashError {
+            message: "The provider is empty".to_string(),
+            error_kind: ClashErrorKind::ContentError,
+        });
+    }
+
+    // 先写临时文件再替换，避免内核读到半个文件
+    let path = provider.cache_path(dir);
+    let io_error = |e: std::io::Error| ClashError {
+        message: e.to_string(),
+        error_kind: ClashErrorKind::IOError,
+    };
+    std::fs::create_dir_all(path.parent().unwrap()).map_err(io_error)?;
+    let temp = path.with_extension("part");
+    std::fs::write(&temp, &content).map_err(io_error)?;
+    std::fs::rename(&temp, &path).map_err(io_error)?;
+    Ok(())
+}
+
+/// 下载订阅引用的 provider，`only_stale` 为 true 时跳过未过期的缓存
+pub async fn prefetch(sub: &Subscription, dir: &Path, only_stale: bool) -> Result<PrefetchReport, ClashError> {
+    let providers = read_providers(&sub.path)?;
+    let options = provider_request(&sub.request);
+    let mut report = PrefetchReport::default();
+    for provider in providers {
+        if only_stale && !provider.is_stale(dir) {
+            report.skipped.push(provider.name);
+            continue;
+        }
+        match fetch_provider(&provider, &options, dir).await {
+            Ok(()) => {
+                log::info!("Provider {} cached from {}", provider.name, provider.url);
+                report.fetched.push(provider.name);
+            }
+            Err(e) => {
+                log::error!("Failed to fetch provider {}: {}", provider.name, e);
+                report.failed.push(PrefetchFailure {
+                    name: provider.name,
+                    error_kind: e.error_kind,
+                    message: e.message,
+                });
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// 删除不再被任何订阅引用的缓存文件
+pub fn remove_unused(subs: &[Subscription], dir: &Path) {
+    let used: HashSet<PathBuf> = subs
+        .iter()
+        .filter_map(|x| read_providers(&x.path).ok())
+        .flatten()
+        .map(|x| x.cache_path(dir))
+        .collect();
+    for kind in [ProviderKind::Rule, ProviderKind::Proxy] {
+        let Ok(entries) = std::fs::read_dir(dir.join(kind.dir_name())) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !used.contains(&path) {
+                if let Err(e) = std::fs::remove_file(&path) {
+                    log::warn!("Failed to remove cached provider {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+/// 刷新订阅引用的 provider 缓存，每个订阅为任务中的一项
+pub fn start_refresh(jobs: &JobRegistry, subs: Vec<Subscription>, dir: PathBuf, only_stale: bool) -> Job {
+    let items = subs
+        .iter()
+        .map(|x| JobItem {
+            id: x.id.to_string(),
+            name: x.display_name(),
+            status: ItemStatus::Pending,
+        })
+        .collect();
+
+    jobs.spawn_exclusive(JobKind::RefreshProviders, items, move |handle| async move {
+        let mut fetched = 0;
+        let mut failed = 0;
+        for (index, sub) in subs.iter().enumerate() {
+            handle.set_item(index, ItemStatus::Downloading);
+            let status = match prefetch(sub, &dir, only_stale).await {
+                Ok(report) => {
+                    fetched += report.fetched.len();
+                    failed += report.failed.len();
+                    match report.failed.first() {
+                        None => ItemStatus::Ok,
+                        Some(x) => ItemStatus::Failed {
+                            error_kind: x.error_kind,
+                            message: format!("{}: {}", x.name, x.message),
+                        },
+                    }
+                }
+                Err(e) => ItemStatus::Failed {
+                    error_kind: e.error_kind,
+                    message: e.message,
+                },
+            };
+            handle.set_item(index, status);
+        }
+        Ok(serde_json::json!({ "fetched": fetched, "failed": failed }))
+    })
+}
@@ -0,0 +1,416 @@
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use uuid::Uuid;
+
+use crate::{
+    clash::controller::{ClashError, ClashErrorKind},
+    settings::{Settings, ValidationError, ValidationErrorKind},
+};
+
+// 内核内置的策略，不需要出现在 proxy-groups 中
+pub const BUILTIN_POLICIES: [&str; 3] = ["DIRECT", "REJECT", "REJECT-DROP"];
+
+/// 内置的规则预设，按注入顺序排列，范围较小的在前
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum RulePreset {
+    SteamCdn,
+    SteamChat,
+    SteamStore,
+    Epic,
+    Ea,
+    BattleNet,
+    XboxLive,
+    Nintendo,
+    Discord,
+}
+
+/// 预设的处理方式，未设置的预设不注入任何规则
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PresetAction {
+    Direct,
+    Proxy { group: String },
+}
+
+impl PresetAction {
+    pub fn policy(&self) -> &str {
+        match self {
+            Self::Direct => "DIRECT",
+            Self::Proxy { group } => group,
+        }
+    }
+}
+
+impl RulePreset {
+    pub const ALL: [RulePreset; 9] = [
+        Self::SteamCdn,
+        Self::SteamChat,
+        Self::SteamStore,
+        Self::Epic,
+        Self::Ea,
+        Self::BattleNet,
+        Self::XboxLive,
+        Self::Nintendo,
+        Self::Discord,
+    ];
+
+    pub fn display_name(&self) -> &'static str {
+        match self {
+            Self::SteamCdn => "Steam CDN / content servers",
+            Self::SteamChat => "Steam chat",
+            Self::SteamStore => "Steam store and community",
+            Self::Epic => "Epic Games",
+            Self::Ea => "EA",
+            Self::BattleNet => "Battle.net",
+            Self::XboxLive => "Xbox Live",
+            Self::Nintendo => "Nintendo",
+            Self::Discord => "Discord",
+        }
+    }
+
+    /// 预设包含的匹配条件，不含策略
+    pub fn matchers(&self) -> &'static [&'static str] {
+        match self {
+            Self::SteamCdn => &[
+                "DOMAIN-SUFFIX,steamcontent.com",
+                "DOMAIN-SUFFIX,steamserver.net",
+                "DOMAIN-SUFFIX,steampipe.akamaized.net",
+                "DOMAIN,client-download.steampowered.com",
+                "DOMAIN-SUFFIX,cm.steampowered.com",
+            ],
+            Self::SteamChat => &[
+                "DOMAIN-SUFFIX,steam-chat.com",
+                "DOMAIN,chat.steampowered.com",
+            ],
+            Self::SteamStore => &[
+                "DOMAIN-SUFFIX,steampowered.com",
+                "DOMAIN-SUFFIX,steamcommunity.com",
+                "DOMAIN-SUFFIX,steamstatic.com",
+                "DOMAIN-SUFFIX,steamusercontent.com",
+                "DOMAIN-SUFFIX,steamgames.com",
+            ],
+            Self::Epic => &[
+                "DOMAIN-SUFFIX,epicgames.com",
+                "DOMAIN-SUFFIX,epicgames.dev",
+                "DOMAIN-SUFFIX,unrealengine.com",
+                "DOMAIN-SUFFIX,epicgames-download1.akamaized.net",
+            ],
+            Self::Ea => &[
+                "DOMAIN-SUFFIX,ea.com",
+                "DOMAIN-SUFFIX,origin.com",
+                "DOMAIN-SUFFIX,tnt-ea.com",
+                "DOMAIN-SUFFIX,eac-cdn.com",
+            ],
+            Self::BattleNet => &[
+                "DOMAIN-SUFFIX,battle.net",
+                "DOMAIN-SUFFIX,blizzard.com",
+                "DOMAIN-SUFFIX,battlenet.com.cn",
+                "DOMAIN-SUFFIX,blzstatic.cn",
+            ],
+            Self::XboxLive => &[
+                "DOMAIN-SUFFIX,xboxlive.com",
+                "DOMAIN-SUFFIX,xbox.com",
+                "DOMAIN-SUFFIX,xboxservices.com",
+                "DOMAIN-SUFFIX,gamepass.com",
+            ],
+            Self::Nintendo => &[
+                "DOMAIN-SUFFIX,nintendo.com",
+                "DOMAIN-SUFFIX,nintendo.net",
+                "DOMAIN-SUFFIX,nintendo.co.jp",
+            ],
+            Self::Discord => &[
+                "DOMAIN-SUFFIX,discord.com",
+                "DOMAIN-SUFFIX,discord.gg",
+                "DOMAIN-SUFFIX,discordapp.com",
+                "DOMAIN-SUFFIX,discordapp.net",
+                "DOMAIN-SUFFIX,discord.media",
+            ],
+        }
+    }
+}
+
+/// 配置中的策略组名称
+pub fn proxy_groups(config: &Value) -> Vec<String> {
+    config
+        .get("proxy-groups")
+        .and_then(|x| x.as_sequence())
+        .map(|groups| {
+            groups
+                .iter()
+                .filter_map(|x| x.get("name")?.as_str().map(|x| x.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// 策略是否可以在该配置中使用
+pub fn is_known_policy(policy: &str, groups: &HashSet<String>) -> bool {
+    BUILTIN_POLICIES.contains(&policy) || groups.contains(policy)
+}
+
+/// 生成预设规则，策略组不在配置中的预设会被跳过
+pub fn preset_rules(presets: &BTreeMap<RulePreset, PresetAction>, config: &Value) -> Vec<String> {
+    let groups: HashSet<String> = proxy_groups(config).into_iter().collect();
+    let mut rules = Vec::new();
+    for (preset, action) in presets {
+        let policy = action.policy();
+        if !is_known_policy(policy, &groups) {
+            log::warn!(
+                "Skipping rule preset {:?}: policy {} is not in the profile",
+                preset,
+                policy
+            );
+            continue;
+        }
+        rules.extend(preset.matchers().iter().map(|x| format!("{},{}", x, policy)));
+    }
+    rules
+}
+
+/// 自定义规则支持的匹配类型
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING-KEBAB-CASE")]
+pub enum RuleType {
+    Domain,
+    DomainSuffix,
+    DomainKeyword,
+    DomainRegex,
+    Geosite,
+    Geoip,
+    IpCidr,
+    IpCidr6,
+    IpAsn,
+    SrcIpCidr,
+    DstPort,
+    SrcPort,
+    ProcessName,
+    ProcessPath,
+}
+
+impl RuleType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Domain => "DOMAIN",
+            Self::DomainSuffix => "DOMAIN-SUFFIX",
+            Self::DomainKeyword => "DOMAIN-KEYWORD",
+            Self::DomainRegex => "DOMAIN-REGEX",
+            Self::Geosite => "GEOSITE",
+            Self::Geoip => "GEOIP",
+            Self::IpCidr => "IP-CIDR",
+            Self::IpCidr6 => "IP-CIDR6",
+            Self::IpAsn => "IP-ASN",
+            Self::SrcIpCidr => "SRC-IP-CIDR",
+            Self::DstPort => "DST-PORT",
+            Self::SrcPort => "SRC-PORT",
+            Self::ProcessName => "PROCESS-NAME",
+            Self::ProcessPath => "PROCESS-PATH",
+        }
+    }
+
+    /// 只有按目标 IP 匹配的规则可以设置 no-resolve
+    pub fn supports_no_resolve(&self) -> bool {
+        matches!(self, Self::Geoip | Self::IpCidr | Self::IpCidr6 | Self::IpAsn)
+    }
+}
+
+/// 用户添加的规则，注入在订阅规则之前
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CustomRule {
+    #[serde(default = "Uuid::new_v4")]
+    pub id: Uuid,
+    #[serde(rename = "type")]
+    pub rule_type: RuleType,
+    pub value: String,
+    pub policy: String,
+    #[serde(default)]
+    pub no_resolve: bool,
+    #[serde(default = "default_rule_enabled")]
+    pub enabled: bool,
+}
+
+fn default_rule_enabled() -> bool {
+    true
+}
+
+fn is_cidr(value: &str, v4: bool, v6: bool) -> bool {
+    let Some((ip, prefix)) = value.split_once('/') else {
+        return false;
+    };
+    let Ok(prefix) = prefix.parse::<u8>() else {
+        return false;
+    };
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(_)) => v4 && prefix <= 32,
+        Ok(IpAddr::V6(_)) => v6 && prefix <= 128,
+        Err(_) => false,
+    }
+}
+
+// 端口支持 `80`、`8000-9000` 以及用 `/` 连接的多段
+fn is_port_list(value: &str) -> bool {
+    value.split('/').all(|part| {
+        let (start, end) = part.split_once('-').unwrap_or((part, part));
+        matches!((start.parse::<u16>(), end.parse::<u16>()), (Ok(x), Ok(y)) if x <= y)
+    })
+}
+
+impl CustomRule {
+    /// 生成配置中的规则文本
+    pub fn to_rule(&self) -> String {
+        let mut rule = format!("{},{},{}", self.rule_type.as_str(), self.value, self.policy);
+        if self.no_resolve {
+            rule.push_str(",no-resolve");
+        }
+        rule
+    }
+
+    /// 按规则语法检查，策略组是否存在由调用方结合订阅检查
+    pub fn validate(&self, field: &str) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+        let invalid = |name: &str, message: String| {
+            ValidationError::new(
+                &format!("{}.{}", field, name),
+                ValidationErrorKind::InvalidValue,
+                message,
+            )
+        };
+
+        let value = self.value.as_str();
+        let valid = !value.is_empty()
+            && !value.contains(',')
+            && value.trim() == value
+            && match self.rule_type {
+                RuleType::Domain | RuleType::DomainSuffix => value
+                    .chars()
+                    .all(|x| x.is_ascii_alphanumeric() || matches!(x, '-' | '.' | '_' | '*' | '+')),
+                RuleType::DomainRegex => Regex::new(value).is_ok(),
+                RuleType::Geosite | RuleType::Geoip => value
+                    .chars()
+                    .all(|x| x.is_ascii_alphanumeric() || matches!(x, '-' | '_' | '!' | '@' | '.')),
+                RuleType::IpCidr => is_cidr(value, true, false),
+                RuleType::IpCidr6 => is_cidr(value, false, true),
+                RuleType::SrcIpCidr => is_cidr(value, true, true),
+                RuleType::IpAsn => value.parse::<u32>().is_ok(),
+                RuleType::DstPort | RuleType::SrcPort => is_port_list(value),
+                RuleType::DomainKeyword | RuleType::ProcessName | RuleType::ProcessPath => true,
+            };
+        if !valid {
+            errors.push(invalid(
+                "value",
+                format!("Invalid value {:?} for {}", value, self.rule_type.as_str()),
+            ));
+        }
+        if self.policy.is_empty() || self.policy.contains(',') {
+            errors.push(invalid("policy", format!("Invalid policy {:?}", self.policy)));
+        }
+        if self.no_resolve && !self.rule_type.supports_no_resolve() {
+            errors.push(invalid(
+                "no_resolve",
+                format!("{} does not support no-resolve", self.rule_type.as_str()),
+            ));
+        }
+        errors
+    }
+}
+
+/// 自定义规则的部分更新，未提供的字段保持不变
+#[derive(Deserialize, Default)]
+pub struct CustomRulePatch {
+    #[serde(rename = "type")]
+    pub rule_type: Option<RuleType>,
+    pub value: Option<String>,
+    pub policy: Option<String>,
+    pub no_resolve: Option<bool>,
+    pub enabled: Option<bool>,
+}
+
+impl CustomRulePatch {
+    pub fn apply(&self, rule: &mut CustomRule) {
+        if let Some(rule_type) = self.rule_type {
+            rule.rule_type = rule_type;
+        }
+        if let Some(value) = &self.value {
+            rule.value = value.trim().to_string();
+        }
+        if let Some(policy) = &self.policy {
+            rule.policy = policy.trim().to_string();
+        }
+        if let Some(no_resolve) = self.no_resolve {
+            rule.no_resolve = no_resolve;
+        }
+        if let Some(enabled) = self.enabled {
+            rule.enabled = enabled;
+        }
+    }
+}
+
+fn rule_not_found(id: &Uuid) -> ClashError {
+    ClashError {
+        message: format!("Rule not found: {}", id),
+        error_kind: ClashErrorKind::NotFoundError,
+    }
+}
+
+pub fn find_rule_mut<'a>(settings: &'a mut Settings, id: &Uuid) -> Result<&'a mut CustomRule, ClashError> {
+    settings
+        .custom_rules
+        .iter_mut()
+        .find(|x| x.id == *id)
+        .ok_or_else(|| rule_not_found(id))
+}
+
+pub fn remove_rule(settings: &mut Settings, id: &Uuid) -> Result<CustomRule, ClashError> {
+    let index = settings
+        .custom_rules
+        .iter()
+        .position(|x| x.id == *id)
+        .ok_or_else(|| rule_not_found(id))?;
+    Ok(settings.custom_rules.remove(index))
+}
+
+/// 按给定的 id 顺序重排规则，必须包含全部规则且不能重复
+pub fn reorder_rules(settings: &mut Settings, ids: &[Uuid]) -> Result<(), ValidationError> {
+    let mut remaining = settings.custom_rules.clone();
+    let mut ordered = Vec::with_capacity(ids.len());
+    for id in ids {
+        let Some(index) = remaining.iter().position(|x| x.id == *id) else {
+            return Err(ValidationError::new(
+                "ids",
+                ValidationErrorKind::InvalidValue,
+                format!("Unknown or duplicate rule id {}", id),
+            ));
+        };
+        ordered.push(remaining.remove(index));
+    }
+    if !remaining.is_empty() {
+        return Err(ValidationError::new(
+            "ids",
+            ValidationErrorKind::InvalidValue,
+            "All rules must be listed",
+        ));
+    }
+    settings.custom_rules = ordered;
+    Ok(())
+}
+
+/// 启用的自定义规则，策略组不在配置中的规则会被跳过
+pub fn custom_rules(rules: &[CustomRule], config: &Value) -> Vec<String> {
+    let groups: HashSet<String> = proxy_groups(config).into_iter().collect();
+    rules
+        .iter()
+        .filter(|x| x.enabled)
+        .filter(|x| {
+            let known = is_known_policy(&x.policy, &groups);
+            if !known {
+                log::warn!("Skipping rule {}: policy is not in the profile", x.to_rule());
+            }
+            known
+        })
+        .map(|x| x.to_rule())
+        .collect()
+}
@@ -1,12 +1,45 @@
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
 use std::fmt::Display;
-use std::sync::{Arc, RwLock, RwLockWriteGuard};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use reqwest::header::{HeaderName, HeaderValue};
+use uuid::Uuid;
 
 
-use crate::clash::controller::EnhancedMode;
+use crate::clash::controller::{EnhancedMode, LogLevel};
+use crate::dashboards::DashboardDirs;
+use crate::rules::{CustomRule, PresetAction, RulePreset};
+
+// 只能通过专用接口修改的字段
+const READ_ONLY_FIELDS: [&str; 4] = ["version", "secret", "subscriptions", "custom_rules"];
+// 内核 external-controller 占用的端口
+const CONTROLLER_PORT: u16 = 9090;
+// 连续修改合并为一次写入的等待时间
+const SAVE_DEBOUNCE: Duration = Duration::from_millis(500);
+// 持续修改时最长的写入延迟
+const SAVE_MAX_DELAY: Duration = Duration::from_secs(3);
+// 未及时处理的变更事件上限
+const CHANGE_CHANNEL_CAPACITY: usize = 64;
+
+/// 当前的设置文件格式版本
+pub const SETTINGS_VERSION: u32 = 3;
+
+type Migration = fn(&mut Map<String, Value>);
+
+// MIGRATIONS[i] 将版本 i 的设置升级到版本 i + 1，只能追加不能修改
+const MIGRATIONS: [Migration; 3] = [migrate_v0_to_v1, migrate_v1_to_v2, migrate_v2_to_v3];
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Settings {
+    #[serde(default = "default_version")]
+    pub version: u32,
     #[serde(default = "default_backend_port")]
     pub backend_port: u16,
     #[serde(default = "default_external_port")]
@@ -18,7 +51,7 @@ pub struct Settings {
     #[serde(default = "default_enhanced_mode")]
     pub enhanced_mode: EnhancedMode,
     #[serde(default = "default_current_sub")]
-    pub current_sub: String,
+    pub current_sub: Option<Uuid>,
     #[serde(default = "default_subscriptions")]
     pub subscriptions: Vec<Subscription>,
     #[serde(default = "default_allow_remote_access")]
@@ -27,6 +60,50 @@ pub struct Settings {
     pub dashboard: String,
     #[serde(default = "default_secret")]
     pub secret: String,
+    #[serde(default = "default_enable_external_server")]
+    pub enable_external_server: bool,
+    // 外部服务使用自签名证书的 HTTPS
+    #[serde(default)]
+    pub external_tls: bool,
+    #[serde(default = "default_log_level")]
+    pub log_level: LogLevel,
+    #[serde(default)]
+    pub geodata: GeodataSettings,
+    // 未列出的预设不注入规则
+    #[serde(default)]
+    pub rule_presets: BTreeMap<RulePreset, PresetAction>,
+    // 按顺序注入在预设规则之前
+    #[serde(default)]
+    pub custom_rules: Vec<CustomRule>,
+    // 按 Steam AppID 设置游戏进程的策略
+    #[serde(default)]
+    pub game_routes: BTreeMap<u32, PresetAction>,
+}
+
+/// 设置变更事件，由 `SettingsInstance` 在每次修改后广播
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingsChange {
+    BackendPort(u16),
+    ExternalPort(u16),
+    SkipProxy(bool),
+    OverrideDns(bool),
+    EnhancedMode(EnhancedMode),
+    CurrentSub(Option<Uuid>),
+    Subscriptions,
+    AllowRemoteAccess(bool),
+    Dashboard(String),
+    Secret,
+    EnableExternalServer(bool),
+    ExternalTls(bool),
+    LogLevel(LogLevel),
+    Geodata,
+    RulePresets,
+    CustomRules,
+    GameRoutes,
+}
+
+fn default_version() -> u32 {
+    SETTINGS_VERSION
 }
 
 fn default_backend_port() -> u16 {
@@ -49,6 +126,14 @@ fn default_allow_remote_access() -> bool {
     false
 }
 
+fn default_enable_external_server() -> bool {
+    true
+}
+
+fn default_log_level() -> LogLevel {
+    LogLevel::Info
+}
+
 fn default_enhanced_mode() -> EnhancedMode {
     EnhancedMode::FakeIp
 }
@@ -61,24 +146,211 @@ fn default_secret() -> String {
     "".to_string()
 }
 
-fn default_current_sub() -> String {
-    "".to_string()
+/// 生成 external-controller 使用的随机 secret
+pub fn generate_secret() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+fn default_current_sub() -> Option<Uuid> {
+    None
 }
 
 fn default_subscriptions() -> Vec<Subscription> {
     Vec::new()
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// geodata 数据库的下载地址
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GeodataSettings {
+    #[serde(default = "default_mmdb_url")]
+    pub mmdb_url: String,
+    #[serde(default = "default_geosite_url")]
+    pub geosite_url: String,
+    #[serde(default = "default_geoip_url")]
+    pub geoip_url: String,
+    #[serde(default = "default_asn_url")]
+    pub asn_url: String,
+    #[serde(default = "default_geodata_request")]
+    pub request: RequestOptions,
+}
+
+fn default_mmdb_url() -> String {
+    "https://github.com/MetaCubeX/meta-rules-dat/releases/download/latest/country.mmdb".to_string()
+}
+
+fn default_geosite_url() -> String {
+    "https://github.com/MetaCubeX/meta-rules-dat/releases/download/latest/geosite.dat".to_string()
+}
+
+fn default_geoip_url() -> String {
+    "https://github.com/MetaCubeX/meta-rules-dat/releases/download/latest/geoip.dat".to_string()
+}
+
+fn default_asn_url() -> String {
+    "https://github.com/P3TERX/GeoLite.mmdb/raw/download/GeoLite2-ASN.mmdb".to_string()
+}
+
+// 数据库文件较大，默认超时比订阅更长
+fn default_geodata_request() -> RequestOptions {
+    RequestOptions {
+        timeout: 120,
+        ..Default::default()
+    }
+}
+
+impl Default for GeodataSettings {
+    fn default() -> Self {
+        Self {
+            mmdb_url: default_mmdb_url(),
+            geosite_url: default_geosite_url(),
+            geoip_url: default_geoip_url(),
+            asn_url: default_asn_url(),
+            request: default_geodata_request(),
+        }
+    }
+}
+
+/// 订阅，在列表中的顺序即为显示顺序
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Subscription {
+    #[serde(default = "Uuid::new_v4")]
+    pub id: Uuid,
     pub path: String,
     pub url: String,
+    // 为空时显示文件名
+    #[serde(default)]
+    pub name: String,
+    #[serde(default = "default_subscription_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub notes: String,
+    #[serde(default)]
+    pub request: RequestOptions,
+}
+
+fn default_subscription_enabled() -> bool {
+    true
+}
+
+/// 下载与更新订阅时使用的请求参数
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RequestOptions {
+    // 为空时使用 `utils::get_user_agent`
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub basic_auth: Option<BasicAuth>,
+    #[serde(default)]
+    pub cookie: Option<String>,
+    // 单位为秒
+    #[serde(default = "default_request_timeout")]
+    pub timeout: u64,
+    #[serde(default = "default_follow_redirects")]
+    pub follow_redirects: bool,
+    // 允许自签名或过期的 TLS 证书
+    #[serde(default)]
+    pub allow_insecure: bool,
+    #[serde(default)]
+    pub fetch_via: FetchVia,
+    // 直连失败时通过运行中的内核重试
+    #[serde(default = "default_retry_via_core")]
+    pub retry_via_core: bool,
+}
+
+/// 获取订阅时的连接方式
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum FetchVia {
+    #[default]
+    Direct,
+    // 运行中内核的 mixed-port
+    Core,
+    // http://、https://、socks5:// 或 socks5h:// 代理
+    Proxy { url: String },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BasicAuth {
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+}
+
+fn default_request_timeout() -> u64 {
+    30
+}
+
+fn default_follow_redirects() -> bool {
+    true
+}
+
+fn default_retry_via_core() -> bool {
+    true
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        Self {
+            user_agent: None,
+            headers: BTreeMap::new(),
+            basic_auth: None,
+            cookie: None,
+            timeout: default_request_timeout(),
+            follow_redirects: default_follow_redirects(),
+            allow_insecure: false,
+            fetch_via: FetchVia::default(),
+            retry_via_core: default_retry_via_core(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationErrorKind {
+    UnknownField,
+    ReadOnly,
+    InvalidType,
+    InvalidPort,
+    PortConflict,
+    UnknownDashboard,
+    UnknownSubscription,
+    DisabledSubscription,
+    DuplicateId,
+    InvalidValue,
+    MissingSecret,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub field: String,
+    pub kind: ValidationErrorKind,
+    pub message: String,
+}
+
+impl ValidationError {
+    pub fn new(field: &str, kind: ValidationErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            field: field.to_string(),
+            kind,
+            message: message.into(),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum SettingsError {
     Serde(serde_json::Error),
     Io(std::io::Error),
+    Conflict { expected: u64, current: u64 },
+    Invalid(Vec<ValidationError>),
 }
 
 impl Display for SettingsError {
@@ -86,6 +358,18 @@ impl Display for SettingsError {
         match self {
             Self::Serde(e) => (e as &dyn Display).fmt(f),
             Self::Io(e) => (e as &dyn Display).fmt(f),
+            Self::Conflict { expected, current } => write!(
+                f,
+                "Settings changed since revision {}, current revision is {}",
+                expected, current
+            ),
+            Self::Invalid(errors) => {
+                let messages: Vec<String> = errors
+                    .iter()
+                    .map(|e| format!("{}: {}", e.field, e.message))
+                    .collect();
+                write!(f, "Invalid settings: {}", messages.join("; "))
+            }
         }
     }
 }
@@ -93,28 +377,793 @@ impl Display for SettingsError {
 impl Subscription {
     pub fn new(path: String, url: String) -> Self {
         Self {
-            path: path,
-            url: url,
+            id: Uuid::new_v4(),
+            path,
+            url,
+            name: String::new(),
+            enabled: true,
+            tags: Vec::new(),
+            notes: String::new(),
+            request: RequestOptions::default(),
         }
     }
+
+    /// 显示名称，未设置时使用文件名
+    pub fn display_name(&self) -> String {
+        if !self.name.is_empty() {
+            return self.name.clone();
+        }
+        std::path::Path::new(&self.path)
+            .file_stem()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.path.clone())
+    }
 }
 
 impl Settings {
+    /// 先写入临时文件并 fsync，保留上一版为 `.bak` 后再重命名覆盖，
+    /// 避免断电时留下空文件
     pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), SettingsError> {
         let path = path.as_ref();
-        if let Some(parent) = path.parent() {
+        let parent = path.parent();
+        if let Some(parent) = parent {
             std::fs::create_dir_all(parent).map_err(SettingsError::Io)?;
         }
-        let mut file = std::fs::File::create(path).map_err(SettingsError::Io)?;
-        serde_json::to_writer_pretty(&mut file, &self).map_err(SettingsError::Serde)
+
+        let content = serde_json::to_vec_pretty(&self).map_err(SettingsError::Serde)?;
+        let temp = backup_path(path, "tmp");
+        let mut file = std::fs::File::create(&temp).map_err(SettingsError::Io)?;
+        file.write_all(&content).map_err(SettingsError::Io)?;
+        file.sync_all().map_err(SettingsError::Io)?;
+        drop(file);
+
+        if path.exists() {
+            let backup = backup_path(path, "bak");
+            let _ = std::fs::remove_file(&backup);
+            // 硬链接不需要复制内容，失败时退回到复制
+            if std::fs::hard_link(path, &backup).is_err() {
+                std::fs::copy(path, &backup).map_err(SettingsError::Io)?;
+            }
+        }
+        std::fs::rename(&temp, path).map_err(SettingsError::Io)?;
+
+        // 同步目录项，确保重命名本身已落盘
+        if let Some(parent) = parent.filter(|x| !x.as_os_str().is_empty()) {
+            if let Ok(dir) = std::fs::File::open(parent) {
+                let _ = dir.sync_all();
+            }
+        }
+        Ok(())
+    }
+
+    /// 读取设置文件，必要时先备份再迁移到当前版本。
+    /// 内容无法解析时保留一份 `.corrupt` 副本并使用默认设置。
+    /// 返回的布尔值表示是否需要写回文件。
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<(Settings, bool), SettingsError> {
+        let path = path.as_ref();
+        let content = std::fs::read(path).map_err(SettingsError::Io)?;
+
+        let mut fields = match serde_json::from_slice::<Value>(&content) {
+            Ok(Value::Object(x)) => x,
+            Ok(_) => {
+                log::error!("Settings file is not a JSON object");
+                preserve_corrupt(path)?;
+                return Ok((Self::load_backup(path), true));
+            }
+            Err(e) => {
+                log::error!("Failed to parse settings: {}", e);
+                preserve_corrupt(path)?;
+                return Ok((Self::load_backup(path), true));
+            }
+        };
+
+        let version = fields
+            .get("version")
+            .and_then(|x| x.as_u64())
+            .unwrap_or(0) as u32;
+        let migrated = version < SETTINGS_VERSION;
+        if migrated {
+            let backup = backup_path(path, &format!("v{}.bak", version));
+            std::fs::copy(path, &backup).map_err(SettingsError::Io)?;
+            log::info!(
+                "Migrating settings from version {} to {}, backup saved to {}",
+                version,
+                SETTINGS_VERSION,
+                backup.display()
+            );
+            migrate(&mut fields);
+        } else if version > SETTINGS_VERSION {
+            log::warn!(
+                "Settings version {} is newer than supported version {}",
+                version,
+                SETTINGS_VERSION
+            );
+        }
+
+        match serde_json::from_value(Value::Object(fields)) {
+            Ok(settings) => Ok((settings, migrated)),
+            Err(e) => {
+                log::error!("Failed to load settings: {}", e);
+                preserve_corrupt(path)?;
+                Ok((Self::load_backup(path), true))
+            }
+        }
+    }
+
+    /// 从其它来源（如导入的备份包）解析设置，旧版本同样会被迁移
+    pub fn from_bytes(data: &[u8]) -> Result<Settings, SettingsError> {
+        let mut fields = match serde_json::from_slice::<Value>(data).map_err(SettingsError::Serde)? {
+            Value::Object(x) => x,
+            _ => {
+                return Err(SettingsError::Invalid(vec![ValidationError::new(
+                    "",
+                    ValidationErrorKind::InvalidType,
+                    "settings must be a JSON object",
+                )]))
+            }
+        };
+        migrate(&mut fields);
+        serde_json::from_value(Value::Object(fields)).map_err(SettingsError::Serde)
+    }
+
+    // 主文件损坏时尝试上一次保存的备份，仍失败则使用默认设置
+    fn load_backup(path: &std::path::Path) -> Settings {
+        let backup = backup_path(path, "bak");
+        if !backup.exists() {
+            return Settings::default();
+        }
+        let settings = std::fs::read(&backup)
+            .ok()
+            .and_then(|x| serde_json::from_slice::<Value>(&x).ok())
+            .filter(|x| x.is_object())
+            .and_then(|x| serde_json::from_value::<Settings>(x).ok());
+        match settings {
+            Some(settings) => {
+                log::warn!("Restored settings from {}", backup.display());
+                settings
+            }
+            None => Settings::default(),
+        }
+    }
+
+    /// 将 JSON 对象中的字段合并到当前设置，并校验被修改的字段
+    pub fn patched(&self, patch: &Map<String, Value>) -> Result<Settings, Vec<ValidationError>> {
+        let mut value = serde_json::to_value(self).map_err(|e| {
+            vec![ValidationError::new("", ValidationErrorKind::InvalidType, e.to_string())]
+        })?;
+        let fields = value.as_object_mut().unwrap();
+        let mut errors = Vec::new();
+
+        for (key, new_value) in patch {
+            if !fields.contains_key(key) {
+                errors.push(ValidationError::new(
+                    key,
+                    ValidationErrorKind::UnknownField,
+                    "Unknown settings field",
+                ));
+                continue;
+            }
+            if READ_ONLY_FIELDS.contains(&key.as_str()) {
+                errors.push(ValidationError::new(
+                    key,
+                    ValidationErrorKind::ReadOnly,
+                    "This field can not be changed through the settings API",
+                ));
+                continue;
+            }
+            // 单独检查每个字段的类型，以便指出具体出错的字段
+            let mut probe = Value::Object(fields.clone());
+            probe[key] = new_value.clone();
+            if let Err(e) = serde_json::from_value::<Settings>(probe) {
+                errors.push(ValidationError::new(
+                    key,
+                    ValidationErrorKind::InvalidType,
+                    e.to_string(),
+                ));
+                continue;
+            }
+            fields.insert(key.clone(), new_value.clone());
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let settings: Settings = serde_json::from_value(value).map_err(|e| {
+            vec![ValidationError::new("", ValidationErrorKind::InvalidType, e.to_string())]
+        })?;
+        let errors: Vec<ValidationError> = settings
+            .validate()
+            .into_iter()
+            .filter(|e| {
+                // 嵌套字段的错误如 geodata.mmdb_url、custom_rules[0].value 也属于本次修改
+                patch.keys().any(|key| {
+                    e.field
+                        .strip_prefix(key.as_str())
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.') || rest.starts_with('['))
+                })
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(settings)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// 比较两份设置，返回发生变化的字段
+    pub fn changes(&self, new: &Settings) -> Vec<SettingsChange> {
+        let mut changes = Vec::new();
+        if self.backend_port != new.backend_port {
+            changes.push(SettingsChange::BackendPort(new.backend_port));
+        }
+        if self.external_port != new.external_port {
+            changes.push(SettingsChange::ExternalPort(new.external_port));
+        }
+        if self.skip_proxy != new.skip_proxy {
+            changes.push(SettingsChange::SkipProxy(new.skip_proxy));
+        }
+        if self.override_dns != new.override_dns {
+            changes.push(SettingsChange::OverrideDns(new.override_dns));
+        }
+        if self.enhanced_mode != new.enhanced_mode {
+            changes.push(SettingsChange::EnhancedMode(new.enhanced_mode));
+        }
+        if self.current_sub != new.current_sub {
+            changes.push(SettingsChange::CurrentSub(new.current_sub));
+        }
+        if self.subscriptions != new.subscriptions {
+            changes.push(SettingsChange::Subscriptions);
+        }
+        if self.allow_remote_access != new.allow_remote_access {
+            changes.push(SettingsChange::AllowRemoteAccess(new.allow_remote_access));
+        }
+        if self.dashboard != new.dashboard {
+            changes.push(SettingsChange::Dashboard(new.dashboard.clone()));
+        }
+        if self.secret != new.secret {
+            changes.push(SettingsChange::Secret);
+        }
+        if self.enable_external_server != new.enable_external_server {
+            changes.push(SettingsChange::EnableExternalServer(new.enable_external_server));
+        }
+        if self.external_tls != new.external_tls {
+            changes.push(SettingsChange::ExternalTls(new.external_tls));
+        }
+        if self.log_level != new.log_level {
+            changes.push(SettingsChange::LogLevel(new.log_level));
+        }
+        if self.geodata != new.geodata {
+            changes.push(SettingsChange::Geodata);
+        }
+        if self.rule_presets != new.rule_presets {
+            changes.push(SettingsChange::RulePresets);
+        }
+        if self.custom_rules != new.custom_rules {
+            changes.push(SettingsChange::CustomRules);
+        }
+        if self.game_routes != new.game_routes {
+            changes.push(SettingsChange::GameRoutes);
+        }
+        changes
+    }
+
+    /// 当前选择的订阅
+    pub fn current_subscription(&self) -> Option<&Subscription> {
+        let id = self.current_sub?;
+        self.subscriptions.iter().find(|x| x.id == id)
+    }
+
+    /// 当前订阅的配置文件路径
+    pub fn current_sub_path(&self) -> Option<&str> {
+        self.current_subscription().map(|x| x.path.as_str())
+    }
+
+    pub fn validate(&self) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+
+        for (field, port) in [
+            ("backend_port", self.backend_port),
+            ("external_port", self.external_port),
+        ] {
+            if port < 1024 {
+                errors.push(ValidationError::new(
+                    field,
+                    ValidationErrorKind::InvalidPort,
+                    "Port must be between 1024 and 65535",
+                ));
+            } else if port == CONTROLLER_PORT {
+                errors.push(ValidationError::new(
+                    field,
+                    ValidationErrorKind::PortConflict,
+                    "Port is used by the external controller",
+                ));
+            }
+        }
+        if self.backend_port == self.external_port {
+            for field in ["backend_port", "external_port"] {
+                errors.push(ValidationError::new(
+                    field,
+                    ValidationErrorKind::PortConflict,
+                    "backend_port and external_port must be different",
+                ));
+            }
+        }
+
+        let dashboard = DashboardDirs::local().ok().and_then(|x| x.find(&self.dashboard));
+        if dashboard.is_none() {
+            errors.push(ValidationError::new(
+                "dashboard",
+                ValidationErrorKind::UnknownDashboard,
+                format!("Dashboard {} is not installed", self.dashboard),
+            ));
+        }
+
+        if self.current_sub.is_some() {
+            match self.current_subscription() {
+                None => errors.push(ValidationError::new(
+                    "current_sub",
+                    ValidationErrorKind::UnknownSubscription,
+                    "current_sub must be one of the saved subscriptions",
+                )),
+                Some(x) if !x.enabled => errors.push(ValidationError::new(
+                    "current_sub",
+                    ValidationErrorKind::DisabledSubscription,
+                    "The selected subscription is disabled",
+                )),
+                _ => (),
+            }
+        }
+
+        let mut ids = std::collections::HashSet::new();
+        for (i, sub) in self.subscriptions.iter().enumerate() {
+            let field = format!("subscriptions[{}]", i);
+            if !ids.insert(sub.id) {
+                errors.push(ValidationError::new(
+                    &format!("{}.id", field),
+                    ValidationErrorKind::DuplicateId,
+                    format!("Duplicate subscription id {}", sub.id),
+                ));
+            }
+            errors.extend(sub.validate(&field));
+        }
+
+        errors.extend(self.geodata.validate("geodata"));
+
+        let mut rule_ids = std::collections::HashSet::new();
+        for (i, rule) in self.custom_rules.iter().enumerate() {
+            let field = format!("custom_rules[{}]", i);
+            if !rule_ids.insert(rule.id) {
+                errors.push(ValidationError::new(
+                    &format!("{}.id", field),
+                    ValidationErrorKind::DuplicateId,
+                    format!("Duplicate rule id {}", rule.id),
+                ));
+            }
+            errors.extend(rule.validate(&field));
+        }
+
+        // 策略组是否存在取决于订阅，在生成配置时检查
+        let actions = self
+            .rule_presets
+            .values()
+            .map(|x| ("rule_presets", x))
+            .chain(self.game_routes.values().map(|x| ("game_routes", x)));
+        for (field, action) in actions {
+            if let PresetAction::Proxy { group } = action {
+                if group.is_empty() || group.contains(',') {
+                    errors.push(ValidationError::new(
+                        field,
+                        ValidationErrorKind::InvalidValue,
+                        format!("Invalid proxy group {:?}", group),
+                    ));
+                }
+            }
+        }
+
+        if self.allow_remote_access && self.secret.is_empty() {
+            errors.push(ValidationError::new(
+                "allow_remote_access",
+                ValidationErrorKind::MissingSecret,
+                "A secret is required before enabling remote access",
+            ));
+        }
+
+        errors
+    }
+}
+
+// 显示名称、标签与备注的长度上限
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_TAG_LENGTH: usize = 32;
+const MAX_TAGS: usize = 16;
+const MAX_NOTES_LENGTH: usize = 1024;
+// 订阅请求超时的上限，单位为秒
+const MAX_REQUEST_TIMEOUT: u64 = 300;
+
+impl Subscription {
+    pub fn validate(&self, field: &str) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+        if self.name.chars().count() > MAX_NAME_LENGTH {
+            errors.push(ValidationError::new(
+                &format!("{}.name", field),
+                ValidationErrorKind::InvalidValue,
+                format!("Name must be at most {} characters", MAX_NAME_LENGTH),
+            ));
+        }
+        if self.tags.len() > MAX_TAGS {
+            errors.push(ValidationError::new(
+                &format!("{}.tags", field),
+                ValidationErrorKind::InvalidValue,
+                format!("At most {} tags are allowed", MAX_TAGS),
+            ));
+        }
+        for tag in &self.tags {
+            if tag.trim().is_empty() || tag.chars().count() > MAX_TAG_LENGTH {
+                errors.push(ValidationError::new(
+                    &format!("{}.tags", field),
+                    ValidationErrorKind::InvalidValue,
+                    format!("Tags must be 1 to {} characters", MAX_TAG_LENGTH),
+                ));
+            }
+        }
+        if self.notes.chars().count() > MAX_NOTES_LENGTH {
+            errors.push(ValidationError::new(
+                &format!("{}.notes", field),
+                ValidationErrorKind::InvalidValue,
+                format!("Notes must be at most {} characters", MAX_NOTES_LENGTH),
+            ));
+        }
+        errors.extend(self.request.validate(&format!("{}.request", field)));
+        errors
+    }
+}
+
+impl RequestOptions {
+    pub fn validate(&self, field: &str) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+        let invalid = |name: &str, message: String| {
+            ValidationError::new(
+                &format!("{}.{}", field, name),
+                ValidationErrorKind::InvalidValue,
+                message,
+            )
+        };
+        if !(1..=MAX_REQUEST_TIMEOUT).contains(&self.timeout) {
+            errors.push(invalid(
+                "timeout",
+                format!("Timeout must be between 1 and {} seconds", MAX_REQUEST_TIMEOUT),
+            ));
+        }
+        if let Some(user_agent) = &self.user_agent {
+            if HeaderValue::from_str(user_agent).is_err() {
+                errors.push(invalid("user_agent", "Invalid User-Agent".to_string()));
+            }
+        }
+        if let Some(cookie) = &self.cookie {
+            if HeaderValue::from_str(cookie).is_err() {
+                errors.push(invalid("cookie", "Invalid cookie".to_string()));
+            }
+        }
+        for (name, value) in &self.headers {
+            if HeaderName::from_bytes(name.as_bytes()).is_err()
+                || HeaderValue::from_str(value).is_err()
+            {
+                errors.push(invalid("headers", format!("Invalid header {}", name)));
+            }
+        }
+        if let FetchVia::Proxy { url } = &self.fetch_via {
+            let scheme = url.split_once("://").map(|x| x.0);
+            if !matches!(scheme, Some("http" | "https" | "socks5" | "socks5h"))
+                || reqwest::Proxy::all(url).is_err()
+            {
+                errors.push(invalid(
+                    "fetch_via",
+                    format!("Invalid proxy {}", url),
+                ));
+            }
+        }
+        if self.basic_auth.as_ref().is_some_and(|x| x.username.contains(':')) {
+            errors.push(invalid(
+                "basic_auth",
+                "Username must not contain ':'".to_string(),
+            ));
+        }
+        errors
+    }
+}
+
+impl GeodataSettings {
+    pub fn validate(&self, field: &str) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+        for (name, url) in [
+            ("mmdb_url", &self.mmdb_url),
+            ("geosite_url", &self.geosite_url),
+            ("geoip_url", &self.geoip_url),
+            ("asn_url", &self.asn_url),
+        ] {
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                errors.push(ValidationError::new(
+                    &format!("{}.{}", field, name),
+                    ValidationErrorKind::InvalidValue,
+                    format!("Invalid URL {}", url),
+                ));
+            }
+        }
+        errors.extend(self.request.validate(&format!("{}.request", field)));
+        errors
+    }
+}
+
+fn backup_path(path: &std::path::Path, suffix: &str) -> std::path::PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+fn preserve_corrupt(path: &std::path::Path) -> Result<(), SettingsError> {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+    let corrupt = backup_path(path, &format!("{}.corrupt", timestamp));
+    std::fs::copy(path, &corrupt).map_err(SettingsError::Io)?;
+    log::warn!(
+        "Unreadable settings preserved as {}, using defaults",
+        corrupt.display()
+    );
+    Ok(())
+}
+
+// 依次执行旧版本到当前版本之间的迁移
+fn migrate(fields: &mut Map<String, Value>) {
+    let version = fields
+        .get("version")
+        .and_then(|x| x.as_u64())
+        .unwrap_or(0) as usize;
+    for migration in MIGRATIONS.iter().skip(version) {
+        migration(fields);
+    }
+    if version < SETTINGS_VERSION as usize {
+        fields.insert("version".to_string(), Value::from(SETTINGS_VERSION));
     }
+}
 
-    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Settings, SettingsError> {
-        let mut file = std::fs::File::open(path).map_err(SettingsError::Io)?;
-        serde_json::from_reader(&mut file).map_err(SettingsError::Serde)
+// 旧版前端会把 dashboard 保存为完整路径，只保留目录名
+fn migrate_v0_to_v1(fields: &mut Map<String, Value>) {
+    if let Some(Value::String(dashboard)) = fields.get_mut("dashboard") {
+        if let Some(name) = dashboard.trim_end_matches('/').rsplit('/').next() {
+            *dashboard = name.to_string();
+        }
     }
 }
 
+// 为订阅分配固定的 id，替代按下标引用
+fn migrate_v1_to_v2(fields: &mut Map<String, Value>) {
+    if let Some(Value::Array(subs)) = fields.get_mut("subscriptions") {
+        for sub in subs.iter_mut().filter_map(|x| x.as_object_mut()) {
+            sub.entry("id")
+                .or_insert_with(|| Value::String(Uuid::new_v4().to_string()));
+            sub.entry("enabled").or_insert(Value::Bool(true));
+        }
+    }
+}
+
+// current_sub 由文件路径改为订阅 id，找不到对应订阅时清空
+fn migrate_v2_to_v3(fields: &mut Map<String, Value>) {
+    let path = fields.get("current_sub").and_then(|x| x.as_str()).map(|x| x.to_string());
+    let id = path.and_then(|path| {
+        fields
+            .get("subscriptions")?
+            .as_array()?
+            .iter()
+            .find(|x| x.get("path").and_then(|x| x.as_str()) == Some(path.as_str()))?
+            .get("id")
+            .cloned()
+    });
+    fields.insert("current_sub".to_string(), id.unwrap_or(Value::Null));
+}
+
+/// 设置的 JSON Schema，字段需与 `Settings` 保持一致
+pub fn settings_schema() -> Value {
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "ToMoon settings",
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "version": {
+                "type": "integer",
+                "readOnly": true,
+                "const": SETTINGS_VERSION,
+                "description": "Format version of the settings file"
+            },
+            "backend_port": {
+                "type": "integer",
+                "minimum": 1024,
+                "maximum": 65535,
+                "default": default_backend_port(),
+                "description": "Port of the local backend, applied after restart"
+            },
+            "external_port": {
+                "type": "integer",
+                "minimum": 1024,
+                "maximum": 65535,
+                "default": default_external_port(),
+                "description": "Port of the LAN-facing server, applied after restart"
+            },
+            "skip_proxy": {
+                "type": "boolean",
+                "default": default_skip_proxy(),
+                "description": "Connect Steam download servers directly"
+            },
+            "override_dns": {
+                "type": "boolean",
+                "default": default_override_dns(),
+                "description": "Replace the DNS section of the profile"
+            },
+            "enhanced_mode": {
+                "type": "string",
+                "enum": ["FakeIp", "RedirHost"],
+                "default": default_enhanced_mode(),
+                "description": "DNS enhanced mode used when override_dns is enabled"
+            },
+            "current_sub": {
+                "type": ["string", "null"],
+                "format": "uuid",
+                "default": default_current_sub(),
+                "description": "Id of the selected subscription"
+            },
+            "subscriptions": {
+                "type": "array",
+                "readOnly": true,
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "id": { "type": "string", "format": "uuid" },
+                        "path": { "type": "string" },
+                        "url": { "type": "string" },
+                        "name": { "type": "string", "maxLength": MAX_NAME_LENGTH },
+                        "enabled": { "type": "boolean" },
+                        "tags": {
+                            "type": "array",
+                            "maxItems": MAX_TAGS,
+                            "items": { "type": "string", "minLength": 1, "maxLength": MAX_TAG_LENGTH }
+                        },
+                        "notes": { "type": "string", "maxLength": MAX_NOTES_LENGTH },
+                        "request": request_options_schema()
+                    }
+                }
+            },
+            "allow_remote_access": {
+                "type": "boolean",
+                "default": default_allow_remote_access(),
+                "description": "Expose the external controller on all interfaces"
+            },
+            "dashboard": {
+                "type": "string",
+                "default": default_dashboard(),
+                "description": "Name of an installed dashboard"
+            },
+            "secret": {
+                "type": "string",
+                "readOnly": true,
+                "description": "Secret of the external controller, see /rotate_secret"
+            },
+            "enable_external_server": {
+                "type": "boolean",
+                "default": default_enable_external_server(),
+                "description": "Run the LAN-facing server, applied after restart"
+            },
+            "external_tls": {
+                "type": "boolean",
+                "default": false,
+                "description": "Serve the LAN-facing server over HTTPS with a self-signed certificate, applied after restart"
+            },
+            "log_level": {
+                "type": "string",
+                "enum": ["silent", "error", "warning", "info", "debug"],
+                "default": default_log_level(),
+                "description": "Log level of the core, applied without restart"
+            },
+            "geodata": geodata_schema(),
+            "rule_presets": {
+                "type": "object",
+                "description": "Route bundled rule presets to DIRECT or a proxy group",
+                "propertyNames": { "enum": RulePreset::ALL },
+                "additionalProperties": preset_action_schema()
+            },
+            "game_routes": {
+                "type": "object",
+                "description": "Route installed Steam games, keyed by AppID, to DIRECT or a proxy group",
+                "propertyNames": { "pattern": "^[0-9]+$" },
+                "additionalProperties": preset_action_schema()
+            },
+            "custom_rules": {
+                "type": "array",
+                "readOnly": true,
+                "description": "User rules, managed through /rules/custom",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "id": { "type": "string", "format": "uuid" },
+                        "type": { "type": "string" },
+                        "value": { "type": "string" },
+                        "policy": { "type": "string" },
+                        "no_resolve": { "type": "boolean" },
+                        "enabled": { "type": "boolean" }
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn preset_action_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "action": { "enum": ["direct", "proxy"] },
+            "group": { "type": "string", "minLength": 1 }
+        },
+        "required": ["action"]
+    })
+}
+
+fn geodata_schema() -> Value {
+    json!({
+        "type": "object",
+        "description": "Download URLs of the GeoIP, GeoSite and ASN databases",
+        "properties": {
+            "mmdb_url": { "type": "string", "format": "uri", "default": default_mmdb_url() },
+            "geosite_url": { "type": "string", "format": "uri", "default": default_geosite_url() },
+            "geoip_url": { "type": "string", "format": "uri", "default": default_geoip_url() },
+            "asn_url": { "type": "string", "format": "uri", "default": default_asn_url() },
+            "request": request_options_schema()
+        }
+    })
+}
+
+// 单独构造以避免 json! 宏展开过深
+fn request_options_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "user_agent": { "type": ["string", "null"] },
+            "headers": {
+                "type": "object",
+                "additionalProperties": { "type": "string" }
+            },
+            "basic_auth": {
+                "type": ["object", "null"],
+                "properties": {
+                    "username": { "type": "string" },
+                    "password": { "type": "string" }
+                },
+                "required": ["username"]
+            },
+            "cookie": { "type": ["string", "null"] },
+            "timeout": {
+                "type": "integer",
+                "minimum": 1,
+                "maximum": MAX_REQUEST_TIMEOUT,
+                "default": default_request_timeout()
+            },
+            "follow_redirects": { "type": "boolean", "default": true },
+            "allow_insecure": { "type": "boolean", "default": false },
+            "fetch_via": {
+                "type": "object",
+                "properties": {
+                    "mode": { "enum": ["direct", "core", "proxy"] },
+                    "url": { "type": "string" }
+                },
+                "required": ["mode"]
+            },
+            "retry_via_core": { "type": "boolean", "default": true }
+        }
+    })
+}
+
 impl Default for Settings {
     fn default() -> Self {
         serde_json::from_str("{}").unwrap()
@@ -125,32 +1174,101 @@ impl Default for Settings {
 pub struct SettingsInstance {
     settings: Arc<RwLock<Settings>>,
     path: std::path::PathBuf,
+    // 每次修改递增，用于避免不同界面之间互相覆盖
+    revision: Arc<AtomicU64>,
+    // 通知后台线程延迟保存
+    save_tx: mpsc::Sender<()>,
+    save_lock: Arc<Mutex<()>>,
+    // 串行化修改，保证变更事件与修改一一对应
+    update_lock: Arc<Mutex<()>>,
+    changes: broadcast::Sender<SettingsChange>,
 }
 
 impl SettingsInstance {
+    /// 使用默认设置创建实例，并生成新的 secret
     pub fn new<P: AsRef<std::path::Path>>(path: P) -> Self {
+        let settings = Settings {
+            secret: generate_secret(),
+            ..Default::default()
+        };
+        Self::with_settings(path, settings)
+    }
+
+    fn with_settings<P: AsRef<std::path::Path>>(path: P, settings: Settings) -> Self {
+        let settings = Arc::new(RwLock::new(settings));
+        let path = path.as_ref().to_path_buf();
+        let save_lock = Arc::new(Mutex::new(()));
+        let save_tx = Self::spawn_saver(settings.clone(), path.clone(), save_lock.clone());
+        let (changes, _) = broadcast::channel(CHANGE_CHANNEL_CAPACITY);
         Self {
-            settings: Arc::new(RwLock::new(Settings::default())),
-            path: path.as_ref().to_path_buf(),
+            settings,
+            path,
+            revision: Arc::new(AtomicU64::new(0)),
+            save_tx,
+            save_lock,
+            update_lock: Arc::new(Mutex::new(())),
+            changes,
         }
     }
 
+    /// 后台保存线程：收到通知后等待修改停止一段时间再写入，
+    /// 所有实例释放后完成最后一次写入并退出
+    fn spawn_saver(
+        settings: Arc<RwLock<Settings>>,
+        path: std::path::PathBuf,
+        save_lock: Arc<Mutex<()>>,
+    ) -> mpsc::Sender<()> {
+        let (tx, rx) = mpsc::channel::<()>();
+        std::thread::spawn(move || {
+            while rx.recv().is_ok() {
+                let started = Instant::now();
+                while started.elapsed() < SAVE_MAX_DELAY {
+                    if rx.recv_timeout(SAVE_DEBOUNCE).is_err() {
+                        break;
+                    }
+                }
+                let _guard = save_lock.lock().unwrap();
+                let snapshot = settings.read().unwrap().clone();
+                if let Err(e) = snapshot.save(&path) {
+                    log::error!("Failed to save settings: {}", e);
+                }
+            }
+        });
+        tx
+    }
+
     pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self, SettingsError> {
-        if path.as_ref().exists() {
-            let settings = Arc::new(RwLock::new(Settings::open(path.as_ref())?));
-            Ok(Self {
-                settings,
-                path: path.as_ref().to_path_buf(),
-            })
+        let instance = if path.as_ref().exists() {
+            let (settings, dirty) = Settings::load(path.as_ref())?;
+            let instance = Self::with_settings(path, settings);
+            if dirty {
+                instance.save()?;
+            }
+            instance
         } else {
             let instance = Self::new(path);
             instance.save()?;
-            Ok(instance)
+            instance
+        };
+        // 旧配置没有 secret 时生成并保存
+        if instance.get().secret.is_empty() {
+            instance.update(|mut x| x.secret = generate_secret())?;
         }
+        Ok(instance)
     }
 
+    /// 立即写入磁盘
     pub fn save(&self) -> Result<(), SettingsError> {
-        self.settings.write().unwrap().save(&self.path)?;
+        let _guard = self.save_lock.lock().unwrap();
+        let snapshot = self.get();
+        snapshot.save(&self.path)
+    }
+
+    // 合并短时间内的多次修改，减少对 SD 卡的写入
+    fn schedule_save(&self) -> Result<(), SettingsError> {
+        if self.save_tx.send(()).is_err() {
+            return self.save();
+        }
         Ok(())
     }
 
@@ -158,9 +1276,92 @@ impl SettingsInstance {
         self.settings.read().unwrap().clone()
     }
 
+    /// 读取设置及其对应的修订号
+    pub fn get_with_revision(&self) -> (Settings, u64) {
+        let settings = self.settings.read().unwrap();
+        (settings.clone(), self.revision.load(Ordering::SeqCst))
+    }
+
+    /// 订阅设置变更事件
+    pub fn subscribe(&self) -> broadcast::Receiver<SettingsChange> {
+        self.changes.subscribe()
+    }
+
+    fn notify(&self, old: &Settings, new: &Settings) {
+        for change in old.changes(new) {
+            log::debug!("Settings changed: {:?}", change);
+            // 没有订阅者时发送失败，可以忽略
+            let _ = self.changes.send(change);
+        }
+    }
+
     pub fn update<T>(&self, function: impl Fn (RwLockWriteGuard<'_, Settings>) -> T) -> Result<T, SettingsError> {
+        let _guard = self.update_lock.lock().unwrap();
+        let old = self.get();
         let result = function(self.settings.write().unwrap());
-        self.save()?;
+        self.revision.fetch_add(1, Ordering::SeqCst);
+        self.notify(&old, &self.get());
+        self.schedule_save()?;
         Ok(result)
     }
+
+    /// 在副本上修改并校验，只拒绝本次修改新引入的错误
+    pub fn modify<T, E: From<SettingsError>>(
+        &self,
+        function: impl FnOnce(&mut Settings) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let _guard = self.update_lock.lock().unwrap();
+        let old = self.get();
+        let mut new = old.clone();
+        let result = function(&mut new)?;
+
+        let before = old.validate();
+        let errors: Vec<ValidationError> = new
+            .validate()
+            .into_iter()
+            .filter(|x| !before.contains(x))
+            .collect();
+        if !errors.is_empty() {
+            return Err(SettingsError::Invalid(errors).into());
+        }
+
+        *self.settings.write().unwrap() = new.clone();
+        self.revision.fetch_add(1, Ordering::SeqCst);
+        self.notify(&old, &new);
+        self.schedule_save()?;
+        Ok(result)
+    }
+
+    /// 在修订号未变化时整体替换设置，返回新的修订号
+    pub fn replace(&self, expected_revision: Option<u64>, settings: Settings) -> Result<u64, SettingsError> {
+        self.replace_with(expected_revision, settings, || Ok(()))
+    }
+
+    /// 修订号校验通过后先执行 `prepare`，成功后才替换设置
+    pub fn replace_with<E: From<SettingsError>>(
+        &self,
+        expected_revision: Option<u64>,
+        settings: Settings,
+        prepare: impl FnOnce() -> Result<(), E>,
+    ) -> Result<u64, E> {
+        // 修订号只在持有 update_lock 时变化
+        let _guard = self.update_lock.lock().unwrap();
+        let old = self.get();
+        let revision = self.revision.load(Ordering::SeqCst);
+        if let Some(expected) = expected_revision.filter(|x| *x != revision) {
+            return Err(SettingsError::Conflict {
+                expected,
+                current: revision,
+            }
+            .into());
+        }
+        prepare()?;
+        let revision = {
+            *self.settings.write().unwrap() = settings.clone();
+            self.revision.fetch_add(1, Ordering::SeqCst) + 1
+        };
+        self.notify(&old, &settings);
+        self.schedule_save()?;
+        Ok(revision)
+    }
 }
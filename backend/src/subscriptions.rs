@@ -1,11 +1,29 @@
 use rand::{distributions::Alphanumeric, Rng};
-use std::{fs, io::ErrorKind, path::PathBuf};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::{fs, io::ErrorKind, path::PathBuf, time::Duration};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
 use content_disposition;
+use uuid::Uuid;
 
 use crate::{
-    clash::{controller::{ClashError, ClashErrorKind}}, settings::{SettingsInstance, Subscription}, utils::{self, get_sub_dir}
+    clash::{controller::{get_running_proxy, ClashError, ClashErrorKind}},
+    jobs::{ItemStatus, Job, JobItem, JobKind, JobRegistry},
+    providers,
+    settings::{
+        FetchVia, RequestOptions, Settings, SettingsInstance, Subscription, ValidationError,
+        ValidationErrorKind,
+    }, utils::{self, get_sub_dir}
 };
 
+// 同时更新的订阅数量上限
+const MAX_CONCURRENT_UPDATES: usize = 4;
+// 单个订阅更新（含重试）的总时长上限
+const SUB_UPDATE_TIMEOUT: Duration = Duration::from_secs(120);
+
 fn sanitize_filename(name: String) -> String {
     let name = name.replace(" ", "_");
     let name = name.replace("/", "_");
@@ -20,7 +38,88 @@ fn gen_random_name() -> String {
         .collect()
 }
 
-async fn fetch_sub(url: &String) -> Result<(String, Option<String>), ClashError> {
+// 按订阅的请求参数构造请求，`proxy` 为空时直连
+pub fn build_request(
+    url: &str,
+    options: &RequestOptions,
+    proxy: Option<&str>,
+) -> Result<reqwest::RequestBuilder, ClashError> {
+    let network_error = |e: reqwest::Error| ClashError {
+        message: e.to_string(),
+        error_kind: ClashErrorKind::NetworkError,
+    };
+    let redirect = if options.follow_redirects {
+        reqwest::redirect::Policy::limited(10)
+    } else {
+        reqwest::redirect::Policy::none()
+    };
+    let mut builder = reqwest::Client::builder()
+        .user_agent(options.user_agent.clone().unwrap_or_else(utils::get_user_agent))
+        .timeout(Duration::from_secs(options.timeout))
+        .redirect(redirect)
+        .danger_accept_invalid_certs(options.allow_insecure);
+    builder = match proxy {
+        Some(proxy) => builder.proxy(reqwest::Proxy::all(proxy).map_err(network_error)?),
+        None => builder.no_proxy(),
+    };
+    let client = builder.build().map_err(network_error)?;
+
+    let mut request = client.get(url);
+    for (name, value) in &options.headers {
+        request = request.header(name, value);
+    }
+    if let Some(auth) = &options.basic_auth {
+        request = request.basic_auth(&auth.username, Some(&auth.password));
+    }
+    if let Some(cookie) = &options.cookie {
+        request = request.header(reqwest::header::COOKIE, cookie);
+    }
+    Ok(request)
+}
+
+async fn send_request(
+    url: &str,
+    options: &RequestOptions,
+    proxy: Option<&str>,
+) -> Result<reqwest::Response, ClashError> {
+    build_request(url, options, proxy)?
+        .send()
+        .await
+        .map_err(|e| ClashError {
+            message: if e.is_timeout() {
+                format!("Request timed out after {} seconds", options.timeout)
+            } else {
+                e.to_string()
+            },
+            error_kind: ClashErrorKind::NetworkError,
+        })
+}
+
+// 按订阅的连接方式发送请求，直连失败时可以通过内核重试
+pub async fn fetch_remote(url: &str, options: &RequestOptions) -> Result<reqwest::Response, ClashError> {
+    match &options.fetch_via {
+        FetchVia::Direct => match send_request(url, options, None).await {
+            Err(e) if options.retry_via_core => match get_running_proxy() {
+                Some(proxy) => {
+                    log::warn!("Direct fetch failed ({}), retrying through {}", e, proxy);
+                    send_request(url, options, Some(&proxy)).await
+                }
+                None => Err(e),
+            },
+            result => result,
+        },
+        FetchVia::Core => {
+            let proxy = get_running_proxy().ok_or_else(|| ClashError {
+                message: "Clash is not running or has no proxy port".to_string(),
+                error_kind: ClashErrorKind::NetworkError,
+            })?;
+            send_request(url, options, Some(&proxy)).await
+        }
+        FetchVia::Proxy { url: proxy } => send_request(url, options, Some(proxy)).await,
+    }
+}
+
+async fn fetch_sub(url: &String, options: &RequestOptions) -> Result<(String, Option<String>), ClashError> {
     let sub_name: Option<String>;
     let file_content: String;
     //是一个本地文件
@@ -51,15 +150,9 @@ async fn fetch_sub(url: &String) -> Result<(String, Option<String>), ClashError>
             });
         }
     } else {
-        let response = minreq::get(url.clone())
-            .with_header("User-Agent", utils::get_user_agent())
-            .send().await
-            .map_err(|e| ClashError {
-                message: e.to_string(),
-                error_kind: ClashErrorKind::NetworkError,
-            })?;
-        
-        match response.status_code {
+        let response = fetch_remote(url, options).await?;
+
+        match response.status().as_u16() {
             200 => (),
             404 => {
                 return Err(ClashError {
@@ -67,6 +160,12 @@ async fn fetch_sub(url: &String) -> Result<(String, Option<String>), ClashError>
                     error_kind: ClashErrorKind::NotFoundError,
                 })
             },
+            c @ 300..=399 => {
+                return Err(ClashError {
+                    message: format!("Status Code: {}, redirects are disabled for this subscription", c),
+                    error_kind: ClashErrorKind::NetworkError,
+                });
+            },
             c => {
                 return Err(ClashError {
                     message: format!("Status Code: {}", c),
@@ -74,7 +173,8 @@ async fn fetch_sub(url: &String) -> Result<(String, Option<String>), ClashError>
                 });
             },
         };
-        sub_name = response.headers.get("content-disposition")
+        sub_name = response.headers().get("content-disposition")
+            .and_then(|header| header.to_str().ok())
             .and_then(|header| {
                 // 尝试从 content-disposition 头部获取文件名
                 content_disposition::parse_content_disposition(header).filename_full()
@@ -86,11 +186,11 @@ async fn fetch_sub(url: &String) -> Result<(String, Option<String>), ClashError>
                 url.rsplit('/').next()
                     .and_then(|last_part| last_part.split('?').next()).map(|s| s.to_string())
             });
-    
-        file_content = response.as_str().map_err(|e| ClashError {
+
+        file_content = response.text().await.map_err(|e| ClashError {
             message: e.to_string(),
             error_kind: ClashErrorKind::ContentError,
-        })?.to_string();
+        })?;
     }
     let sub_name = sub_name.map(|s| sanitize_filename(s));
     if !utils::check_yaml(&file_content) {
@@ -104,7 +204,7 @@ async fn fetch_sub(url: &String) -> Result<(String, Option<String>), ClashError>
 }
 
 async fn update_sub(sub: &Subscription) -> Result<(), ClashError> {
-    let (content, _) = match fetch_sub(&sub.url).await {
+    let (content, _) = match fetch_sub(&sub.url, &sub.request).await {
         Ok(x) => x,
         Err(e) => {
             log::error!("Failed while updating sub.");
@@ -121,8 +221,12 @@ async fn update_sub(sub: &Subscription) -> Result<(), ClashError> {
     Ok(())
 }
 
-pub async fn download_new_sub(url: &String, settings: &SettingsInstance) -> Result<String, ClashError> {
-    let (file_content, sub_name) = match fetch_sub(&url).await {
+pub async fn download_new_sub(
+    url: &String,
+    options: RequestOptions,
+    settings: &SettingsInstance,
+) -> Result<Subscription, ClashError> {
+    let (file_content, sub_name) = match fetch_sub(&url, &options).await {
         Ok(x) => x,
         Err(e) => {
             log::error!("Failed while fetching sub.");
@@ -168,24 +272,234 @@ pub async fn download_new_sub(url: &String, settings: &SettingsInstance) -> Resu
     //修改下载状态
     log::info!("Download profile successfully.");
     //存入设置
-    settings.update(|mut x|
-        x.subscriptions.push(crate::settings::Subscription::new(
-            filepath.to_str().unwrap().to_string(),
-            url.clone())
-        ))
+    let mut sub = Subscription::new(filepath.to_str().unwrap().to_string(), url.clone());
+    sub.request = options;
+    settings.update(|mut x| x.subscriptions.push(sub.clone()))
         .map_err(|e| ClashError {
                 message: e.to_string(),
             error_kind: ClashErrorKind::NotFoundError,
         })?;
-    Ok(sub_name)
+    Ok(sub)
 }
 
-pub async fn update_subs(subs: Vec<Subscription>) {
-    for i in subs {
-        // 异步任务
-        match update_sub(&i).await {
-            Ok(_) => log::info!("Subscription {} updated.", i.path),
-            Err(e) => log::error!("Error updating subscription: {}", e),
+// 订阅下载后预取其中的 provider，失败只记录日志，不影响订阅本身
+async fn prefetch_providers(sub: &Subscription) {
+    let result = match utils::get_provider_dir() {
+        Ok(dir) => providers::prefetch(sub, &dir, false).await,
+        Err(e) => Err(ClashError {
+            message: e.to_string(),
+            error_kind: ClashErrorKind::IOError,
+        }),
+    };
+    match result {
+        Ok(report) if !report.failed.is_empty() => {
+            log::warn!("{} providers of {} failed to prefetch", report.failed.len(), sub.path)
         }
+        Ok(_) => (),
+        Err(e) => log::error!("Failed to prefetch providers of {}: {}", sub.path, e),
     }
 }
+
+/// 在后台下载新订阅，成功时任务结果为新建的订阅
+pub fn start_download(
+    jobs: &JobRegistry,
+    url: String,
+    options: RequestOptions,
+    settings: SettingsInstance,
+) -> Job {
+    jobs.spawn(JobKind::DownloadSub, vec![], move |_| async move {
+        let sub = download_new_sub(&url, options, &settings).await?;
+        prefetch_providers(&sub).await;
+        serde_json::to_value(sub).map_err(|e| ClashError {
+            message: e.to_string(),
+            error_kind: ClashErrorKind::ContentError,
+        })
+    })
+}
+
+/// 并发更新给定的订阅。已有更新任务在运行时，订阅相同则返回该任务，否则返回冲突错误
+pub fn start_update(jobs: &JobRegistry, subs: Vec<Subscription>) -> Result<Job, ClashError> {
+    let items: Vec<JobItem> = subs
+        .iter()
+        .map(|x| JobItem {
+            id: x.id.to_string(),
+            name: x.display_name(),
+            status: ItemStatus::Pending,
+        })
+        .collect();
+    let ids: HashSet<String> = items.iter().map(|x| x.id.clone()).collect();
+
+    let job = jobs.spawn_exclusive(JobKind::UpdateSubs, items, move |handle| async move {
+        let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_UPDATES));
+        let mut tasks = JoinSet::new();
+        for (index, sub) in subs.into_iter().enumerate() {
+            let handle = handle.clone();
+            let semaphore = semaphore.clone();
+            tasks.spawn(async move {
+                let Ok(_permit) = semaphore.acquire_owned().await else {
+                    return false;
+                };
+                handle.set_item(index, ItemStatus::Downloading);
+                let status = match timeout(SUB_UPDATE_TIMEOUT, update_sub(&sub)).await {
+                    Ok(Ok(())) => {
+                        log::info!("Subscription {} updated.", sub.path);
+                        ItemStatus::Ok
+                    }
+                    Ok(Err(e)) => ItemStatus::Failed {
+                        error_kind: e.error_kind,
+                        message: e.message,
+                    },
+                    Err(_) => ItemStatus::Failed {
+                        error_kind: ClashErrorKind::NetworkError,
+                        message: format!(
+                            "Update timed out after {} seconds",
+                            SUB_UPDATE_TIMEOUT.as_secs()
+                        ),
+                    },
+                };
+                if let ItemStatus::Failed { message, .. } = &status {
+                    log::error!("Error updating subscription {}: {}", sub.path, message);
+                }
+                let ok = status == ItemStatus::Ok;
+                if ok {
+                    prefetch_providers(&sub).await;
+                }
+                handle.set_item(index, status);
+                ok
+            });
+        }
+
+        let (mut updated, mut failed) = (0, 0);
+        while let Some(result) = tasks.join_next().await {
+            match result {
+                Ok(true) => updated += 1,
+                _ => failed += 1,
+            }
+        }
+        // 全部失败时整个任务视为失败
+        if updated == 0 && failed > 0 {
+            return Err(ClashError {
+                message: format!("All {} subscriptions failed to update", failed),
+                error_kind: ClashErrorKind::NetworkError,
+            });
+        }
+        Ok(serde_json::json!({ "updated": updated, "failed": failed }))
+    });
+
+    // 返回的是已在运行的任务时，只有订阅相同才视为同一请求
+    if job.items.iter().map(|x| x.id.clone()).collect::<HashSet<_>>() != ids {
+        return Err(ClashError {
+            message: format!("Another subscription update is running: {}", job.id),
+            error_kind: ClashErrorKind::ConflictError,
+        });
+    }
+    Ok(job)
+}
+
+/// 订阅元数据的部分更新，未提供的字段保持不变
+#[derive(Deserialize, Default)]
+pub struct SubscriptionPatch {
+    pub name: Option<String>,
+    pub enabled: Option<bool>,
+    pub tags: Option<Vec<String>>,
+    pub notes: Option<String>,
+    pub request: Option<RequestOptions>,
+}
+
+impl SubscriptionPatch {
+    pub fn apply(&self, sub: &mut Subscription) {
+        if let Some(name) = &self.name {
+            sub.name = name.trim().to_string();
+        }
+        if let Some(enabled) = self.enabled {
+            sub.enabled = enabled;
+        }
+        if let Some(tags) = &self.tags {
+            sub.tags.clear();
+            for tag in tags.iter().map(|x| x.trim().to_string()) {
+                if !sub.tags.contains(&tag) {
+                    sub.tags.push(tag);
+                }
+            }
+        }
+        if let Some(notes) = &self.notes {
+            sub.notes = notes.clone();
+        }
+        if let Some(request) = &self.request {
+            sub.request = request.clone();
+        }
+    }
+}
+
+fn sub_not_found(id: &Uuid) -> ClashError {
+    ClashError {
+        message: format!("Subscription not found: {}", id),
+        error_kind: ClashErrorKind::NotFoundError,
+    }
+}
+
+pub fn find_sub_mut<'a>(settings: &'a mut Settings, id: &Uuid) -> Result<&'a mut Subscription, ClashError> {
+    settings
+        .subscriptions
+        .iter_mut()
+        .find(|x| x.id == *id)
+        .ok_or_else(|| sub_not_found(id))
+}
+
+/// 按给定的 id 顺序重排订阅，必须包含全部订阅且不能重复
+pub fn reorder_subs(settings: &mut Settings, ids: &[Uuid]) -> Result<(), ValidationError> {
+    let mut ordered = Vec::with_capacity(ids.len());
+    for id in ids {
+        let Some(index) = settings.subscriptions.iter().position(|x| x.id == *id) else {
+            return Err(ValidationError::new(
+                "ids",
+                ValidationErrorKind::UnknownSubscription,
+                format!("Unknown or duplicate subscription id {}", id),
+            ));
+        };
+        ordered.push(settings.subscriptions.remove(index));
+    }
+    if !settings.subscriptions.is_empty() {
+        return Err(ValidationError::new(
+            "ids",
+            ValidationErrorKind::UnknownSubscription,
+            "All subscriptions must be listed",
+        ));
+    }
+    settings.subscriptions = ordered;
+    Ok(())
+}
+
+/// 选择当前订阅，停用的订阅不能被选择
+pub fn select_sub(settings: &SettingsInstance, id: &Uuid) -> Result<Subscription, actix_web::Error> {
+    settings.modify(|x| {
+        let sub = find_sub_mut(x, id)?.clone();
+        x.current_sub = Some(sub.id);
+        Ok(sub)
+    })
+}
+
+/// 删除订阅，位于订阅目录中的文件一并删除
+pub fn delete_sub(settings: &SettingsInstance, id: &Uuid) -> Result<(), actix_web::Error> {
+    let removed = settings.modify(|x| {
+        let index = x
+            .subscriptions
+            .iter()
+            .position(|sub| sub.id == *id)
+            .ok_or_else(|| sub_not_found(id))?;
+        let removed = x.subscriptions.remove(index);
+        if x.current_sub == Some(removed.id) {
+            x.current_sub = None;
+        }
+        Ok::<_, actix_web::Error>(removed)
+    })?;
+
+    let in_sub_dir = get_sub_dir().is_ok_and(|dir| PathBuf::from(&removed.path).starts_with(dir));
+    let still_used = settings.get().subscriptions.iter().any(|x| x.path == removed.path);
+    if in_sub_dir && !still_used {
+        if let Err(e) = fs::remove_file(&removed.path) {
+            log::error!("delete file error: {}", e);
+        }
+    }
+    Ok(())
+}
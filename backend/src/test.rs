@@ -1,6 +1,26 @@
 #[cfg(test)]
 mod tests {
 
+    use crate::api::proxy;
+    use crate::auth::{AuthError, Authenticator};
+    use crate::backup::{self, Bundle, BundleDirs, ConflictPolicy, ExportOptions, ImportOptions};
+    use crate::clash::preview;
+    use crate::dashboards::{self, DashboardDirs, DashboardSource};
+    use crate::clash::controller::LogLevel;
+    use crate::clash::runtime::ApplyAction;
+    use crate::clash::controller::{ClashError, ClashErrorKind, Controller};
+    use crate::jobs::{ItemStatus, JobKind, JobRegistry, JobState};
+    use crate::matcher::{MatchTarget, Matcher};
+    use crate::games;
+    use crate::geodata::{self, GeoDatabase};
+    use crate::providers;
+    use crate::rules::{self, CustomRule, PresetAction, RulePreset, RuleType};
+    use crate::subscriptions::{self, SubscriptionPatch};
+    use crate::tls::{self, ServerCertificate};
+    use crate::settings::{
+        BasicAuth, FetchVia, GeodataSettings, RequestOptions, Settings, SettingsChange, SettingsError, SettingsInstance,
+        Subscription, ValidationErrorKind, SETTINGS_VERSION,
+    };
     use crate::utils;
     use regex::Regex;
     use serde_yaml::{Mapping, Value};
@@ -296,4 +316,1347 @@ mod tests {
         );
         fs::write("/tmp/tomoon.debug.log", log).unwrap();
     }
+
+    #[test]
+    fn preview_diff() {
+        let original: Value = serde_yaml::from_str(
+            "
+        mode: rule
+        secret: abc
+        rules:
+            - DOMAIN-SUFFIX,google.com,PROXY
+            - MATCH,DIRECT
+        ",
+        )
+        .unwrap();
+        let mut running: Value = serde_yaml::from_str(
+            "
+        mode: rule
+        secret: abc
+        tun:
+            enable: true
+        rules:
+            - DOMAIN,test.steampowered.com,DIRECT
+            - DOMAIN-SUFFIX,google.com,PROXY
+            - MATCH,DIRECT
+        ",
+        )
+        .unwrap();
+        preview::redact_secret(&mut running);
+
+        let diff = preview::ConfigDiff::between(&original, &running);
+        assert!(diff.added.contains_key("tun"));
+        assert!(diff.removed.is_empty());
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].key, "secret");
+        assert_eq!(
+            diff.rules.added,
+            vec![preview::RuleLine {
+                index: 0,
+                rule: "DOMAIN,test.steampowered.com,DIRECT".to_string(),
+            }]
+        );
+        assert!(diff.rules.removed.is_empty());
+    }
+
+    #[test]
+    fn rule_presets_injected() {
+        let dir = std::env::temp_dir().join(format!("tomoon-presets-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sub.yaml");
+        fs::write(
+            &path,
+            "proxy-groups:\n  - name: Games\n    type: select\n    proxies: [DIRECT]\nrules:\n  - MATCH,DIRECT\n",
+        )
+        .unwrap();
+
+        let mut settings = Settings {
+            skip_proxy: true,
+            ..Default::default()
+        };
+        settings.rule_presets.insert(RulePreset::Discord, PresetAction::Direct);
+        settings.rule_presets.insert(
+            RulePreset::SteamStore,
+            PresetAction::Proxy { group: "Games".to_string() },
+        );
+        // 配置中没有的策略组会被跳过
+        settings.rule_presets.insert(
+            RulePreset::Epic,
+            PresetAction::Proxy { group: "Missing".to_string() },
+        );
+        let config = Controller::default().render_config(&path, &settings).unwrap();
+        let rules: Vec<&str> = config["rules"]
+            .as_sequence()
+            .unwrap()
+            .iter()
+            .map(|x| x.as_str().unwrap())
+            .collect();
+
+        assert_eq!(rules[0], "DOMAIN-SUFFIX,steamserver.net,DIRECT");
+        assert_eq!(rules[2], "DOMAIN,test.steampowered.com,DIRECT");
+        assert_eq!(rules[3], "DOMAIN-SUFFIX,steampowered.com,Games");
+        let discord = rules.iter().position(|x| *x == "DOMAIN-SUFFIX,discord.com,DIRECT");
+        assert!(discord.unwrap() > 3);
+        assert!(!rules.iter().any(|x| x.contains("epicgames")));
+        assert_eq!(*rules.last().unwrap(), "MATCH,DIRECT");
+        assert_eq!(
+            rules.len(),
+            3 + RulePreset::SteamStore.matchers().len() + RulePreset::Discord.matchers().len() + 1
+        );
+
+        let invalid = serde_json::json!({ "rule_presets": { "steam_cdn": { "action": "proxy", "group": "" } } });
+        assert!(settings.patched(invalid.as_object().unwrap()).is_err());
+        assert_eq!(bundle_import(&settings).rule_presets, settings.rule_presets);
+
+        // 没有 rules 的配置也会注入规则，但不添加兜底规则
+        fs::write(&path, "proxy-groups:\n  - name: Games\n    type: select\n    proxies: [DIRECT]\n").unwrap();
+        let config = Controller::default().render_config(&path, &settings).unwrap();
+        let rules = config["rules"].as_sequence().unwrap();
+        assert_eq!(rules[0].as_str(), Some("DOMAIN-SUFFIX,steamserver.net,DIRECT"));
+        assert!(!rules.iter().any(|x| x.as_str().is_some_and(|x| x.starts_with("MATCH,"))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn custom_rules_validation_and_order() {
+        let rule = |rule_type: RuleType, value: &str, policy: &str| CustomRule {
+            id: uuid::Uuid::new_v4(),
+            rule_type,
+            value: value.to_string(),
+            policy: policy.to_string(),
+            no_resolve: false,
+            enabled: true,
+        };
+
+        assert!(rule(RuleType::IpCidr, "10.0.0.0/8", "DIRECT").validate("r").is_empty());
+        assert!(!rule(RuleType::IpCidr, "10.0.0.0/33", "DIRECT").validate("r").is_empty());
+        assert!(!rule(RuleType::IpCidr, "fd00::/8", "DIRECT").validate("r").is_empty());
+        assert!(rule(RuleType::IpCidr6, "fd00::/8", "DIRECT").validate("r").is_empty());
+        assert!(rule(RuleType::DstPort, "80/8000-9000", "DIRECT").validate("r").is_empty());
+        assert!(!rule(RuleType::DstPort, "9000-8000", "DIRECT").validate("r").is_empty());
+        assert!(!rule(RuleType::DomainRegex, "(steam", "DIRECT").validate("r").is_empty());
+        assert!(!rule(RuleType::Domain, "a.com", "A,B").validate("r").is_empty());
+        let mut no_resolve = rule(RuleType::Domain, "a.com", "DIRECT");
+        no_resolve.no_resolve = true;
+        let errors = no_resolve.validate("custom_rules[0]");
+        assert_eq!(errors[0].field, "custom_rules[0].no_resolve");
+        let mut cidr = rule(RuleType::IpCidr, "10.0.0.0/8", "DIRECT");
+        cidr.no_resolve = true;
+        assert_eq!(cidr.to_rule(), "IP-CIDR,10.0.0.0/8,DIRECT,no-resolve");
+
+        let dir = std::env::temp_dir().join(format!("tomoon-custom-rules-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sub.yaml");
+        fs::write(
+            &path,
+            "proxy-groups:\n  - name: Games\n    type: select\n    proxies: [DIRECT]\nrules:\n  - MATCH,DIRECT\n",
+        )
+        .unwrap();
+
+        let mut settings = Settings {
+            skip_proxy: false,
+            ..Default::default()
+        };
+        settings.rule_presets.insert(RulePreset::Discord, PresetAction::Direct);
+        let first = rule(RuleType::DomainSuffix, "example.com", "Games");
+        let second = rule(RuleType::ProcessName, "game.exe", "REJECT");
+        let mut disabled = rule(RuleType::Domain, "off.example.com", "DIRECT");
+        disabled.enabled = false;
+        settings.custom_rules = vec![first.clone(), second.clone(), disabled.clone()];
+        let rule_errors = |x: &Settings| -> Vec<ValidationErrorKind> {
+            x.validate()
+                .into_iter()
+                .filter(|e| e.field.starts_with("custom_rules"))
+                .map(|e| e.kind)
+                .collect()
+        };
+        assert!(rule_errors(&settings).is_empty());
+
+        rules::reorder_rules(&mut settings, &[second.id, first.id, disabled.id]).unwrap();
+        assert!(rules::reorder_rules(&mut settings, &[second.id]).is_err());
+        assert!(rules::reorder_rules(&mut settings, &[second.id, second.id, first.id]).is_err());
+
+        let config = Controller::default().render_config(&path, &settings).unwrap();
+        let rendered: Vec<&str> = config["rules"]
+            .as_sequence()
+            .unwrap()
+            .iter()
+            .map(|x| x.as_str().unwrap())
+            .collect();
+        // 自定义规则位于预设之前，禁用的规则不注入
+        assert_eq!(rendered[0], "DOMAIN,test.steampowered.com,DIRECT");
+        assert_eq!(rendered[1], "PROCESS-NAME,game.exe,REJECT");
+        assert_eq!(rendered[2], "DOMAIN-SUFFIX,example.com,Games");
+        assert!(rendered[3].ends_with(",DIRECT") && rendered[3].contains("discord"));
+        assert!(!rendered.iter().any(|x| x.contains("off.example.com")));
+        assert_eq!(bundle_import(&settings).custom_rules, settings.custom_rules);
+
+        settings.custom_rules.push(settings.custom_rules[0].clone());
+        assert_eq!(rule_errors(&settings), vec![ValidationErrorKind::DuplicateId]);
+        // 自定义规则只能通过 /rules/custom 修改
+        let patch = serde_json::json!({ "custom_rules": [] });
+        assert!(Settings::default().patched(patch.as_object().unwrap()).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn steam_game_routes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("tomoon-games-{}", std::process::id()));
+        let steam = dir.join("Steam");
+        let sdcard = dir.join("sdcard");
+        fs::create_dir_all(steam.join("steamapps")).unwrap();
+        fs::create_dir_all(sdcard.join("steamapps")).unwrap();
+        fs::write(
+            steam.join("steamapps/libraryfolders.vdf"),
+            format!(
+                "\"libraryfolders\"\n{{\n\t\"0\"\n\t{{\n\t\t\"path\"\t\t\"{}\"\n\t}}\n\t\"1\"\n\t{{\n\t\t\"path\"\t\t\"{}\"\n\t}}\n}}\n",
+                steam.display(),
+                sdcard.display()
+            ),
+        )
+        .unwrap();
+        let manifest = |library: &PathBuf, app_id: u32, name: &str, install_dir: &str| {
+            fs::write(
+                library.join(format!("steamapps/appmanifest_{}.acf", app_id)),
+                format!(
+                    "\"AppState\"\n{{\n\t\"appid\"\t\t\"{}\"\n\t\"name\"\t\t\"{}\"\n\t\"installdir\"\t\t\"{}\"\n}}\n",
+                    app_id, name, install_dir
+                ),
+            )
+            .unwrap();
+        };
+        manifest(&steam, 1091500, "Cyberpunk 2077", "Cyberpunk 2077");
+        manifest(&sdcard, 570, "Dota 2", "dota 2 beta");
+        manifest(&steam, 1493710, "Proton Experimental", "Proton - Experimental");
+
+        let windows = steam.join("steamapps/common/Cyberpunk 2077");
+        fs::create_dir_all(windows.join("bin/x64")).unwrap();
+        fs::create_dir_all(windows.join("_CommonRedist")).unwrap();
+        fs::write(windows.join("bin/x64/Cyberpunk2077.exe"), "MZ").unwrap();
+        fs::write(windows.join("_CommonRedist/vcredist.exe"), "MZ").unwrap();
+        let native = sdcard.join("steamapps/common/dota 2 beta/game/bin/linuxsteamrt64");
+        fs::create_dir_all(&native).unwrap();
+        fs::write(native.join("dota2"), b"\x7fELF\x02").unwrap();
+        fs::write(native.join("dota.sh"), "#!/bin/sh").unwrap();
+        for file in ["dota2", "dota.sh"] {
+            fs::set_permissions(native.join(file), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let list = games::list_games(&steam);
+        let names: Vec<&str> = list.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, vec!["Cyberpunk 2077", "Dota 2"]);
+        assert_eq!(list[1].install_dir, sdcard.join("steamapps/common/dota 2 beta"));
+
+        let profile: Value =
+            serde_yaml::from_str("proxy-groups:\n  - name: Games\n    type: select\n    proxies: [DIRECT]\n").unwrap();
+        let mut routes = std::collections::BTreeMap::new();
+        routes.insert(1091500, PresetAction::Proxy { group: "Games".to_string() });
+        routes.insert(570, PresetAction::Direct);
+        // 未安装的游戏与不存在的策略组会被跳过
+        routes.insert(730, PresetAction::Direct);
+        let rules = games::game_rules(&routes, &list, &profile);
+        assert_eq!(
+            rules,
+            vec![
+                format!("PROCESS-PATH,{},DIRECT", native.join("dota2").display()),
+                "PROCESS-NAME,Cyberpunk2077.exe,Games".to_string(),
+            ]
+        );
+        routes.insert(570, PresetAction::Proxy { group: "Missing".to_string() });
+        assert_eq!(games::game_rules(&routes, &list, &profile).len(), 1);
+
+        let patch = serde_json::json!({ "game_routes": { "570": { "action": "direct" } } });
+        let updated = Settings::default().patched(patch.as_object().unwrap()).unwrap();
+        assert_eq!(updated.game_routes.get(&570), Some(&PresetAction::Direct));
+        assert_eq!(bundle_import(&updated).game_routes, updated.game_routes);
+        let invalid = serde_json::json!({ "game_routes": { "570": { "action": "proxy", "group": "" } } });
+        assert!(Settings::default().patched(invalid.as_object().unwrap()).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pairing_lockout() {
+        let auth = Authenticator::default();
+        let ip = Some("192.168.1.20".parse().unwrap());
+        let (code, _) = auth.pairing_code();
+        let wrong = if code == "000000" { "111111" } else { "000000" };
+
+        for _ in 0..5 {
+            assert!(matches!(auth.login(ip, wrong), Err(AuthError::InvalidCode)));
+        }
+        assert!(matches!(auth.login(ip, &code), Err(AuthError::Locked { .. })));
+
+        // 其他地址不受影响，配对码只能使用一次
+        let other = Some("192.168.1.21".parse().unwrap());
+        let token = auth.login(other, &code).unwrap();
+        assert!(auth.verify(&token));
+        assert!(auth.login(other, &code).is_err());
+        auth.logout(&token);
+        assert!(!auth.verify(&token));
+    }
+
+    #[test]
+    fn pairing_global_failure_limit() {
+        let auth = Authenticator::default();
+        let (code, _) = auth.pairing_code();
+        let wrong = if code == "000000" { "111111" } else { "000000" };
+
+        // 每个地址都未达到锁定次数，但累计失败后配对码会更换
+        for i in 0..20 {
+            let ip = Some(format!("10.0.{}.1", i / 4).parse().unwrap());
+            assert!(matches!(auth.login(ip, wrong), Err(AuthError::InvalidCode)));
+        }
+        let ip = Some("10.0.100.1".parse().unwrap());
+        let (rotated, _) = auth.pairing_code();
+        // 新配对码有极小概率与旧的相同
+        if rotated != code {
+            assert!(matches!(auth.login(ip, &code), Err(AuthError::InvalidCode)));
+        }
+        assert!(auth.login(ip, &rotated).is_ok());
+    }
+
+    #[test]
+    fn pairing_info_links() {
+        use crate::api::auth::build_pairing_info;
+
+        let auth = Authenticator::default();
+        let addresses: Vec<std::net::IpAddr> =
+            vec!["192.168.1.2".parse().unwrap(), "fd00::2".parse().unwrap()];
+        let info = build_pairing_info(&auth, &addresses, 55556, Some("AB:CD".to_string())).unwrap();
+        let info = serde_json::to_value(info).unwrap();
+
+        let token = info["token"].as_str().unwrap().to_string();
+        assert_eq!(
+            info["urls"],
+            serde_json::json!([
+                format!("https://192.168.1.2:55556/auth/pair?token={}", token),
+                format!("https://[fd00::2]:55556/auth/pair?token={}", token),
+            ])
+        );
+        assert_eq!(info["url"], info["urls"][0]);
+        assert_eq!(info["code"].as_str().unwrap(), auth.pairing_code().0);
+        assert_eq!(info["fingerprint"], "AB:CD");
+        assert!(info["qr_svg"].as_str().unwrap().contains("<svg"));
+
+        // 链接中的令牌只能兑换一次
+        let ip = Some("192.168.1.3".parse().unwrap());
+        let session = auth.redeem_pairing_token(ip, &token).unwrap();
+        assert!(auth.verify(&session));
+        assert!(auth.redeem_pairing_token(ip, &token).is_err());
+
+        // 未启用 HTTPS 时不返回指纹
+        let info = build_pairing_info(&auth, &addresses[..1], 55556, None).unwrap();
+        let info = serde_json::to_value(info).unwrap();
+        assert!(info["url"].as_str().unwrap().starts_with("http://192.168.1.2:55556/"));
+        assert!(info.get("fingerprint").is_none());
+        assert_ne!(info["token"].as_str().unwrap(), token);
+    }
+
+    #[test]
+    fn local_address_order() {
+        let parse = |x: &[&str]| -> Vec<std::net::IpAddr> { x.iter().map(|x| x.parse().unwrap()).collect() };
+        // 多个网卡上的相同地址只保留一个，回环与链路本地地址被过滤
+        let addresses = parse(&[
+            "fd00::2",
+            "192.168.1.3",
+            "127.0.0.1",
+            "192.168.1.2",
+            "fe80::1",
+            "169.254.0.1",
+            "192.168.1.3",
+            "fd00::2",
+        ]);
+        assert_eq!(
+            utils::reachable_addresses(addresses.clone(), None),
+            parse(&["192.168.1.2", "192.168.1.3", "fd00::2"])
+        );
+        assert_eq!(
+            utils::reachable_addresses(addresses, Some("192.168.1.3".parse().unwrap())),
+            parse(&["192.168.1.3", "192.168.1.2", "fd00::2"])
+        );
+    }
+
+    #[test]
+    fn settings_patch_validation() {
+        let settings = Settings::default();
+        let patch = |x: serde_json::Value| settings.patched(x.as_object().unwrap());
+
+        let updated = patch(serde_json::json!({ "skip_proxy": false })).unwrap();
+        assert!(!updated.skip_proxy);
+
+        let kinds = |x: serde_json::Value| -> Vec<ValidationErrorKind> {
+            patch(x).err().unwrap().into_iter().map(|e| e.kind).collect()
+        };
+        assert_eq!(kinds(serde_json::json!({ "foo": 1 })), vec![ValidationErrorKind::UnknownField]);
+        assert_eq!(kinds(serde_json::json!({ "secret": "x" })), vec![ValidationErrorKind::ReadOnly]);
+        assert_eq!(
+            kinds(serde_json::json!({ "external_port": 70000 })),
+            vec![ValidationErrorKind::InvalidType]
+        );
+        assert_eq!(
+            kinds(serde_json::json!({ "external_port": 80 })),
+            vec![ValidationErrorKind::InvalidPort]
+        );
+        assert_eq!(
+            kinds(serde_json::json!({ "external_port": 55555 })),
+            vec![ValidationErrorKind::PortConflict]
+        );
+
+        // 嵌套字段的错误不能被过滤掉
+        let errors = patch(serde_json::json!({ "geodata": { "mmdb_url": "ftp://x" } })).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "geodata.mmdb_url");
+    }
+
+    #[test]
+    fn settings_migration_and_recovery() {
+        let dir = std::env::temp_dir().join(format!("tomoon-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tomoon.json");
+
+        fs::write(&path, r#"{"skip_proxy": false, "dashboard": "/a/web/metacubexd/"}"#).unwrap();
+        let (settings, dirty) = Settings::load(&path).unwrap();
+        assert!(dirty);
+        assert_eq!(settings.version, SETTINGS_VERSION);
+        assert!(!settings.skip_proxy);
+        assert_eq!(settings.dashboard, "metacubexd");
+        assert!(dir.join("tomoon.json.v0.bak").exists());
+
+        fs::write(&path, "{\"skip_proxy\": fal").unwrap();
+        let (settings, dirty) = Settings::load(&path).unwrap();
+        assert!(dirty);
+        assert!(settings.skip_proxy);
+        let corrupt = fs::read_dir(&dir)
+            .unwrap()
+            .filter_map(|x| x.ok())
+            .any(|x| x.file_name().to_string_lossy().ends_with(".corrupt"));
+        assert!(corrupt);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn settings_debounced_save() {
+        let dir = std::env::temp_dir().join(format!("tomoon-save-{}", std::process::id()));
+        let path = dir.join("tomoon.json");
+        let instance = SettingsInstance::open(&path).unwrap();
+
+        instance.update(|mut x| x.skip_proxy = false).unwrap();
+        instance.update(|mut x| x.override_dns = false).unwrap();
+        // 修改后不会立即写入
+        assert!(Settings::load(&path).unwrap().0.skip_proxy);
+
+        std::thread::sleep(std::time::Duration::from_millis(1500));
+        let (saved, _) = Settings::load(&path).unwrap();
+        assert!(!saved.skip_proxy);
+        assert!(!saved.override_dns);
+        assert!(dir.join("tomoon.json.bak").exists());
+        assert!(!dir.join("tomoon.json.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn settings_change_events() {
+        let path = std::env::temp_dir()
+            .join(format!("tomoon-events-{}", std::process::id()))
+            .join("tomoon.json");
+        let instance = SettingsInstance::new(&path);
+        let mut changes = instance.subscribe();
+
+        instance.update(|mut x| x.skip_proxy = false).unwrap();
+        instance
+            .update(|mut x| {
+                x.log_level = LogLevel::Debug;
+                x.allow_remote_access = true;
+            })
+            .unwrap();
+
+        let received: Vec<SettingsChange> =
+            std::iter::from_fn(|| changes.try_recv().ok()).collect();
+        assert_eq!(
+            received,
+            vec![
+                SettingsChange::SkipProxy(false),
+                SettingsChange::AllowRemoteAccess(true),
+                SettingsChange::LogLevel(LogLevel::Debug),
+            ]
+        );
+        let action = received.iter().map(ApplyAction::for_change).max();
+        assert_eq!(action, Some(ApplyAction::Restart));
+        assert_eq!(
+            ApplyAction::for_change(&SettingsChange::LogLevel(LogLevel::Info)),
+            ApplyAction::HotPatch
+        );
+
+        instance.save().unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn rotate_secret() {
+        use crate::clash::runtime::Runtime;
+        use actix_web::{test, web, App};
+
+        let dir = std::env::temp_dir().join(format!("tomoon-secret-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let profile = dir.join("sub.yaml");
+        fs::write(&profile, "secret: from-sub\nrules:\n  - MATCH,DIRECT\n").unwrap();
+
+        // 无法读取设置时使用的默认实例同样带有 secret
+        let runtime = Runtime {
+            settings: SettingsInstance::new(dir.join("tomoon.json")),
+            controller: Default::default(),
+            auth: Authenticator::default(),
+            jobs: JobRegistry::default(),
+        };
+        let old = runtime.settings.get().secret;
+        assert!(!old.is_empty());
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(runtime.clone()))
+                .route("/rotate_secret", web::post().to(crate::api::controller::rotate_secret)),
+        )
+        .await;
+        let request = test::TestRequest::post().uri("/rotate_secret").to_request();
+        let response: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+
+        let secret = runtime.settings.get().secret;
+        assert_ne!(secret, old);
+        assert_eq!(response["data"], secret.as_str());
+        let config = Controller::default()
+            .render_config(&profile, &runtime.settings.get())
+            .unwrap();
+        assert_eq!(config["secret"].as_str().unwrap(), secret);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // 导出后导入到默认设置中，返回导入后的设置
+    fn bundle_import(settings: &Settings) -> Settings {
+        let root = std::env::temp_dir().join(format!("tomoon-bundle-empty-{}", std::process::id()));
+        let dirs = BundleDirs {
+            subs: root.join("subs"),
+            dashboards: root.join("dashboards"),
+        };
+        let data = backup::export(settings, &dirs, &ExportOptions::default()).unwrap();
+        let bundle = Bundle::read(&data).unwrap();
+        bundle.plan(&Settings::default(), &dirs, &ImportOptions::default()).settings
+    }
+
+    #[test]
+    fn settings_bundle_round_trip() {
+        let root = std::env::temp_dir().join(format!("tomoon-bundle-{}", std::process::id()));
+        let dirs = |name: &str| BundleDirs {
+            subs: root.join(name).join("subs"),
+            dashboards: root.join(name).join("dashboards"),
+        };
+        let (source, target) = (dirs("source"), dirs("target"));
+        fs::create_dir_all(&source.subs).unwrap();
+        fs::create_dir_all(source.dashboards.join("custom/assets")).unwrap();
+        fs::create_dir_all(&target.subs).unwrap();
+
+        let profile = "rules:\n  - MATCH,DIRECT\n";
+        fs::write(source.subs.join("a.yaml"), profile).unwrap();
+        fs::write(source.dashboards.join("custom/index.html"), "<html>").unwrap();
+        fs::write(source.dashboards.join("custom/assets/app.js"), "1").unwrap();
+        // 目标设备上已有同名但内容不同的订阅
+        fs::write(target.subs.join("a.yaml"), "rules: []\n").unwrap();
+
+        let mut settings = Settings::default();
+        let path = source.subs.join("a.yaml").to_string_lossy().to_string();
+        settings.subscriptions.push(Subscription::new(path, "https://a".to_string()));
+        settings.subscriptions[0]
+            .request
+            .headers
+            .insert("Authorization".to_string(), "Bearer token".to_string());
+        settings.current_sub = Some(settings.subscriptions[0].id);
+        settings.skip_proxy = false;
+        settings.dashboard = "custom".to_string();
+
+        let data = backup::export(&settings, &source, &ExportOptions { strip_secret: true }).unwrap();
+        let bundle = Bundle::read(&data).unwrap();
+
+        let local = Settings {
+            secret: "local".to_string(),
+            ..Default::default()
+        };
+        let plan = bundle.plan(&local, &target, &ImportOptions::default());
+        let renamed = target.subs.join("a_1.yaml").to_string_lossy().to_string();
+        assert_eq!(plan.report.renamed.len(), 1);
+        assert_eq!(plan.report.renamed[0].to, renamed);
+        assert_eq!(plan.settings.current_sub, settings.current_sub);
+        assert_eq!(plan.settings.subscriptions.len(), 1);
+        assert_eq!(plan.settings.subscriptions[0].path, renamed);
+        assert_eq!(plan.settings.subscriptions[0].id, settings.subscriptions[0].id);
+        assert!(!plan.settings.skip_proxy);
+        // 导出时去掉了 secret 与请求头，保留本机的 secret
+        assert_eq!(plan.settings.secret, "local");
+        assert!(plan.settings.subscriptions[0].request.headers.is_empty());
+        // 备份包自带所选的面板
+        assert_eq!(plan.settings.dashboard, "custom");
+        assert!(plan
+            .validate()
+            .iter()
+            .all(|x| x.kind != ValidationErrorKind::UnknownDashboard));
+        // 计划阶段不写入任何文件
+        assert!(!target.dashboards.exists());
+
+        plan.apply().unwrap();
+        assert_eq!(fs::read_to_string(&renamed).unwrap(), profile);
+        assert_eq!(fs::read_to_string(target.subs.join("a.yaml")).unwrap(), "rules: []\n");
+        assert_eq!(fs::read_to_string(target.dashboards.join("custom/assets/app.js")).unwrap(), "1");
+
+        let skip = ImportOptions {
+            conflict: ConflictPolicy::Skip,
+            ..Default::default()
+        };
+        let plan = bundle.plan(&local, &target, &skip);
+        assert_eq!(plan.report.skipped, vec!["subs/a.yaml", "dashboards/custom"]);
+
+        // 面板重命名后所选面板指向新目录
+        let plan = bundle.plan(&local, &target, &ImportOptions::default());
+        assert_eq!(plan.settings.dashboard, "custom_1");
+        assert!(plan
+            .validate()
+            .iter()
+            .all(|x| x.kind != ValidationErrorKind::UnknownDashboard));
+
+        // 覆盖时替换整个面板目录，不留下备份
+        fs::write(target.dashboards.join("custom/old.js"), "0").unwrap();
+        let overwrite = ImportOptions {
+            conflict: ConflictPolicy::Overwrite,
+            ..Default::default()
+        };
+        let plan = bundle.plan(&local, &target, &overwrite);
+        plan.apply().unwrap();
+        assert!(!target.dashboards.join("custom/old.js").exists());
+        assert!(!target.dashboards.join("custom.import-backup").exists());
+
+        // 修订号已变化时不写入文件
+        let instance = SettingsInstance::new(root.join("tomoon.json"));
+        let (_, revision) = instance.get_with_revision();
+        instance.replace(None, local.clone()).unwrap();
+        let mut applied = false;
+        let result = instance.replace_with(Some(revision), plan.settings.clone(), || {
+            applied = true;
+            Ok::<_, SettingsError>(())
+        });
+        assert!(matches!(result, Err(SettingsError::Conflict { .. })));
+        assert!(!applied);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn subscription_metadata() {
+        // 旧版本的订阅在迁移时获得 id
+        let dir = std::env::temp_dir().join(format!("tomoon-subs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tomoon.json");
+        fs::write(
+            &path,
+            r#"{"version": 1, "current_sub": "/b.yaml", "subscriptions": [{"path": "/a.yaml", "url": "a"}, {"path": "/b.yaml", "url": "b"}]}"#,
+        )
+        .unwrap();
+        let (settings, dirty) = Settings::load(&path).unwrap();
+        assert!(dirty);
+        assert!(settings.subscriptions.iter().all(|x| x.enabled));
+        assert_ne!(settings.subscriptions[0].id, settings.subscriptions[1].id);
+        assert_eq!(settings.subscriptions[0].display_name(), "a");
+        // current_sub 由路径迁移为 id
+        assert_eq!(settings.current_sub_path(), Some("/b.yaml"));
+
+        let instance = SettingsInstance::new(&path);
+        instance.replace(None, settings.clone()).unwrap();
+        let (a, b) = (settings.subscriptions[0].id, settings.subscriptions[1].id);
+
+        let patch = SubscriptionPatch {
+            name: Some("  Home ".to_string()),
+            tags: Some(vec!["x".to_string(), "x ".to_string(), "y".to_string()]),
+            ..Default::default()
+        };
+        instance
+            .modify(|x| {
+                patch.apply(subscriptions::find_sub_mut(x, &a)?);
+                Ok::<_, actix_web::Error>(())
+            })
+            .unwrap();
+        let sub = &instance.get().subscriptions[0];
+        assert_eq!(sub.display_name(), "Home");
+        assert_eq!(sub.tags, vec!["x", "y"]);
+
+        instance
+            .modify(|x| subscriptions::reorder_subs(x, &[b, a]).map_err(|e| SettingsError::Invalid(vec![e])))
+            .unwrap();
+        assert_eq!(instance.get().subscriptions[0].id, b);
+        let result = instance.modify(|x| {
+            subscriptions::reorder_subs(x, &[b, b]).map_err(|e| SettingsError::Invalid(vec![e]))
+        });
+        assert!(matches!(result, Err(SettingsError::Invalid(_))));
+        assert_eq!(instance.get().subscriptions.len(), 2);
+
+        // 当前订阅不能被停用
+        subscriptions::select_sub(&instance, &a).unwrap();
+        let result = instance.modify(|x| {
+            x.subscriptions[1].enabled = false;
+            Ok::<_, SettingsError>(())
+        });
+        match result {
+            Err(SettingsError::Invalid(errors)) => {
+                assert_eq!(errors[0].kind, ValidationErrorKind::DisabledSubscription)
+            }
+            _ => panic!("disabling the current subscription should fail"),
+        }
+        assert!(instance.get().subscriptions[1].enabled);
+
+        instance.save().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn subscription_request_options() {
+        let options = RequestOptions {
+            headers: [("X-Token".to_string(), "abc".to_string())].into(),
+            basic_auth: Some(BasicAuth {
+                username: "user".to_string(),
+                password: "pass".to_string(),
+            }),
+            cookie: Some("session=1".to_string()),
+            ..Default::default()
+        };
+        assert!(options.validate("request").is_empty());
+
+        let request = subscriptions::build_request("https://example.com/sub", &options, None)
+            .unwrap()
+            .build()
+            .unwrap();
+        let headers = request.headers();
+        assert_eq!(headers["x-token"], "abc");
+        assert_eq!(headers["cookie"], "session=1");
+        assert_eq!(headers["authorization"], "Basic dXNlcjpwYXNz");
+
+        // 旧设置中的订阅使用默认请求参数
+        let sub: Subscription = serde_json::from_str(r#"{"path": "/a.yaml", "url": "a"}"#).unwrap();
+        assert_eq!(sub.request, RequestOptions::default());
+
+        let invalid = RequestOptions {
+            headers: [("Bad Header".to_string(), "x".to_string())].into(),
+            timeout: 0,
+            ..Default::default()
+        };
+        let fields: Vec<String> = invalid
+            .validate("request")
+            .into_iter()
+            .map(|x| x.field)
+            .collect();
+        assert_eq!(fields, vec!["request.timeout", "request.headers"]);
+    }
+
+    #[tokio::test]
+    async fn subscription_fetch_via_proxy() {
+        use std::io::{Read, Write};
+
+        // 充当 HTTP 代理，记录收到的请求行
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0u8; 4096];
+            let n = stream.read(&mut buffer).unwrap();
+            let body = "rules: []\n";
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            String::from_utf8_lossy(&buffer[..n]).lines().next().unwrap().to_string()
+        });
+
+        let options = RequestOptions {
+            fetch_via: FetchVia::Proxy { url: proxy },
+            ..Default::default()
+        };
+        assert!(options.validate("request").is_empty());
+        let response = subscriptions::fetch_remote("http://sub.invalid/x", &options)
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "rules: []\n");
+        assert_eq!(server.join().unwrap(), "GET http://sub.invalid/x HTTP/1.1");
+
+        let invalid = RequestOptions {
+            fetch_via: FetchVia::Proxy { url: "ftp://127.0.0.1:21".to_string() },
+            ..Default::default()
+        };
+        assert_eq!(invalid.validate("request")[0].field, "request.fetch_via");
+    }
+
+    #[tokio::test]
+    async fn provider_prefetch_cache() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/steam.yaml", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0u8; 4096];
+            assert!(stream.read(&mut buffer).unwrap() > 0);
+            let body = "payload:\n  - '+.steampowered.com'\n";
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+
+        let dir = std::env::temp_dir().join(format!("tomoon-providers-{}", std::process::id()));
+        let cache = dir.join("cache");
+        fs::create_dir_all(&dir).unwrap();
+        let profile = format!(
+            "rule-providers:\n  steam:\n    type: http\n    behavior: domain\n    url: {}\n    path: ./rules/steam.yaml\n  broken:\n    type: http\n    behavior: domain\n    url: http://127.0.0.1:1/broken.yaml\n    path: ./rules/broken.yaml\n  local:\n    type: file\n    behavior: domain\n    path: ./rules/local.yaml\nrules:\n  - RULE-SET,steam,DIRECT\n",
+            url
+        );
+        let path = dir.join("sub.yaml");
+        fs::write(&path, &profile).unwrap();
+        let sub = Subscription::new(path.to_string_lossy().to_string(), String::new());
+
+        let found = providers::read_providers(&sub.path).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].interval, 24 * 60 * 60);
+
+        let report = providers::prefetch(&sub, &cache, false).await.unwrap();
+        server.join().unwrap();
+        assert_eq!(report.fetched, vec!["steam".to_string()]);
+        assert_eq!(report.failed[0].name, "broken");
+        assert!(!found[0].is_stale(&cache));
+
+        // 未过期的缓存不会重复下载
+        let report = providers::prefetch(&sub, &cache, true).await.unwrap();
+        assert_eq!(report.skipped, vec!["steam".to_string()]);
+
+        let mut config: serde_yaml::Value = serde_yaml::from_str(&profile).unwrap();
+        providers::rewrite_paths(config.as_mapping_mut().unwrap(), &cache);
+        let rules = &config["rule-providers"];
+        assert_eq!(
+            rules["steam"]["path"].as_str().unwrap(),
+            found[0].cache_path(&cache).to_str().unwrap()
+        );
+        assert_eq!(rules["broken"]["path"].as_str().unwrap(), "./rules/broken.yaml");
+        assert_eq!(rules["local"]["path"].as_str().unwrap(), "./rules/local.yaml");
+
+        providers::remove_unused(&[], &cache);
+        assert!(!found[0].cache_path(&cache).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn core_provider_list() {
+        use crate::clash::controller::parse_providers;
+        use crate::providers::ProviderKind;
+
+        let proxies = serde_json::json!({
+            "providers": {
+                "default": {
+                    "name": "default",
+                    "type": "Proxy",
+                    "vehicleType": "Compatible",
+                    "proxies": [{ "name": "DIRECT" }]
+                },
+                "airport": {
+                    "name": "airport",
+                    "type": "Proxy",
+                    "vehicleType": "HTTP",
+                    "updatedAt": "2024-05-01T08:00:00Z",
+                    "proxies": [{ "name": "hk-01" }, { "name": "jp-01" }]
+                }
+            }
+        });
+        let found = parse_providers(ProviderKind::Proxy, &proxies);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].kind, ProviderKind::Proxy);
+        assert_eq!(found[0].name, "airport");
+        assert_eq!(found[0].vehicle_type, "HTTP");
+        assert_eq!(found[0].updated_at.as_deref(), Some("2024-05-01T08:00:00Z"));
+        assert_eq!(found[0].behavior, None);
+        assert_eq!(found[0].count, 2);
+
+        let rules = serde_json::json!({
+            "providers": {
+                "steam": {
+                    "name": "steam",
+                    "type": "Rule",
+                    "vehicleType": "File",
+                    "behavior": "Domain",
+                    "ruleCount": 42
+                }
+            }
+        });
+        let found = parse_providers(ProviderKind::Rule, &rules);
+        assert_eq!(found[0].behavior.as_deref(), Some("Domain"));
+        assert_eq!(found[0].count, 42);
+        assert_eq!(found[0].updated_at, None);
+
+        assert!(parse_providers(ProviderKind::Rule, &serde_json::json!({})).is_empty());
+    }
+
+    // 构造只含元数据段的 MMDB 文件
+    // 生成 zip，deflate 为 true 时压缩内容
+    fn zip_archive(files: &[(&str, &[u8])], deflate: bool) -> Vec<u8> {
+        use std::io::Write;
+
+        let method = match deflate {
+            true => zip::CompressionMethod::Deflated,
+            false => zip::CompressionMethod::Stored,
+        };
+        let options = zip::write::SimpleFileOptions::default().compression_method(method);
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, content) in files {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(content).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn dashboard_install_and_remove() {
+        let dir = std::env::temp_dir().join(format!("tomoon-dashboards-{}", std::process::id()));
+        let dirs = DashboardDirs {
+            bundled: dir.join("web"),
+            custom: dir.join("custom"),
+        };
+        fs::create_dir_all(dirs.bundled.join("yacd-meta")).unwrap();
+        fs::write(dirs.bundled.join("yacd-meta/index.html"), "yacd").unwrap();
+        // 没有 index.html 的目录不是面板
+        fs::create_dir_all(dirs.bundled.join("broken")).unwrap();
+
+        let zip = zip_archive(&[("index.html", b"zashboard"), ("assets/app.js", b"js")], true);
+        let installed = dirs.install(&zip, "zashboard", false).unwrap();
+        assert_eq!(installed.source, DashboardSource::Custom);
+        assert_eq!(fs::read_to_string(installed.path.join("assets/app.js")).unwrap(), "js");
+        assert!(dirs.install(&zip, "zashboard", false).is_err());
+        let stored = zip_archive(&[("index.html", b"v2")], false);
+        dirs.install(&stored, "zashboard", true).unwrap();
+        assert_eq!(fs::read_to_string(dirs.custom.join("zashboard/index.html")).unwrap(), "v2");
+
+        // tar.gz 中的面板位于唯一的顶层目录下
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()));
+        for (path, content) in [("./metacubexd/index.html", "xd"), ("./metacubexd/app.js", "js")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, content.as_bytes()).unwrap();
+        }
+        let tarball = builder.into_inner().unwrap().finish().unwrap();
+        let name = dashboards::name_from_file(std::path::Path::new("/tmp/metacubexd.tar.gz")).unwrap();
+        assert_eq!(name, "metacubexd");
+        dirs.install(&tarball, &name, false).unwrap();
+        assert!(dirs.custom.join("metacubexd/app.js").exists());
+
+        let names: Vec<(String, DashboardSource)> = dirs.list().into_iter().map(|x| (x.name, x.source)).collect();
+        assert_eq!(
+            names,
+            vec![
+                ("yacd-meta".to_string(), DashboardSource::Bundled),
+                ("metacubexd".to_string(), DashboardSource::Custom),
+                ("zashboard".to_string(), DashboardSource::Custom),
+            ]
+        );
+
+        let escape = zip_archive(&[("../index.html", b"x")], false);
+        assert!(dirs.install(&escape, "escape", false).is_err());
+        assert!(!dir.join("index.html").exists());
+        let missing = zip_archive(&[("readme.txt", b"x")], false);
+        assert!(dirs.install(&missing, "missing", false).is_err());
+        assert!(dirs.install(&zip, "yacd-meta", true).is_err());
+        assert!(dirs.install(&zip, "../web", false).is_err());
+        assert!(dirs.install(b"not an archive", "plain", false).is_err());
+        assert!(!dirs.custom.join("missing").exists());
+
+        // 外部服务按路径读取面板文件
+        let metacubexd = dirs.find("metacubexd").unwrap();
+        assert_eq!(metacubexd.file(""), Some(metacubexd.path.join("index.html")));
+        assert_eq!(metacubexd.file("/app.js"), Some(metacubexd.path.join("app.js")));
+        assert!(metacubexd.file("../zashboard/index.html").is_none());
+        assert!(metacubexd.file("missing.js").is_none());
+
+        assert!(dirs.remove("yacd-meta").is_err());
+        dirs.remove("zashboard").unwrap();
+        assert!(dirs.find("zashboard").is_none());
+        assert!(dirs.remove("zashboard").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn controller_proxy() {
+        assert_eq!(proxy::upstream_path("/api/proxies", ""), "/proxies");
+        assert_eq!(proxy::upstream_path("/api/logs", "token=abc&level=info"), "/logs?level=info");
+        assert_eq!(proxy::upstream_path("/api/traffic", "token"), "/traffic");
+        assert_eq!(proxy::upstream_path("/api", ""), "/");
+        // 节点名称中的转义字符原样转发
+        assert_eq!(
+            proxy::upstream_path("/api/proxies/HK%20%231%3F/delay", "url=x"),
+            "/proxies/HK%20%231%3F/delay?url=x"
+        );
+
+        assert!(proxy::parse_response_head(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n").is_none());
+        let head = proxy::parse_response_head(
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n\x81\x02{}",
+        )
+        .unwrap();
+        assert_eq!(head.status, 101);
+        assert!(head
+            .headers
+            .contains(&("Sec-WebSocket-Accept".to_string(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string())));
+        assert_eq!(head.rest, b"\x81\x02{}");
+    }
+
+    // 只包含 1.0.0.0/8 -> CN 的 IPv4 数据库，记录中的 country 通过指针引用
+    fn country_mmdb() -> Vec<u8> {
+        let node_count = 8u32;
+        let mut data = Vec::new();
+        for node in 0..node_count {
+            let (left, right) = match node {
+                7 => (node_count, node_count + 16),
+                _ => (node + 1, node_count),
+            };
+            data.extend_from_slice(&left.to_be_bytes()[1..]);
+            data.extend_from_slice(&right.to_be_bytes()[1..]);
+        }
+        data.extend_from_slice(&[0u8; 16]);
+        data.extend_from_slice(b"\xE1\x47country\x20\x0B");
+        data.extend_from_slice(b"\xE1\x48iso_code\x42CN");
+        data.extend_from_slice(b"\xAB\xCD\xEFMaxMind.com\xE3");
+        for (key, value) in [("node_count", node_count), ("record_size", 24), ("ip_version", 4)] {
+            data.push(0x40 | key.len() as u8);
+            data.extend_from_slice(key.as_bytes());
+            data.push(0xC4);
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        data
+    }
+
+    fn proto_bytes(field: u8, value: &[u8]) -> Vec<u8> {
+        let mut data = vec![(field << 3) | 2, value.len() as u8];
+        data.extend_from_slice(value);
+        data
+    }
+
+    #[test]
+    fn rule_match_tester() {
+        let dir = std::env::temp_dir().join(format!("tomoon-match-{}", std::process::id()));
+        fs::create_dir_all(dir.join("ruleset")).unwrap();
+        fs::write(dir.join("Country.mmdb"), country_mmdb()).unwrap();
+        let mut domain = vec![0x08, 0x02];
+        domain.extend(proto_bytes(2, b"steampowered.com"));
+        let mut full = vec![0x08, 0x03];
+        full.extend(proto_bytes(2, b"exact.example.com"));
+        full.extend(proto_bytes(3, &proto_bytes(1, b"cn")));
+        let mut site = proto_bytes(1, b"STEAM");
+        site.extend(proto_bytes(2, &domain));
+        site.extend(proto_bytes(2, &full));
+        fs::write(dir.join("GeoSite.dat"), proto_bytes(1, &site)).unwrap();
+        fs::write(dir.join("ruleset/games.yaml"), "payload:\n  - '+.epicgames.com'\n").unwrap();
+        fs::write(dir.join("ruleset/riot.yaml"), "payload:\n  - 'domain-keyword,riotgames'\n").unwrap();
+
+        let config: Value = serde_yaml::from_str(
+            r#"
+rule-providers:
+  games:
+    type: http
+    behavior: domain
+    path: ./ruleset/games.yaml
+  riot:
+    type: file
+    behavior: classical
+    path: ./ruleset/riot.yaml
+rules:
+  - DOMAIN-SUFFIX,example.org,Proxy
+  - GEOSITE,steam,DIRECT
+  - RULE-SET,games,Proxy
+  - IP-CIDR,10.0.0.0/8,DIRECT,no-resolve
+  - GEOIP,CN,DIRECT
+  - DST-PORT,27015-27050,DIRECT
+  - PROCESS-NAME,game.exe,REJECT
+  - AND,((DOMAIN,x.com),(NETWORK,UDP)),REJECT
+  - RULE-SET,riot,DIRECT
+  - MATCH,Proxy
+"#,
+        )
+        .unwrap();
+        let run = |host: Option<&str>, port: Option<u16>, process: Option<&str>, resolved: Option<&str>| {
+            let target = MatchTarget {
+                host: host.map(|x| x.to_string()),
+                port,
+                process: process.map(|x| x.to_string()),
+                network: None,
+            };
+            Matcher::new(&config, &dir, target, resolved.map(|x| x.parse().unwrap())).run()
+        };
+        let index = |report: &crate::matcher::MatchReport| report.matched.as_ref().map(|x| x.index);
+
+        let report = run(Some("Store.SteamPowered.com."), None, None, None);
+        assert_eq!(index(&report), Some(1));
+        assert_eq!(report.policy, "DIRECT");
+        assert!(report.skipped.is_empty());
+        assert_eq!(index(&run(Some("www.epicgames.com"), None, None, None)), Some(2));
+        // classical 条目的类型与顶层规则一样不区分大小写
+        assert_eq!(index(&run(Some("client.riotgames.com"), None, None, Some("8.8.8.8"))), Some(8));
+        // 带属性的列表只匹配有该属性的条目
+        let attr = GeoDatabase::GeoSite.locate(&dir);
+        let data = fs::read(attr).unwrap();
+        assert_eq!(geodata::geosite_contains(&data, "steam@cn", "exact.example.com"), Some(true));
+        assert_eq!(geodata::geosite_contains(&data, "steam@cn", "store.steampowered.com"), Some(false));
+        assert_eq!(geodata::geosite_contains(&data, "missing", "example.com"), None);
+
+        assert_eq!(index(&run(Some("10.1.1.1"), None, None, None)), Some(3));
+        let report = run(Some("1.2.3.4"), None, None, None);
+        assert_eq!(report.matched.unwrap().rule, "GEOIP,CN,DIRECT");
+
+        // 未解析的域名跳过 IP 规则，no-resolve 的规则直接不匹配
+        let report = run(Some("unknown.test"), None, None, None);
+        assert_eq!(index(&report), Some(9));
+        assert_eq!(report.policy, "Proxy");
+        let skipped: Vec<usize> = report.skipped.iter().map(|x| x.index).collect();
+        assert_eq!(skipped, vec![4, 5, 6, 7]);
+
+        let report = run(Some("unknown.test"), Some(27016), None, Some("8.8.8.8"));
+        assert_eq!(index(&report), Some(5));
+        assert_eq!(report.resolved_ip, Some("8.8.8.8".parse().unwrap()));
+        assert_eq!(index(&run(None, None, Some("/home/deck/game.exe"), None)), Some(6));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn mmdb_with_metadata(database_type: &str, build_epoch: u32, node_count: u32) -> Vec<u8> {
+        let mut data = vec![0u8; 16];
+        data.extend_from_slice(b"\xAB\xCD\xEFMaxMind.com");
+        data.push(0xE3);
+        for (key, value) in [
+            ("database_type", None),
+            ("build_epoch", Some((2u8, build_epoch))),
+            ("node_count", Some((0u8, node_count))),
+        ] {
+            data.push(0x40 | key.len() as u8);
+            data.extend_from_slice(key.as_bytes());
+            match value {
+                None => {
+                    data.push(0x40 | database_type.len() as u8);
+                    data.extend_from_slice(database_type.as_bytes());
+                }
+                // uint64 为扩展类型，uint32 直接编码在控制字节中
+                Some((2, x)) => {
+                    data.extend_from_slice(&[0x04, 0x02]);
+                    data.extend_from_slice(&x.to_be_bytes());
+                }
+                Some((_, x)) => {
+                    data.push(0xC4);
+                    data.extend_from_slice(&x.to_be_bytes());
+                }
+            }
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn geodata_verify_and_update() {
+        let mmdb = mmdb_with_metadata("GeoLite2-Country", 1700000000, 42);
+        let metadata = geodata::mmdb_metadata(&mmdb).unwrap();
+        assert_eq!(metadata["database_type"], "GeoLite2-Country");
+        assert_eq!(metadata["build_epoch"], 1700000000);
+        assert!(geodata::verify(GeoDatabase::Mmdb, &mmdb).is_ok());
+        assert!(geodata::verify(GeoDatabase::Asn, b"not a database").is_err());
+        assert!(geodata::verify(GeoDatabase::Mmdb, &mmdb_with_metadata("x", 1, 0)).is_err());
+        assert!(geodata::verify(GeoDatabase::GeoSite, &[0x0a, 0x01, 0x00]).is_ok());
+        assert!(geodata::verify(GeoDatabase::GeoSite, b"<html>").is_err());
+
+        let dir = std::env::temp_dir().join(format!("tomoon-geodata-{}", std::process::id()));
+        let core = dir.join("core");
+        fs::create_dir_all(&core).unwrap();
+        // 已有文件名大小写不同时沿用原文件
+        fs::write(core.join("country.mmdb"), b"old").unwrap();
+        assert_eq!(GeoDatabase::Mmdb.locate(&core), core.join("country.mmdb"));
+        assert_eq!(GeoDatabase::GeoSite.locate(&core), core.join("GeoSite.dat"));
+
+        let source = dir.join("source.mmdb");
+        fs::write(&source, &mmdb).unwrap();
+        let settings = GeodataSettings {
+            mmdb_url: format!("file://{}", source.display()),
+            geosite_url: format!("file://{}", dir.join("missing.dat").display()),
+            ..Default::default()
+        };
+        geodata::update(GeoDatabase::Mmdb, &core, &settings).await.unwrap();
+        assert!(geodata::update(GeoDatabase::GeoSite, &core, &settings).await.is_err());
+        assert!(!core.join("GeoSite.dat").exists());
+
+        let files = geodata::list(&core, &settings);
+        assert_eq!(files.len(), 4);
+        assert!(files[0].exists);
+        assert_eq!(files[0].build_epoch, Some(1700000000));
+        assert_eq!(files[0].version.as_deref(), Some("GeoLite2-Country (1700000000)"));
+        assert!(!files[1].exists);
+
+        let invalid = GeodataSettings {
+            asn_url: "ftp://example.com/asn.mmdb".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(invalid.validate("geodata")[0].field, "geodata.asn_url");
+        assert!(GeodataSettings::default().validate("geodata").is_empty());
+
+        // 导入备份包时保留数据库地址
+        let exported = Settings {
+            geodata: settings.clone(),
+            ..Default::default()
+        };
+        assert_eq!(bundle_import(&exported).geodata, settings);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn subscription_update_job() {
+        let dir = std::env::temp_dir().join(format!("tomoon-update-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.yaml");
+        fs::write(&source, "rules:\n  - MATCH,DIRECT\n").unwrap();
+
+        let target = dir.join("a.yaml").to_string_lossy().to_string();
+        let subs = vec![
+            Subscription::new(target.clone(), format!("file://{}", source.display())),
+            Subscription::new(
+                dir.join("b.yaml").to_string_lossy().to_string(),
+                format!("file://{}", dir.join("missing.yaml").display()),
+            ),
+        ];
+        let jobs = JobRegistry::default();
+        let job = subscriptions::start_update(&jobs, subs.clone()).unwrap();
+        assert!(job.items.iter().all(|x| x.status == ItemStatus::Pending));
+        assert_eq!(job.progress.total, 2);
+        // 运行中的任务不会被重复创建，订阅不同的请求返回冲突
+        let reversed: Vec<Subscription> = subs.iter().rev().cloned().collect();
+        assert_eq!(subscriptions::start_update(&jobs, reversed).unwrap().id, job.id);
+        let conflict = subscriptions::start_update(&jobs, subs[..1].to_vec()).unwrap_err();
+        assert_eq!(conflict.error_kind, ClashErrorKind::ConflictError);
+        assert_eq!(jobs.list(Some(JobKind::UpdateSubs)).len(), 1);
+
+        let job = jobs.wait(&job.id).await.unwrap();
+        assert_eq!(job.state, JobState::Succeeded);
+        assert_eq!(job.progress.done, 2);
+        assert_eq!(job.result.unwrap()["failed"], 1);
+        assert_eq!(job.items[0].status, ItemStatus::Ok);
+        assert!(matches!(
+            job.items[1].status,
+            ItemStatus::Failed {
+                error_kind: ClashErrorKind::IOError,
+                ..
+            }
+        ));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "rules:\n  - MATCH,DIRECT\n");
+        assert_eq!(jobs.list(Some(JobKind::UpdateSubs))[0].id, job.id);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn job_cancel_and_errors() {
+        let jobs = JobRegistry::default();
+        let job = jobs.spawn(JobKind::DownloadSub, vec![], |_| async {
+            std::future::pending::<Result<serde_json::Value, ClashError>>().await
+        });
+        assert!(!job.finished());
+        jobs.cancel(&job.id).unwrap();
+        let job = jobs.wait(&job.id).await.unwrap();
+        assert_eq!(job.state, JobState::Cancelled);
+        assert!(job.finished_at.is_some());
+
+        let failed = jobs.spawn(JobKind::DownloadSub, vec![], |_| async {
+            Err(ClashError {
+                message: "boom".to_string(),
+                error_kind: ClashErrorKind::NetworkError,
+            })
+        });
+        let failed = jobs.wait(&failed.id).await.unwrap();
+        assert_eq!(failed.state, JobState::Failed);
+        assert_eq!(failed.error.unwrap().message, "boom");
+
+        // 最近开始的任务排在前面
+        let listed = jobs.list(Some(JobKind::DownloadSub));
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].id, failed.id);
+        assert!(jobs.list(Some(JobKind::UpdateSubs)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn external_https_server() {
+        let dir = std::env::temp_dir().join(format!("tomoon-tls-{}", std::process::id()));
+        let addresses: Vec<std::net::IpAddr> = vec!["192.168.1.2".parse().unwrap(), "fd00::2".parse().unwrap()];
+        let certificate = ServerCertificate::load_or_create(&dir, &addresses).unwrap();
+        let fingerprint = certificate.fingerprint();
+        assert_eq!(fingerprint.len(), 32 * 3 - 1);
+        // 再次启动时使用保存的证书
+        let reloaded = ServerCertificate::load_or_create(&dir, &addresses).unwrap();
+        assert_eq!(reloaded.fingerprint(), fingerprint);
+        let reloaded = ServerCertificate::load_or_create(&dir, &addresses[..1]).unwrap();
+        assert_eq!(reloaded.fingerprint(), fingerprint);
+        assert_ne!(ServerCertificate::generate(&addresses).unwrap().fingerprint(), fingerprint);
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let server = tls::run_https_server(
+            || actix_web::App::new().route("/ping", actix_web::web::get().to(|| async { "pong" })),
+            port,
+            tls::server_config(&dir, &certificate).unwrap(),
+        )
+        .unwrap();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        let client = || {
+            reqwest::Client::builder()
+                .danger_accept_invalid_certs(true)
+                .tls_info(true)
+                .no_proxy()
+                .build()
+                .unwrap()
+        };
+        // 每次使用新的客户端，避免复用连接或恢复会话
+        let served = || async {
+            let response = client()
+                .get(format!("https://127.0.0.1:{}/ping", port))
+                .send()
+                .await
+                .unwrap();
+            let cert = response
+                .extensions()
+                .get::<reqwest::tls::TlsInfo>()
+                .and_then(|x| x.peer_certificate())
+                .unwrap()
+                .to_vec();
+            assert_eq!(response.text().await.unwrap(), "pong");
+            ServerCertificate { cert, key: Vec::new() }.fingerprint()
+        };
+        assert_eq!(served().await, fingerprint);
+
+        // 地址变化后重新生成，运行中的服务在下次握手时使用新证书
+        let moved: Vec<std::net::IpAddr> = vec!["192.168.1.3".parse().unwrap()];
+        let renewed = ServerCertificate::load_or_create(&dir, &moved).unwrap();
+        assert_ne!(renewed.fingerprint(), fingerprint);
+        assert_eq!(served().await, renewed.fingerprint());
+        // 明文请求无法完成握手
+        assert!(client().get(format!("http://127.0.0.1:{}/ping", port)).send().await.is_err());
+
+        handle.stop(false).await;
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
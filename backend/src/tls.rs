@@ -0,0 +1,261 @@
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use actix_http::{body::MessageBody, HttpService, Request, Response};
+use actix_service::{fn_service, map_config, IntoServiceFactory, ServiceFactory, ServiceFactoryExt};
+use actix_web::{
+    cookie::time::{self, OffsetDateTime},
+    dev::{AppConfig, Server},
+    rt::net::TcpStream,
+};
+use rcgen::{CertificateParams, DnType, ExtendedKeyUsagePurpose, KeyPair, SanType, PKCS_ECDSA_P256_SHA256};
+use ring::digest;
+use rustls::{
+    crypto::ring::default_provider,
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::extensions::GeneralName;
+
+// 证书有效期不超过 825 天，否则部分系统会拒绝
+const VALIDITY_DAYS: i64 = 825;
+// 剩余有效期不足时重新生成
+const RENEW_BEFORE_DAYS: i64 = 30;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 外部服务使用的自签名证书与 PKCS#8 私钥，均为 DER 格式
+pub struct ServerCertificate {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+fn tls_error(e: impl ToString) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+// 先写入临时文件再替换，避免读到写了一半的文件
+fn write_file(path: &Path, data: &[u8], mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    fs::write(&temp, data)?;
+    fs::set_permissions(&temp, fs::Permissions::from_mode(mode))?;
+    fs::rename(&temp, path)
+}
+
+impl ServerCertificate {
+    /// 生成 ECDSA P-256 自签名证书，`addresses` 写入 subjectAltName
+    pub fn generate(addresses: &[IpAddr]) -> io::Result<Self> {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).map_err(tls_error)?;
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).map_err(tls_error)?;
+        params
+            .subject_alt_names
+            .extend(addresses.iter().map(|x| SanType::IpAddress(*x)));
+        params.distinguished_name.remove(DnType::CommonName);
+        params.distinguished_name.push(DnType::CommonName, "ToMoon");
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - time::Duration::days(1);
+        params.not_after = now + time::Duration::days(VALIDITY_DAYS);
+        let cert = params.self_signed(&key).map_err(tls_error)?;
+
+        Ok(Self {
+            cert: cert.der().to_vec(),
+            key: key.serialize_der(),
+        })
+    }
+
+    /// 证书在续期时间之前且 subjectAltName 包含全部 `addresses`
+    fn covers(&self, addresses: &[IpAddr]) -> bool {
+        let Ok((_, cert)) = x509_parser::parse_x509_certificate(&self.cert) else {
+            return false;
+        };
+        let renew_at = cert.validity().not_after.timestamp() - RENEW_BEFORE_DAYS * 24 * 3600;
+        if OffsetDateTime::now_utc().unix_timestamp() > renew_at {
+            return false;
+        }
+        let names: Vec<IpAddr> = match cert.subject_alternative_name() {
+            Ok(Some(x)) => x
+                .value
+                .general_names
+                .iter()
+                .filter_map(|x| match x {
+                    GeneralName::IPAddress(ip) => match ip.len() {
+                        4 => <[u8; 4]>::try_from(*ip).ok().map(IpAddr::from),
+                        16 => <[u8; 16]>::try_from(*ip).ok().map(IpAddr::from),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect(),
+            _ => return false,
+        };
+        addresses.iter().all(|x| names.contains(x))
+    }
+
+    /// 读取 `dir` 中保存的证书，不存在、即将过期或未包含当前地址时重新生成并保存
+    pub fn load_or_create(dir: &Path, addresses: &[IpAddr]) -> io::Result<Self> {
+        let (cert_path, key_path) = (dir.join("cert.der"), dir.join("key.der"));
+        if let (Ok(cert), Ok(key)) = (fs::read(&cert_path), fs::read(&key_path)) {
+            let certificate = Self { cert, key };
+            if certificate.covers(addresses) {
+                return Ok(certificate);
+            }
+        }
+
+        let certificate = Self::generate(addresses)?;
+        fs::create_dir_all(dir)?;
+        // 证书最后写入，外部服务据此重新加载
+        write_file(&key_path, &certificate.key, 0o600)?;
+        write_file(&cert_path, &certificate.cert, 0o644)?;
+        log::info!(
+            "Generated TLS certificate {} ({})",
+            cert_path.display(),
+            certificate.fingerprint()
+        );
+        Ok(certificate)
+    }
+
+    /// 证书的 SHA-256 指纹，格式与浏览器显示的一致，如 `AB:CD:...`
+    pub fn fingerprint(&self) -> String {
+        digest::digest(&digest::SHA256, &self.cert)
+            .as_ref()
+            .iter()
+            .map(|x| format!("{:02X}", x))
+            .collect::<Vec<String>>()
+            .join(":")
+    }
+
+    fn certified_key(&self) -> io::Result<CertifiedKey> {
+        CertifiedKey::from_der(
+            vec![CertificateDer::from(self.cert.clone())],
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.key.clone())),
+            &default_provider(),
+        )
+        .map_err(tls_error)
+    }
+}
+
+/// 握手时使用 `dir` 中最新的证书，配对信息更换证书后无需重启外部服务
+#[derive(Debug)]
+struct CertificateResolver {
+    dir: PathBuf,
+    // (证书文件的修改时间, 证书)
+    current: RwLock<(Option<SystemTime>, Arc<CertifiedKey>)>,
+}
+
+fn cert_modified(dir: &Path) -> Option<SystemTime> {
+    fs::metadata(dir.join("cert.der"))
+        .and_then(|x| x.modified())
+        .ok()
+}
+
+impl CertificateResolver {
+    fn reload(&self) -> io::Result<Arc<CertifiedKey>> {
+        let modified = cert_modified(&self.dir);
+        let certificate = ServerCertificate {
+            cert: fs::read(self.dir.join("cert.der"))?,
+            key: fs::read(self.dir.join("key.der"))?,
+        };
+        let key = Arc::new(certificate.certified_key()?);
+        *self.current.write().unwrap() = (modified, key.clone());
+        log::info!("External server uses certificate {}", certificate.fingerprint());
+        Ok(key)
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let (modified, key) = self.current.read().unwrap().clone();
+        if cert_modified(&self.dir) == modified {
+            return Some(key);
+        }
+        match self.reload() {
+            Ok(x) => Some(x),
+            Err(e) => {
+                log::warn!("Failed to reload the TLS certificate: {}", e);
+                Some(key)
+            }
+        }
+    }
+}
+
+/// `certificate` 为 `dir` 中已保存的证书，证书文件更新后在下次握手时生效
+pub fn server_config(dir: &Path, certificate: &ServerCertificate) -> io::Result<rustls::ServerConfig> {
+    let resolver = CertificateResolver {
+        dir: dir.to_path_buf(),
+        current: RwLock::new((cert_modified(dir), Arc::new(certificate.certified_key()?))),
+    };
+    Ok(rustls::ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver)))
+}
+
+/// 外部服务的证书目录
+pub fn certificate_dir() -> io::Result<PathBuf> {
+    Ok(crate::utils::get_decky_data_dir()?.join("tls"))
+}
+
+/// 以 HTTPS 运行 `factory` 创建的 App，同时接受 IPv4 与 IPv6 连接
+pub fn run_https_server<F, I, S, B>(factory: F, port: u16, config: rustls::ServerConfig) -> io::Result<Server>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<Request, Config = AppConfig> + 'static,
+    S::Error: Into<actix_web::Error> + 'static,
+    S::InitError: std::fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    B: MessageBody + 'static,
+{
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let service = move || {
+        let acceptor = acceptor.clone();
+        let factory = factory.clone();
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        fn_service(move |stream: TcpStream| {
+            let acceptor = acceptor.clone();
+            async move {
+                let peer = stream.peer_addr().ok();
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => Ok((stream, peer)),
+                    Ok(Err(e)) => {
+                        log::debug!("TLS handshake with {:?} failed: {}", peer, e);
+                        Err(())
+                    }
+                    Err(_) => {
+                        log::debug!("TLS handshake with {:?} timed out", peer);
+                        Err(())
+                    }
+                }
+            }
+        })
+        .and_then(
+            HttpService::build()
+                .secure()
+                .local_addr(addr)
+                // AppConfig 只在生成 URL 时使用，默认值即可
+                .h1(map_config(
+                    factory().into_factory().map_err(|e| e.into().error_response()),
+                    |_| AppConfig::default(),
+                ))
+                .map_err(|e| log::debug!("HTTPS connection error: {}", e)),
+        )
+    };
+    // `::` 默认同时接受 IPv4 连接，系统禁用 IPv6 时只监听 IPv4
+    let listener = std::net::TcpListener::bind(("::", port))
+        .or_else(|_| std::net::TcpListener::bind(("0.0.0.0", port)))?;
+    Ok(Server::build()
+        .listen("tomoon-external-https", listener, service)?
+        .workers(1)
+        .run())
+}
@@ -1,3 +1,6 @@
+use std::net::IpAddr;
+
+use local_ip_address::list_afinet_netifas;
 use regex::Regex;
 
 use sysinfo::{ProcessExt, System, SystemExt};
@@ -69,6 +72,77 @@ pub fn get_sub_dir() -> std::io::Result<std::path::PathBuf> {
     Ok(path)
 }
 
+/// 内核的工作目录，geodata 等文件位于此处
+pub fn get_core_dir() -> std::io::Result<std::path::PathBuf> {
+    Ok(get_current_working_dir()?.join("bin/core"))
+}
+
+/// 预先下载的 rule-providers 与 proxy-providers 缓存
+pub fn get_provider_dir() -> std::io::Result<std::path::PathBuf> {
+    let path = get_decky_data_dir()?.join("providers");
+    Ok(path)
+}
+
+/// Decky 为插件分配的设置目录，由启动后端的 Python 进程传入
+pub fn get_decky_settings_dir() -> std::io::Result<std::path::PathBuf> {
+    if let Ok(dir) = std::env::var("DECKY_PLUGIN_SETTINGS_DIR") {
+        return Ok(std::path::PathBuf::from(dir));
+    }
+    let settings_dir = get_current_working_dir()?
+        .parent().ok_or(std::io::ErrorKind::AddrNotAvailable)?
+        .parent().ok_or(std::io::ErrorKind::AddrNotAvailable)?
+        .join("settings/tomoon");
+    Ok(settings_dir)
+}
+
+/// 用户安装的自定义面板，每个子目录为一个面板
+/// 沿用旧版 Python 后端的目录，已安装的面板无需迁移
+pub fn get_dashboard_dir() -> std::io::Result<std::path::PathBuf> {
+    let path = get_decky_settings_dir()?.join("dashboard");
+    Ok(path)
+}
+
+/// Steam 安装目录，插件以 root 运行，通过 Decky 提供的用户目录定位
+pub fn get_steam_dir() -> std::path::PathBuf {
+    let home = std::env::var("DECKY_USER_HOME")
+        .or_else(|_| std::env::var("HOME"))
+        .unwrap_or_else(|_| String::from("/home/deck"));
+    std::path::PathBuf::from(home).join(".local/share/Steam")
+}
+
+/// 局域网中可访问本机的地址，首选地址排在最前
+pub fn get_local_addresses() -> Vec<IpAddr> {
+    let addresses = list_afinet_netifas()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(_, ip)| ip);
+    reachable_addresses(addresses, local_ip_address::local_ip().ok())
+}
+
+/// 过滤无法从局域网访问的地址并去重，`preferred` 排在最前
+pub fn reachable_addresses(
+    addresses: impl IntoIterator<Item = IpAddr>,
+    preferred: Option<IpAddr>,
+) -> Vec<IpAddr> {
+    let mut addresses: Vec<IpAddr> = addresses
+        .into_iter()
+        .filter(|ip| match ip {
+            IpAddr::V4(x) => !x.is_loopback() && !x.is_link_local() && !x.is_unspecified(),
+            // fe80::/10 需要带网卡名才能访问，不适合放进链接
+            IpAddr::V6(x) => {
+                !x.is_loopback() && !x.is_unspecified() && (x.segments()[0] & 0xffc0) != 0xfe80
+            }
+        })
+        .collect();
+    // IPv4 在前，同族按地址排序使重复项相邻
+    addresses.sort_by_key(|ip| (ip.is_ipv6(), *ip));
+    addresses.dedup();
+    if let Some(index) = addresses.iter().position(|x| Some(*x) == preferred) {
+        addresses[..=index].rotate_right(1);
+    }
+    addresses
+}
+
 pub fn get_user_agent() -> String {
     format!(
         "ToMoon/{} mihomo/1.19.4 clash-verge/2.2.3 Clash/v1.18.0",
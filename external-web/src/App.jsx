@@ -47,6 +47,10 @@ function App() {
                 </svg>
               </button>
             </div>
+            {/* 面板通过 ToMoon 转发到内核，后端地址填写 /api 即可，无需 secret */}
+            <a className='px-2 text-indigo-500 dark:text-indigo-400 text-center' href={getBaseHost() + "ui/"}>
+              打开控制面板（后端地址 {window.location.origin}/api）
+            </a>
           </div>
         </div>
       </div>
@@ -70,11 +74,61 @@ const getColorSet = () => {
       button: color.indigo[500]
     };
 };
-const onDownloadBtnClick = (url, isSubscribed) => {
-  const colorSet = getColorSet();
-  let baseHost = '/';
+const getBaseHost = () => {
   if (import.meta.env.DEV) {
-    baseHost = 'http://127.0.0.1:55556/';
+    return 'http://127.0.0.1:55556/';
+  }
+  return '/';
+};
+
+// 请求返回 401 时，要求输入 Deck 上显示的配对码
+const pair = async () => {
+  const colorSet = getColorSet();
+  const { value: code } = await Swal.fire({
+    iconColor: colorSet.icon,
+    confirmButtonColor: colorSet.button,
+    background: colorSet.background,
+    color: colorSet.text,
+    title: "配对",
+    text: "请输入 Steam Deck 上 ToMoon 显示的配对码",
+    input: "text",
+    inputAttributes: { inputmode: "numeric", autocomplete: "one-time-code" },
+    showCancelButton: true,
+  });
+  if (!code) {
+    return false;
+  }
+  await axios.post(getBaseHost() + "auth/login", {
+    code: code.trim(),
+  }, {
+    headers: { 'content-type': 'application/x-www-form-urlencoded' },
+    withCredentials: true,
+  });
+  return true;
+};
+
+const onDownloadBtnClick = async (url, isSubscribed) => {
+  const colorSet = getColorSet();
+  const baseHost = getBaseHost();
+  const status = await axios.get(baseHost + "auth/status", { withCredentials: true })
+    .catch(() => null);
+  if (!status?.data?.success) {
+    try {
+      if (!(await pair())) {
+        return;
+      }
+    } catch (error) {
+      Swal.fire({
+        iconColor: colorSet.icon,
+        confirmButtonColor: colorSet.button,
+        background: colorSet.background,
+        color: colorSet.text,
+        icon: 'error',
+        title: '配对失败',
+        text: error?.response?.data?.data ?? error?.message,
+      });
+      return;
+    }
   }
   Swal.fire({
     iconColor: colorSet.icon,
@@ -91,6 +145,7 @@ const onDownloadBtnClick = (url, isSubscribed) => {
     subconv: isSubscribed
   }, {
     headers: { 'content-type': 'application/x-www-form-urlencoded' },
+    withCredentials: true,
   }).then((response) => {
     if (response.status === 200) {
       Swal.fire({
@@ -22,9 +22,6 @@ class Plugin:
 
         utils.write_font_config()
 
-        dashboard_list = dashboard.get_dashboard_list()
-        logger.info(f"dashboard_list: {dashboard_list}")
-
         logger.info("Start Tomoon.")
         # os.system("chmod -R a+x " + decky.DECKY_PLUGIN_DIR)
         # 切换到工作目录
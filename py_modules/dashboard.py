@@ -1,5 +1,6 @@
+import json
 import os
-from pathlib import Path
+import urllib.request
 
 import decky_plugin
 from config import logger
@@ -10,30 +11,27 @@ defalut_dashboard = os.path.join(
 defalut_dashboard_list = [defalut_dashboard]
 
 
-def get_dashboard_list():
-    dashboard_list = []
+def get_backend_port():
+    # 后端端口保存在 Rust 后端的设置文件中
+    settings_path = os.path.join(decky_plugin.DECKY_PLUGIN_RUNTIME_DIR, "tomoon.json")
+    try:
+        with open(settings_path, "r") as f:
+            return json.load(f).get("backend_port", 55555)
+    except Exception:
+        return 55555
 
+
+def get_dashboard_list():
+    # 面板的查找与校验由 Rust 后端负责，这里只返回面板路径
     try:
-        # 遍历 dashboard_dir 下深度 1 的路径, 如果存在 xxx/index.html 则认为是一个 dashboard
-        dashboard_dir_path = Path(f"{decky_plugin.DECKY_PLUGIN_DIR}/bin/core/web")
-
-        for path in dashboard_dir_path.iterdir():
-            if path.is_dir() and (path / "index.html").exists():
-                dashboard_list.append(str(path))
-
-        custom_dashboard_dir_path = Path(
-            f"{decky_plugin.DECKY_PLUGIN_SETTINGS_DIR}/dashboard"
-        )
-        # 如果 custom_dashboard_dir_path 不存在 创建
-        if not custom_dashboard_dir_path.is_dir():
-            custom_dashboard_dir_path.mkdir(parents=True)
-        for path in custom_dashboard_dir_path.iterdir():
-            if path.is_dir() and (path / "index.html").exists():
-                dashboard_list.append(str(path))
+        url = f"http://localhost:{get_backend_port()}/dashboards"
+        with urllib.request.urlopen(url, timeout=5) as response:
+            data = json.load(response)
+        dashboard_list = [x["path"] for x in data.get("data", [])]
 
         logger.info(f"get_dashboard_list: {dashboard_list}")
 
-        return dashboard_list
+        return dashboard_list or defalut_dashboard_list
     except Exception as e:
         logger.error(f"error during get_dashboard_list: {e}")
         return defalut_dashboard_list
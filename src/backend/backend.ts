@@ -4,6 +4,8 @@ import axios from "axios";
 import { EnhancedMode } from ".";
 
 const USDPL_PORT: number = 55555;
+// 本机后端，只监听 localhost
+const LOCAL_BACKEND_URL = "http://localhost:55555";
 
 // Utility
 
@@ -43,32 +45,66 @@ export async function getEnabled(): Promise<boolean> {
   return (await call_backend("get_clash_status", []))[0];
 }
 
-export async function downloadSub(value: String): Promise<any[]> {
-  return (await call_backend("download_sub", [value]))[0];
+// 返回新建的下载任务，进度通过 getJob 查询
+export async function downloadSub(value: String): Promise<any> {
+  return await localApi<any>("POST", "/download_sub?wait=false", { link: value });
 }
 
-export async function getDownloadStatus(): Promise<String> {
-  return (await call_backend("get_download_status", []))[0];
+export async function getJob(id: String): Promise<any> {
+  return await localApi<any>("GET", `/jobs/${id}`);
 }
 
 export async function getSubList(): Promise<String> {
-  return (await call_backend("get_sub_list", []))[0];
+  return JSON.stringify(await localApi<any[]>("GET", "/subs"));
 }
 
-export async function deleteSub(value: Number): Promise<[boolean, String]> {
-  return (await call_backend("delete_sub", [value])).slice(0, 2);
+export async function deleteSub(value: String): Promise<[boolean, String]> {
+  try {
+    await localApi<void>("DELETE", `/subs/${value}`);
+    return [true, ""];
+  } catch (e: any) {
+    return [false, e?.response?.data?.data || String(e)];
+  }
 }
 
 export async function setSub(value: String): Promise<any> {
-  return (await call_backend("set_sub", [value]))[0];
+  return await localApi<any>("POST", `/subs/${value}/select`);
 }
 
+// 返回更新任务，进度通过 getJob 查询
 export async function updateSubs(): Promise<any> {
-  return (await call_backend("update_subs", []))[0];
+  return await localApi<any>("POST", "/subs/update");
+}
+
+export async function getProviders(): Promise<any> {
+  return await localApi<any>("GET", "/providers");
 }
 
-export async function getUpdateStatus(): Promise<String> {
-  return (await call_backend("get_update_status", []))[0];
+export async function updateProvider(kind: "rule" | "proxy", name: String): Promise<[boolean, String]> {
+  try {
+    await localApi<void>("POST", `/providers/${kind}/${encodeURIComponent(name.toString())}/update`);
+    return [true, ""];
+  } catch (e: any) {
+    return [false, e?.response?.data?.data || String(e)];
+  }
+}
+
+export async function healthCheckProvider(name: String): Promise<[boolean, String]> {
+  try {
+    await localApi<void>("POST", `/providers/proxy/${encodeURIComponent(name.toString())}/healthcheck`);
+    return [true, ""];
+  } catch (e: any) {
+    return [false, e?.response?.data?.data || String(e)];
+  }
+}
+
+export async function getGeodata(): Promise<any> {
+  return await localApi<any>("GET", "/geodata");
+}
+
+// 返回新建的更新任务
+export async function updateGeodata(): Promise<any> {
+  return await localApi<any>("POST", "/geodata/update");
 }
 
 export async function createDebugLog(): Promise<boolean> {
@@ -80,7 +116,8 @@ export async function getRunningStatus(): Promise<String> {
 }
 
 export async function getCurrentSub(): Promise<string> {
-  return (await call_backend("get_current_sub", []))[0];
+  const subs = await localApi<any[]>("GET", "/subs");
+  return subs.find((x) => x.current)?.id || "";
 }
 
 export class PyBackendData {
@@ -190,6 +227,40 @@ export class PyBackend {
   }
 }
 
+/**
+ * 调用本机后端的 REST 接口
+ * @param path 以 / 开头的接口路径
+ * @returns 响应中的 data 字段
+ */
+export async function localApi<T>(
+  method: "GET" | "POST" | "PATCH" | "DELETE",
+  path: string,
+  data?: any
+): Promise<T> {
+  const response = await axios.request({
+    method: method,
+    url: `${LOCAL_BACKEND_URL}${path}`,
+    data: data,
+  });
+  return response.data.data as T;
+}
+
+export interface PairingInfo {
+  url: string;
+  urls: string[];
+  port: number;
+  token: string;
+  code: string;
+  expires_in: number;
+  qr_svg: string;
+  fingerprint?: string;
+}
+
+export interface PairingCode {
+  code: string;
+  expires_in: number;
+}
+
 export enum ApiCallMethod {
   GET = "GET",
   POST = "POST",
@@ -207,7 +278,7 @@ export function apiCallMethod(
   params: {},
   method: ApiCallMethod = ApiCallMethod.POST
 ): Promise<any> {
-  const url = `http://localhost:55556/${name}`;
+  const url = `${LOCAL_BACKEND_URL}/${name}`;
   const headers = { "content-type": "application/x-www-form-urlencoded" };
 
   // 封装请求逻辑，根据 method 参数决定使用 GET 还是 POST
@@ -277,4 +348,17 @@ export class ApiCallBackend {
   public static async setDashboard(value: String) {
     return await apiCallMethod("set_dashboard", { dashboard: value });
   }
+
+  // 外部服务配对
+  public static async getPairingInfo() {
+    return await localApi<PairingInfo>("GET", "/pairing_info");
+  }
+
+  public static async regeneratePairingCode() {
+    return await localApi<PairingCode>("POST", "/pairing_code");
+  }
+
+  public static async revokeSessions() {
+    return await localApi<void>("POST", "/revoke_sessions");
+  }
 }
@@ -0,0 +1,126 @@
+import { PanelSection, PanelSectionRow, Field } from "@decky/ui";
+import { FC, useEffect, useState } from "react";
+import { ApiCallBackend, PairingInfo } from "../backend/backend";
+import { ActionButtonItem } from ".";
+import { localizationManager, L } from "../i18n";
+
+const formatExpiry = (seconds: number) => {
+  const minutes = Math.floor(seconds / 60);
+  return `${minutes}:${String(seconds % 60).padStart(2, "0")}`;
+};
+
+export const PairingComponent: FC = () => {
+  const [info, setInfo] = useState<PairingInfo | null>(null);
+  const [expiresIn, setExpiresIn] = useState(0);
+  const [disabled, setDisabled] = useState(false);
+
+  const loadInfo = async () => {
+    try {
+      const data = await ApiCallBackend.getPairingInfo();
+      setInfo(data);
+      setExpiresIn(data.expires_in);
+      setDisabled(false);
+    } catch (e) {
+      // 外部服务关闭时后端返回 400
+      console.warn("getPairingInfo failed:", e);
+      setInfo(null);
+      setDisabled(true);
+    }
+  };
+
+  useEffect(() => {
+    loadInfo();
+  }, []);
+
+  // 倒计时结束后重新获取，后端会自动更换过期的配对码
+  useEffect(() => {
+    if (!info) {
+      return;
+    }
+    if (expiresIn <= 0) {
+      loadInfo();
+      return;
+    }
+    const handle = setTimeout(() => setExpiresIn(expiresIn - 1), 1000);
+    return () => clearTimeout(handle);
+  }, [info, expiresIn]);
+
+  if (disabled) {
+    return (
+      <PanelSection title={localizationManager.getString(L.PAIRING)}>
+        <PanelSectionRow>
+          {localizationManager.getString(L.EXTERNAL_SERVER_DISABLED)}
+        </PanelSectionRow>
+      </PanelSection>
+    );
+  }
+
+  return (
+    <PanelSection title={localizationManager.getString(L.PAIRING)}>
+      {info && (
+        <>
+          <PanelSectionRow>
+            <div style={{ display: "flex", justifyContent: "center" }}>
+              <img
+                src={`data:image/svg+xml;utf8,${encodeURIComponent(info.qr_svg)}`}
+                width={128}
+                height={128}
+              />
+            </div>
+            <p style={{ textAlign: "center", wordBreak: "break-all" }}>
+              {info.url.split("/auth/")[0]}
+            </p>
+          </PanelSectionRow>
+          <PanelSectionRow>
+            <Field
+              focusable
+              label={localizationManager.getString(L.PAIRING_CODE)}
+            >
+              {info.code}
+            </Field>
+          </PanelSectionRow>
+          <PanelSectionRow>
+            <Field
+              focusable
+              label={localizationManager.getString(L.PAIRING_CODE_EXPIRES)}
+            >
+              {formatExpiry(expiresIn)}
+            </Field>
+          </PanelSectionRow>
+          {info.fingerprint && (
+            <PanelSectionRow>
+              <Field
+                focusable
+                label={localizationManager.getString(L.CERT_FINGERPRINT)}
+                description={info.fingerprint}
+              />
+            </PanelSectionRow>
+          )}
+        </>
+      )}
+      <PanelSectionRow>
+        <ActionButtonItem
+          layout="below"
+          onClick={async () => {
+            await ApiCallBackend.regeneratePairingCode();
+            // 二维码中的一次性令牌也需要重新签发
+            await loadInfo();
+          }}
+        >
+          {localizationManager.getString(L.REGENERATE_PAIRING_CODE)}
+        </ActionButtonItem>
+      </PanelSectionRow>
+      <PanelSectionRow>
+        <ActionButtonItem
+          layout="below"
+          onClick={async () => {
+            await ApiCallBackend.revokeSessions();
+            await loadInfo();
+          }}
+        >
+          {localizationManager.getString(L.REVOKE_SESSIONS)}
+        </ActionButtonItem>
+      </PanelSectionRow>
+    </PanelSection>
+  );
+};
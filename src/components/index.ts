@@ -1,3 +1,4 @@
 export * from "./actionButtonItem";
 export * from "./Version";
-export * from "./SubList";
\ No newline at end of file
+export * from "./SubList";
+export * from "./Pairing";
\ No newline at end of file
@@ -74,6 +74,15 @@ const I18N_KEYS = [
   "UPDATE_TO",
   "INSTALLED_VERSION",
   "LATEST_VERSION",
+
+  // External server pairing
+  "PAIRING",
+  "PAIRING_CODE",
+  "PAIRING_CODE_EXPIRES",
+  "CERT_FINGERPRINT",
+  "REGENERATE_PAIRING_CODE",
+  "REVOKE_SESSIONS",
+  "EXTERNAL_SERVER_DISABLED",
 ] as const;
 
 // 创建常量对象并导出
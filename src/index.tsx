@@ -22,7 +22,11 @@ import { Subscriptions, About, Debug } from "./pages";
 import * as backend from "./backend/backend";
 
 import { ApiCallBackend, PyBackend, EnhancedMode } from "./backend";
-import { ActionButtonItem, VersionComponent } from "./components";
+import {
+  ActionButtonItem,
+  PairingComponent,
+  VersionComponent,
+} from "./components";
 import { localizationManager, L } from "./i18n";
 
 let enabledGlobal = false;
@@ -70,26 +74,25 @@ const Content: FC<{}> = ({}) => {
       // console.log(`getSubList: ${v}`);
       let x: Array<any> = JSON.parse(v.toString());
       let re = new RegExp("(?<=subs/).+.yaml$");
-      let i = 0;
       subs = x.map((x) => {
         let name = re.exec(x.path);
         return {
-          id: i++,
-          name: name![0],
+          id: x.id,
+          name: x.name || name![0],
           url: x.url,
         };
       });
       let items = x.map((x) => {
         let name = re.exec(x.path);
         return {
-          label: name![0],
-          data: x.path,
+          label: x.name || name![0],
+          data: x.id,
         };
       });
       subs_option = items;
       setOptions(subs_option);
       console.log("Subs ready");
-      setIsSelectionDisabled(i == 0);
+      setIsSelectionDisabled(x.length == 0);
       //console.log(sub);
     });
   };
@@ -396,6 +399,8 @@ const Content: FC<{}> = ({}) => {
         </PanelSectionRow>
       </PanelSection>
 
+      <PairingComponent />
+
       <VersionComponent />
     </div>
   );
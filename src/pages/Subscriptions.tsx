@@ -22,68 +22,51 @@ export const Subscriptions: FC<SubProp> = ({ Subscriptions }) => {
   const [updateTips, setUpdateTips] = useState("");
   const [QRPageUrl, setQRPageUrl] = useState("");
 
-  let checkStatusHandler: any;
-  let checkUpdateStatusHandler: any;
-
-  const refreshDownloadStatus = () => {
-    backend.resolve(backend.getDownloadStatus(), (v: any) => {
-      let response = v.toString();
-      switch (response) {
-        case "Downloading":
-          setDownloadTips("Downloading...");
-          break;
-        case "Failed":
-          setDownloadTips("Download Failed");
-          break;
-        case "Success":
-          setDownloadTips("Download Succeeded");
-          // 刷新 Subs
-          refreshSubs();
-          break;
-      }
-      if (response != "Downloading") {
-        clearInterval(checkStatusHandler);
-        setDownlaodBtnDisable(false);
+  // 每 500ms 查询一次任务，结束后回调
+  const watchJob = (id: String, onFinished: (job: any) => void) => {
+    const poll = async () => {
+      const job = await backend.getJob(id).catch(() => ({ state: "failed" }));
+      if (job.state == "running") {
+        setTimeout(poll, 500);
+      } else {
+        onFinished(job);
       }
-    });
+    };
+    setTimeout(poll, 500);
   };
 
-  const refreshUpdateStatus = () => {
-    backend.resolve(backend.getUpdateStatus(), (v: any) => {
-      let response = v.toString();
-      switch (response) {
-        case "Downloading":
-          setDownloadTips("Downloading... Please wait");
-          break;
-        case "Error":
-          setDownloadTips("Update Error");
-          break;
-        case "Failed":
-          setDownloadTips("Update Failed");
-          break;
-        case "Success":
-          setDownloadTips("Update Succeeded");
-          // 刷新 Subs
-          refreshSubs();
-          break;
-      }
-      if (response != "Downloading") {
-        clearInterval(checkUpdateStatusHandler);
-        setUpdateBtnDisable(false);
-      }
-    });
+  const downloadFinished = (job: any) => {
+    if (job.state == "succeeded") {
+      setDownloadTips("Download Succeeded");
+      // 刷新 Subs
+      refreshSubs();
+    } else {
+      setDownloadTips("Download Failed");
+    }
+    setDownlaodBtnDisable(false);
+  };
+
+  const updateFinished = (job: any) => {
+    if (job.state == "succeeded") {
+      // 部分订阅更新失败时任务仍然成功
+      setDownloadTips(job.result?.failed > 0 ? "Update Error" : "Update Succeeded");
+      // 刷新 Subs
+      refreshSubs();
+    } else {
+      setDownloadTips("Update Failed");
+    }
+    setUpdateBtnDisable(false);
   };
 
   const refreshSubs = () => {
     backend.resolve(backend.getSubList(), (v: String) => {
       let x: Array<any> = JSON.parse(v.toString());
       let re = new RegExp("(?<=subs/).+.yaml$");
-      let i = 0;
       let subs = x.map((x) => {
         let name = re.exec(x.path);
         return {
-          id: i++,
-          name: name![0],
+          id: x.id,
+          name: x.name || name![0],
           url: x.url,
         };
       });
@@ -138,10 +121,14 @@ export const Subscriptions: FC<SubProp> = ({ Subscriptions }) => {
           disabled={downlaodBtnDisable}
           onClick={() => {
             setDownlaodBtnDisable(true);
-            backend.resolve(backend.downloadSub(text), () => {
-              console.log("download sub: " + text);
-            });
-            checkStatusHandler = setInterval(refreshDownloadStatus, 500);
+            setDownloadTips("Downloading...");
+            backend
+              .downloadSub(text)
+              .then((job) => {
+                console.log("download sub: " + text);
+                watchJob(job.id, downloadFinished);
+              })
+              .catch(() => downloadFinished({ state: "failed" }));
           }}
         >
           {localizationManager.getString(L.DOWNLOAD)}
@@ -151,10 +138,14 @@ export const Subscriptions: FC<SubProp> = ({ Subscriptions }) => {
           description={updateTips}
           onClick={() => {
             setUpdateBtnDisable(true);
-            backend.resolve(backend.updateSubs(), () => {
-              console.log("update subs.");
-            });
-            checkUpdateStatusHandler = setInterval(refreshUpdateStatus, 500);
+            setDownloadTips("Downloading... Please wait");
+            backend
+              .updateSubs()
+              .then((job) => {
+                console.log("update subs.");
+                watchJob(job.id, updateFinished);
+              })
+              .catch(() => updateFinished({ state: "failed" }));
           }}
           disabled={updateBtnDisable}
         >